@@ -0,0 +1,45 @@
+#import bevy_pbr::mesh_view_bindings
+#import bevy_pbr::mesh_bindings
+#import bevy_pbr::mesh_functions
+
+@group(1) @binding(0)
+var block_texture: texture_2d_array<f32>;
+@group(1) @binding(1)
+var block_sampler: sampler;
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+    @location(2) uv: vec2<f32>,
+    @location(3) color: vec4<f32>,
+    @location(4) layer: u32,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) world_normal: vec3<f32>,
+    @location(1) uv: vec2<f32>,
+    @location(2) color: vec4<f32>,
+    @location(3) @interpolate(flat) layer: u32,
+};
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+    let world_position = mesh_position_local_to_world(mesh.model, vec4<f32>(vertex.position, 1.0));
+    out.clip_position = mesh_position_world_to_clip(world_position);
+    out.world_normal = mesh_normal_local_to_world(vertex.normal);
+    out.uv = vertex.uv;
+    out.color = vertex.color;
+    out.layer = vertex.layer;
+    return out;
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    // Uvs count tiles and the sampler repeats, so merged faces tile without any seams
+    let texture_color = textureSample(block_texture, block_sampler, in.uv, i32(in.layer));
+    // Tops a little brighter than the sides and bottoms darker, light and ao come from the vertex color
+    let shade = 0.75 + 0.25 * in.world_normal.y;
+    return vec4<f32>(texture_color.rgb * in.color.rgb * shade, texture_color.a);
+}
@@ -1,7 +1,6 @@
 mod components;
 mod states;
 mod systems;
-use std::path::PathBuf;
 use std::time::Duration;
 
 use belly::prelude::*;
@@ -9,8 +8,8 @@ use bevy::prelude::*;
 
 use bevy_quinnet::client::QuinnetClientPlugin;
 use bevy_tweening::TweeningPlugin;
+use common::game::assets::asset_dir;
 use components::GameState;
-use directories::ProjectDirs;
 use iyes_loopless::prelude::*;
 use states::game::setup::GamePlugin;
 use states::loading::LoadingPlugin;
@@ -18,20 +17,14 @@ use states::menu::MenuPlugin;
 use states::splashscreen::SplashscreenPlugin;
 
 fn main() {
-    let asset_path = if let Some(proj_dirs) = ProjectDirs::from("com", "vinox", "vinox") {
-        proj_dirs.data_dir().join("assets")
-    } else {
-        let mut path = PathBuf::new();
-        path.push("assets");
-        path
-    };
-    //TODO: make directory for assets if it doesn't exist and also copy over the game assets to it
+    // Sets up the data dir with the built in assets the first time
+    let asset_path = asset_dir();
     App::new()
         .add_plugins(
             DefaultPlugins
                 .set(AssetPlugin {
                     asset_folder: asset_path.to_string_lossy().to_string(),
-                    watch_for_changes: false,
+                    watch_for_changes: true,
                 })
                 .set(ImagePlugin::default_nearest()),
         )
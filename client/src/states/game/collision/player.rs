@@ -3,14 +3,19 @@ use bevy::prelude::*;
 use bevy_quinnet::client::Client;
 use bevy_rapier3d::prelude::{Collider, CollisionGroups, Group, QueryFilter, RapierContext, Rot};
 use common::{
-    game::world::chunk::{voxel_to_world, world_to_voxel, ChunkComp, CurrentChunks, CHUNK_SIZE},
+    game::world::{
+        block_state::BlockState,
+        chunk::{
+            voxel_to_world, world_to_voxel, ChunkComp, CurrentChunks, LoadableTypes, CHUNK_EDGE,
+        },
+    },
     networking::components::ClientMessage,
 };
 
 use crate::states::game::{
     input::player::FPSCamera,
     networking::{components::ControlledPlayer, syncing::HighLightCube},
-    world::chunk::DirtyChunk,
+    world::{chunk::DirtyChunk, lighting::LightQueue},
 };
 
 use bevy_rapier3d::prelude::TOIStatus::Converged;
@@ -46,16 +51,18 @@ pub fn interact(
         (With<HighLightCube>, Without<ControlledPlayer>),
     >,
     mut current_item: Local<CurrentItem>,
+    mut light_queue: ResMut<LightQueue>,
+    loadable_types: Res<LoadableTypes>,
 ) {
     let item_string = match current_item.clone() {
-        CurrentItem::Grass => "vinoxgrass",
-        CurrentItem::Dirt => "vinoxdirt",
-        CurrentItem::Concrete => "vinoxconcrete",
-        CurrentItem::Cobblestone => "vinoxcobblestone",
-        CurrentItem::Moss => "vinoxmoss",
-        CurrentItem::Wood => "vinoxwood",
-        CurrentItem::Greybrick => "vinoxgreybrick",
-        CurrentItem::Glass => "vinoxglass",
+        CurrentItem::Grass => "vinox:grass",
+        CurrentItem::Dirt => "vinox:dirt",
+        CurrentItem::Concrete => "vinox:concrete",
+        CurrentItem::Cobblestone => "vinox:cobblestone",
+        CurrentItem::Moss => "vinox:moss",
+        CurrentItem::Wood => "vinox:wood",
+        CurrentItem::Greybrick => "vinox:greybrick",
+        CurrentItem::Glass => "vinox:glass",
     };
 
     for key in keys.get_just_pressed() {
@@ -85,6 +92,30 @@ pub fn interact(
                 QueryFilter::only_fixed(),
             );
             if let Some((_, toi)) = hit {
+                // Right clicking something interactable uses it instead of placing against it
+                if mouse_right {
+                    let target = world_to_voxel(toi.point - (toi.normal / Vec3::splat(2.0)));
+                    let interactable = current_chunks
+                        .get_entity(target.0)
+                        .and_then(|chunk_entity| chunks.get(chunk_entity).ok())
+                        .and_then(|chunk| chunk.chunk_data.get_block(target.1))
+                        .and_then(|block| {
+                            loadable_types
+                                .blocks
+                                .get(BlockState::block_id_of(&block))
+                                .map(|block_type| block_type.interactable)
+                        })
+                        .unwrap_or(false);
+                    if interactable {
+                        client
+                            .connection_mut()
+                            .try_send_message(ClientMessage::InteractBlock {
+                                chunk_pos: target.0,
+                                voxel_pos: [target.1.x as u8, target.1.y as u8, target.1.z as u8],
+                            });
+                        return;
+                    }
+                }
                 let point = if mouse_right {
                     toi.point + (toi.normal / Vec3::splat(2.0))
                 } else {
@@ -99,7 +130,7 @@ pub fn interact(
                             block_visibility.toggle();
                         }
                         block_transform.translation =
-                            voxel_to_world(pos.1, pos.0) - Vec3::splat(0.5);
+                            voxel_to_world(pos.1, pos.0) + Vec3::splat(0.5);
                     }
                     if let Ok(mut chunk) = chunks.get_mut(chunk_entity) {
                         if mouse_right {
@@ -112,6 +143,7 @@ pub fn interact(
                             {
                                 chunk.chunk_data.add_block_state(&item_string.to_string());
                                 chunk.chunk_data.set_block(pos.1, item_string.to_string());
+                                light_queue.blocks.push((pos.0, pos.1));
                                 client.connection_mut().try_send_message(
                                     ClientMessage::SentBlock {
                                         chunk_pos: pos.0,
@@ -122,6 +154,7 @@ pub fn interact(
                             }
                         } else if mouse_left {
                             chunk.chunk_data.set_block(pos.1, "air".to_string());
+                            light_queue.blocks.push((pos.0, pos.1));
                             client
                                 .connection_mut()
                                 .try_send_message(ClientMessage::SentBlock {
@@ -131,14 +164,14 @@ pub fn interact(
                                 });
                         }
                         match pos.1.x {
-                            1 => {
+                            0 => {
                                 if let Some(neighbor_chunk) =
                                     current_chunks.get_entity(pos.0 + IVec3::new(-1, 0, 0))
                                 {
                                     commands.entity(neighbor_chunk).insert(DirtyChunk);
                                 }
                             }
-                            CHUNK_SIZE => {
+                            CHUNK_EDGE => {
                                 if let Some(neighbor_chunk) =
                                     current_chunks.get_entity(pos.0 + IVec3::new(1, 0, 0))
                                 {
@@ -148,14 +181,14 @@ pub fn interact(
                             _ => {}
                         }
                         match pos.1.y {
-                            1 => {
+                            0 => {
                                 if let Some(neighbor_chunk) =
                                     current_chunks.get_entity(pos.0 + IVec3::new(0, -1, 0))
                                 {
                                     commands.entity(neighbor_chunk).insert(DirtyChunk);
                                 }
                             }
-                            CHUNK_SIZE => {
+                            CHUNK_EDGE => {
                                 if let Some(neighbor_chunk) =
                                     current_chunks.get_entity(pos.0 + IVec3::new(0, 1, 0))
                                 {
@@ -165,14 +198,14 @@ pub fn interact(
                             _ => {}
                         }
                         match pos.1.z {
-                            1 => {
+                            0 => {
                                 if let Some(neighbor_chunk) =
                                     current_chunks.get_entity(pos.0 + IVec3::new(0, 0, -1))
                                 {
                                     commands.entity(neighbor_chunk).insert(DirtyChunk);
                                 }
                             }
-                            CHUNK_SIZE => {
+                            CHUNK_EDGE => {
                                 if let Some(neighbor_chunk) =
                                     current_chunks.get_entity(pos.0 + IVec3::new(0, 0, 1))
                                 {
@@ -7,7 +7,8 @@ use crate::components::GameState;
 use super::{
     components::{ClientLobby, NetworkMapping},
     syncing::{
-        client_send_naive_position, client_sync_players, get_id, lerp_new_location, wait_for_chunks,
+        client_send_naive_position, client_sync_players, join_server, lerp_new_location,
+        wait_for_chunks,
     },
 };
 
@@ -19,7 +20,7 @@ impl Plugin for NetworkingPlugin {
             .insert_resource(ClientLobby::default())
             .insert_resource(EntityBuffer::default())
             .add_system(client_sync_players.run_in_state(GameState::Game))
-            .add_system(get_id.run_in_state(GameState::Game))
+            .add_enter_system(GameState::Game, join_server)
             .add_fixed_timestep_system(
                 "network_update",
                 0,
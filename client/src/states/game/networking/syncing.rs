@@ -16,9 +16,12 @@ use common::{
 };
 use zstd::stream::copy_decode;
 
-use crate::states::game::{
-    networking::components::ControlledPlayer,
-    world::chunk::{CreateChunkEvent, PlayerChunk, SetBlockEvent},
+use crate::states::{
+    game::{
+        networking::components::ControlledPlayer,
+        world::chunk::{CreateChunkEvent, PlayerChunk, SetBlockEvent},
+    },
+    loading::{ContentMessage, ContentSync},
 };
 
 use super::components::{ClientData, ClientLobby, NetworkMapping, PlayerInfo};
@@ -31,29 +34,19 @@ pub struct JustSpawned {
 #[derive(Component)]
 pub struct HighLightCube;
 
-pub fn get_id(
+// The id and content are sorted out while loading so joining is the first thing that happens in game
+pub fn join_server(
     mut client: ResMut<Client>,
-    mut client_data: ResMut<ClientData>,
-    mut has_connected: Local<bool>,
+    client_data: Res<ClientData>,
+    content_sync: Res<ContentSync>,
 ) {
-    if *has_connected {
-    } else {
-        while let Some(message) = client
-            .connection_mut()
-            .try_receive_message::<ServerMessage>()
-        {
-            if let ServerMessage::ClientId { id } = message {
-                client_data.0 = id;
-                client
-                    .connection_mut()
-                    .try_send_message(ClientMessage::Join {
-                        user_name: "test".to_string(),
-                        id,
-                    });
-                *has_connected = true;
-            }
-        }
-    }
+    client
+        .connection_mut()
+        .try_send_message(ClientMessage::Join {
+            user_name: "test".to_string(),
+            id: client_data.0,
+            content_hash: content_sync.server.hash,
+        });
 }
 
 //TODO: Refactor this is a lot in one function
@@ -70,6 +63,7 @@ pub fn client_sync_players(
     player_builder: Res<PlayerBundleBuilder>,
     mut chunk_event: EventWriter<CreateChunkEvent>,
     mut block_event: EventWriter<SetBlockEvent>,
+    mut content_event: EventWriter<ContentMessage>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     asset_server: Res<AssetServer>,
@@ -165,13 +159,26 @@ pub fn client_sync_players(
                 }
                 ServerMessage::LevelData { chunk_data, pos } => {
                     let mut temp_output = Cursor::new(Vec::new());
-                    copy_decode(&chunk_data[..], &mut temp_output).unwrap();
-                    let level_data: RawChunk = bincode::deserialize(temp_output.get_ref()).unwrap();
+                    if let Err(error) = copy_decode(&chunk_data[..], &mut temp_output) {
+                        warn!("Couldn't decompress chunk {pos} from the server: {error}");
+                        continue;
+                    }
+                    let level_data: RawChunk = match bincode::deserialize(temp_output.get_ref()) {
+                        Ok(level_data) => level_data,
+                        Err(error) => {
+                            warn!("Couldn't read chunk {pos} from the server: {error}");
+                            continue;
+                        }
+                    };
                     chunk_event.send(CreateChunkEvent {
                         raw_chunk: level_data,
                         pos,
                     });
                 }
+                // The server reloaded its content
+                ServerMessage::ContentInfo { .. } | ServerMessage::ContentFile { .. } => {
+                    content_event.send(ContentMessage(message));
+                }
                 _ => {}
             }
         }
@@ -0,0 +1,62 @@
+use bevy::{
+    pbr::{MaterialPipeline, MaterialPipelineKey},
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        mesh::{MeshVertexAttribute, MeshVertexBufferLayout},
+        render_resource::{
+            AsBindGroup, RenderPipelineDescriptor, ShaderRef, SpecializedMeshPipelineError,
+            VertexFormat,
+        },
+    },
+};
+
+// Which layer of the block texture array the face uses. Uvs on chunk meshes count tiles so merged faces
+// repeat the texture, the sampler wraps them
+pub const ATTRIBUTE_LAYER: MeshVertexAttribute =
+    MeshVertexAttribute::new("Layer", 988_540_917, VertexFormat::Uint32);
+
+// The shader is built in instead of read from the asset folder, copies of it there never get updated
+pub const CHUNK_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 5_873_209_441_365_120_117);
+
+// Chunk material. Light and AO are already baked into the vertex colors so this is unlit
+#[derive(AsBindGroup, TypeUuid, Debug, Clone)]
+#[uuid = "7c3a4e2f-51b8-4d0a-9a57-3f0c1d6b8e21"]
+pub struct BlockMaterial {
+    #[texture(0, dimension = "2d_array")]
+    #[sampler(1)]
+    pub texture: Handle<Image>,
+    pub alpha_mode: AlphaMode,
+}
+
+impl Material for BlockMaterial {
+    fn vertex_shader() -> ShaderRef {
+        CHUNK_SHADER_HANDLE.typed().into()
+    }
+
+    fn fragment_shader() -> ShaderRef {
+        CHUNK_SHADER_HANDLE.typed().into()
+    }
+
+    fn alpha_mode(&self) -> AlphaMode {
+        self.alpha_mode
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayout,
+        _key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        let vertex_layout = layout.get_layout(&[
+            Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
+            Mesh::ATTRIBUTE_NORMAL.at_shader_location(1),
+            Mesh::ATTRIBUTE_UV_0.at_shader_location(2),
+            Mesh::ATTRIBUTE_COLOR.at_shader_location(3),
+            ATTRIBUTE_LAYER.at_shader_location(4),
+        ])?;
+        descriptor.vertex.buffers = vec![vertex_layout];
+        Ok(())
+    }
+}
@@ -1,16 +1,30 @@
-use std::{collections::HashSet, ops::Deref, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    f32::consts::{FRAC_PI_2, PI},
+    ops::Deref,
+    time::Duration,
+};
 
 use bevy::{
+    asset::{HandleId, LoadState},
     math::Vec3A,
     prelude::*,
-    render::{mesh::Indices, primitives::Aabb, render_resource::PrimitiveTopology},
+    render::{
+        mesh::Indices, primitives::Aabb, render_resource::PrimitiveTopology, renderer::RenderDevice,
+    },
     tasks::{AsyncComputeTaskPool, Task},
 };
 use bevy_rapier3d::prelude::Collider;
 use bevy_tweening::{lens::TransformPositionLens, *};
-use common::game::world::chunk::{
-    voxel_to_world, Chunk, ChunkComp, CurrentChunks, LoadableTypes, RawChunk, ViewDistance, Voxel,
-    VoxelVisibility, CHUNK_SIZE,
+use common::game::world::{
+    block_state::BlockState,
+    chunk::{
+        voxel_to_world, Chunk, ChunkComp, CurrentChunks, PaddedChunkView, ViewDistance, Voxel,
+        VoxelVisibility, CHUNK_SIZE, NEIGHBOR_OFFSETS,
+    },
+    geometry::Cuboid,
+    light::{LightChunk, PaddedLightView, MAX_LIGHT},
+    registry::BlockRegistry,
 };
 use futures_lite::future;
 use itertools::Itertools;
@@ -20,6 +34,11 @@ use crate::states::{
     loading::LoadableAssets,
 };
 
+use super::{
+    material::{BlockMaterial, ATTRIBUTE_LAYER},
+    texture_array::{build_block_textures, MISSING_LAYER},
+};
+
 pub const EMPTY: VoxelVisibility = VoxelVisibility::Empty;
 pub const OPAQUE: VoxelVisibility = VoxelVisibility::Opaque;
 pub const TRANSPARENT: VoxelVisibility = VoxelVisibility::Transparent;
@@ -68,6 +87,66 @@ impl Side {
     pub fn normals(&self) -> [[f32; 3]; 4] {
         [self.normal(), self.normal(), self.normal(), self.normal()]
     }
+
+    pub fn normal_axis(&self) -> usize {
+        match self.axis {
+            Axis::X => 0,
+            Axis::Y => 1,
+            Axis::Z => 2,
+        }
+    }
+
+    // Corners of this side of a unit cube, in the order every face uses
+    pub fn unit_positions(&self) -> [[f32; 3]; 4] {
+        match (&self.axis, &self.positive) {
+            (Axis::X, false) => [
+                [0.0, 0.0, 1.0],
+                [0.0, 0.0, 0.0],
+                [0.0, 1.0, 1.0],
+                [0.0, 1.0, 0.0],
+            ],
+            (Axis::X, true) => [
+                [1.0, 0.0, 0.0],
+                [1.0, 0.0, 1.0],
+                [1.0, 1.0, 0.0],
+                [1.0, 1.0, 1.0],
+            ],
+            (Axis::Y, false) => [
+                [0.0, 0.0, 1.0],
+                [1.0, 0.0, 1.0],
+                [0.0, 0.0, 0.0],
+                [1.0, 0.0, 0.0],
+            ],
+            (Axis::Y, true) => [
+                [0.0, 1.0, 1.0],
+                [0.0, 1.0, 0.0],
+                [1.0, 1.0, 1.0],
+                [1.0, 1.0, 0.0],
+            ],
+            (Axis::Z, false) => [
+                [0.0, 0.0, 0.0],
+                [1.0, 0.0, 0.0],
+                [0.0, 1.0, 0.0],
+                [1.0, 1.0, 0.0],
+            ],
+            (Axis::Z, true) => [
+                [1.0, 0.0, 1.0],
+                [0.0, 0.0, 1.0],
+                [1.0, 1.0, 1.0],
+                [0.0, 1.0, 1.0],
+            ],
+        }
+    }
+
+    // The axes a face's width and height run along, width follows the texture's u and height its v
+    pub fn tangent_axes(&self) -> (usize, usize) {
+        match (self.axis, self.positive) {
+            (Axis::X, _) => (2, 1),
+            (Axis::Y, false) => (0, 2),
+            (Axis::Y, true) => (2, 0),
+            (Axis::Z, _) => (0, 1),
+        }
+    }
 }
 
 pub struct Face<'a> {
@@ -103,87 +182,245 @@ impl QuadGroups {
     pub fn iter_with_ao<'a, C, V>(
         &'a self,
         chunk: &'a C,
-        loadable_types: &'a LoadableTypes,
+        registry: &'a BlockRegistry,
     ) -> impl Iterator<Item = FaceWithAO<'a>>
     where
         C: Chunk<Output = V>,
         V: Voxel,
     {
         self.iter()
-            .map(|face| FaceWithAO::new(face, chunk, loadable_types))
+            .map(|face| FaceWithAO::new(face, chunk, registry))
+    }
+
+    pub fn len(&self) -> usize {
+        self.groups.iter().map(|quads| quads.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.iter().all(|quads| quads.is_empty())
+    }
+
+    // Greedy meshing. Faces in the same layer that would look exactly the same (same block, AO and light at
+    // every corner) get merged into bigger quads, so flat ground ends up as a handful of quads
+    pub fn merge<C, V>(
+        &self,
+        chunk: &C,
+        light: &PaddedLightView,
+        registry: &BlockRegistry,
+    ) -> QuadGroups
+    where
+        C: Chunk<Output = V>,
+        V: Voxel + Copy,
+    {
+        let size = [C::X, C::Y, C::Z];
+        let mut merged = QuadGroups::default();
+        for (index, quads) in self.groups.iter().enumerate() {
+            let side = Side::from(index);
+            let (u, v) = side.tangent_axes();
+            let mut layers: HashMap<usize, Vec<Option<FaceKey<V>>>> = HashMap::new();
+            for quad in quads.iter() {
+                let face = Face { side, quad };
+                let [x, y, z] = quad.voxel;
+                let key = (
+                    chunk.get(x as u32, y as u32, z as u32, registry),
+                    face_aos(&face, chunk, registry),
+                    face_lights(&face, chunk, light, registry).map(f32::to_bits),
+                );
+                let mask = layers
+                    .entry(quad.voxel[side.normal_axis()])
+                    .or_insert_with(|| vec![None; size[u] * size[v]]);
+                mask[quad.voxel[u] + quad.voxel[v] * size[u]] = Some(key);
+            }
+
+            let mut layers: Vec<_> = layers.into_iter().collect();
+            layers.sort_unstable_by_key(|(layer, _)| *layer);
+            for (layer, mut mask) in layers {
+                for (start_u, start_v, width, height) in greedy_rects(&mut mask, size[u]) {
+                    let mut voxel = [0; 3];
+                    voxel[side.normal_axis()] = layer;
+                    voxel[u] = start_u;
+                    voxel[v] = start_v;
+                    merged.groups[index].push(Quad {
+                        voxel,
+                        width: width as u32,
+                        height: height as u32,
+                    });
+                }
+            }
+        }
+        merged
     }
 }
 
-pub(crate) fn face_aos<C, V>(face: &Face, chunk: &C, loadable_types: &LoadableTypes) -> [u32; 4]
+// What has to match for two faces to be merged: the voxel, its AO and its light
+type FaceKey<V> = (V, [u32; 4], [u32; 4]);
+
+// Covers every filled cell of a row major mask with rectangles, (u, v, width, height) for each. Rows are grown
+// as wide as they go first and then as tall as the whole row still matches
+fn greedy_rects<K: Copy + Eq>(
+    mask: &mut [Option<K>],
+    row_len: usize,
+) -> Vec<(usize, usize, usize, usize)> {
+    let rows = mask.len() / row_len;
+    let mut rects = Vec::new();
+    for v in 0..rows {
+        let mut u = 0;
+        while u < row_len {
+            let Some(key) = mask[u + v * row_len] else {
+                u += 1;
+                continue;
+            };
+            let mut width = 1;
+            while u + width < row_len && mask[u + width + v * row_len] == Some(key) {
+                width += 1;
+            }
+            let mut height = 1;
+            while v + height < rows {
+                let start = u + (v + height) * row_len;
+                if mask[start..start + width]
+                    .iter()
+                    .any(|cell| *cell != Some(key))
+                {
+                    break;
+                }
+                height += 1;
+            }
+            for row in v..v + height {
+                let start = u + row * row_len;
+                mask[start..start + width].fill(None);
+            }
+            rects.push((u, v, width, height));
+            u += width;
+        }
+    }
+    rects
+}
+
+// The voxel a face looks out into and the eight voxels around that one in the same layer, in the order
+// side_aos expects
+pub(crate) fn face_neighbors(face: &Face) -> (UVec3, [UVec3; 8]) {
+    let [x, y, z] = face.voxel();
+    let (x, y, z) = (x as u32, y as u32, z as u32);
+
+    let front = match (face.side.axis, face.side.positive) {
+        (Axis::X, false) => UVec3::new(x - 1, y, z),
+        (Axis::X, true) => UVec3::new(x + 1, y, z),
+        (Axis::Y, false) => UVec3::new(x, y - 1, z),
+        (Axis::Y, true) => UVec3::new(x, y + 1, z),
+        (Axis::Z, false) => UVec3::new(x, y, z - 1),
+        (Axis::Z, true) => UVec3::new(x, y, z + 1),
+    };
+    let ring = match (face.side.axis, face.side.positive) {
+        (Axis::X, false) => [
+            UVec3::new(x - 1, y, z + 1),
+            UVec3::new(x - 1, y - 1, z + 1),
+            UVec3::new(x - 1, y - 1, z),
+            UVec3::new(x - 1, y - 1, z - 1),
+            UVec3::new(x - 1, y, z - 1),
+            UVec3::new(x - 1, y + 1, z - 1),
+            UVec3::new(x - 1, y + 1, z),
+            UVec3::new(x - 1, y + 1, z + 1),
+        ],
+        (Axis::X, true) => [
+            UVec3::new(x + 1, y, z - 1),
+            UVec3::new(x + 1, y - 1, z - 1),
+            UVec3::new(x + 1, y - 1, z),
+            UVec3::new(x + 1, y - 1, z + 1),
+            UVec3::new(x + 1, y, z + 1),
+            UVec3::new(x + 1, y + 1, z + 1),
+            UVec3::new(x + 1, y + 1, z),
+            UVec3::new(x + 1, y + 1, z - 1),
+        ],
+        (Axis::Y, false) => [
+            UVec3::new(x - 1, y - 1, z),
+            UVec3::new(x - 1, y - 1, z + 1),
+            UVec3::new(x, y - 1, z + 1),
+            UVec3::new(x + 1, y - 1, z + 1),
+            UVec3::new(x + 1, y - 1, z),
+            UVec3::new(x + 1, y - 1, z - 1),
+            UVec3::new(x, y - 1, z - 1),
+            UVec3::new(x - 1, y - 1, z - 1),
+        ],
+        (Axis::Y, true) => [
+            UVec3::new(x, y + 1, z + 1),
+            UVec3::new(x - 1, y + 1, z + 1),
+            UVec3::new(x - 1, y + 1, z),
+            UVec3::new(x - 1, y + 1, z - 1),
+            UVec3::new(x, y + 1, z - 1),
+            UVec3::new(x + 1, y + 1, z - 1),
+            UVec3::new(x + 1, y + 1, z),
+            UVec3::new(x + 1, y + 1, z + 1),
+        ],
+        (Axis::Z, false) => [
+            UVec3::new(x - 1, y, z - 1),
+            UVec3::new(x - 1, y - 1, z - 1),
+            UVec3::new(x, y - 1, z - 1),
+            UVec3::new(x + 1, y - 1, z - 1),
+            UVec3::new(x + 1, y, z - 1),
+            UVec3::new(x + 1, y + 1, z - 1),
+            UVec3::new(x, y + 1, z - 1),
+            UVec3::new(x - 1, y + 1, z - 1),
+        ],
+        (Axis::Z, true) => [
+            UVec3::new(x + 1, y, z + 1),
+            UVec3::new(x + 1, y - 1, z + 1),
+            UVec3::new(x, y - 1, z + 1),
+            UVec3::new(x - 1, y - 1, z + 1),
+            UVec3::new(x - 1, y, z + 1),
+            UVec3::new(x - 1, y + 1, z + 1),
+            UVec3::new(x, y + 1, z + 1),
+            UVec3::new(x + 1, y + 1, z + 1),
+        ],
+    };
+    (front, ring)
+}
+
+pub(crate) fn face_aos<C, V>(face: &Face, chunk: &C, registry: &BlockRegistry) -> [u32; 4]
 where
     C: Chunk<Output = V>,
     V: Voxel,
 {
-    let [x, y, z] = face.voxel();
-    let (x, y, z) = (x as u32, y as u32, z as u32);
+    let (_, ring) = face_neighbors(face);
+    side_aos(ring.map(|pos| chunk.get(pos.x, pos.y, pos.z, registry)))
+}
 
-    match (face.side.axis, face.side.positive) {
-        (Axis::X, false) => side_aos([
-            chunk.get(x - 1, y, z + 1, loadable_types),
-            chunk.get(x - 1, y - 1, z + 1, loadable_types),
-            chunk.get(x - 1, y - 1, z, loadable_types),
-            chunk.get(x - 1, y - 1, z - 1, loadable_types),
-            chunk.get(x - 1, y, z - 1, loadable_types),
-            chunk.get(x - 1, y + 1, z - 1, loadable_types),
-            chunk.get(x - 1, y + 1, z, loadable_types),
-            chunk.get(x - 1, y + 1, z + 1, loadable_types),
-        ]),
-        (Axis::X, true) => side_aos([
-            chunk.get(x + 1, y, z - 1, loadable_types),
-            chunk.get(x + 1, y - 1, z - 1, loadable_types),
-            chunk.get(x + 1, y - 1, z, loadable_types),
-            chunk.get(x + 1, y - 1, z + 1, loadable_types),
-            chunk.get(x + 1, y, z + 1, loadable_types),
-            chunk.get(x + 1, y + 1, z + 1, loadable_types),
-            chunk.get(x + 1, y + 1, z, loadable_types),
-            chunk.get(x + 1, y + 1, z - 1, loadable_types),
-        ]),
-        (Axis::Y, false) => side_aos([
-            chunk.get(x - 1, y - 1, z, loadable_types),
-            chunk.get(x - 1, y - 1, z + 1, loadable_types),
-            chunk.get(x, y - 1, z + 1, loadable_types),
-            chunk.get(x + 1, y - 1, z + 1, loadable_types),
-            chunk.get(x + 1, y - 1, z, loadable_types),
-            chunk.get(x + 1, y - 1, z - 1, loadable_types),
-            chunk.get(x, y - 1, z - 1, loadable_types),
-            chunk.get(x - 1, y - 1, z - 1, loadable_types),
-        ]),
-        (Axis::Y, true) => side_aos([
-            chunk.get(x, y + 1, z + 1, loadable_types),
-            chunk.get(x - 1, y + 1, z + 1, loadable_types),
-            chunk.get(x - 1, y + 1, z, loadable_types),
-            chunk.get(x - 1, y + 1, z - 1, loadable_types),
-            chunk.get(x, y + 1, z - 1, loadable_types),
-            chunk.get(x + 1, y + 1, z - 1, loadable_types),
-            chunk.get(x + 1, y + 1, z, loadable_types),
-            chunk.get(x + 1, y + 1, z + 1, loadable_types),
-        ]),
-        (Axis::Z, false) => side_aos([
-            chunk.get(x - 1, y, z - 1, loadable_types),
-            chunk.get(x - 1, y - 1, z - 1, loadable_types),
-            chunk.get(x, y - 1, z - 1, loadable_types),
-            chunk.get(x + 1, y - 1, z - 1, loadable_types),
-            chunk.get(x + 1, y, z - 1, loadable_types),
-            chunk.get(x + 1, y + 1, z - 1, loadable_types),
-            chunk.get(x, y + 1, z - 1, loadable_types),
-            chunk.get(x - 1, y + 1, z - 1, loadable_types),
-        ]),
-        (Axis::Z, true) => side_aos([
-            chunk.get(x + 1, y, z + 1, loadable_types),
-            chunk.get(x + 1, y - 1, z + 1, loadable_types),
-            chunk.get(x, y - 1, z + 1, loadable_types),
-            chunk.get(x - 1, y - 1, z + 1, loadable_types),
-            chunk.get(x - 1, y, z + 1, loadable_types),
-            chunk.get(x - 1, y + 1, z + 1, loadable_types),
-            chunk.get(x, y + 1, z + 1, loadable_types),
-            chunk.get(x + 1, y + 1, z + 1, loadable_types),
-        ]),
-    }
+// Ring positions that touch each vertex of a face, the same ones side_aos uses
+const VERTEX_RING: [[usize; 3]; 4] = [[0, 1, 2], [2, 3, 4], [6, 7, 0], [4, 5, 6]];
+
+// Smooth light for each vertex of a face from 0.0 to 1.0. Averages the voxel in front of the face with the
+// three around each corner that light can reach, block light and sky light are averaged separately
+pub(crate) fn face_lights<C, V>(
+    face: &Face,
+    chunk: &C,
+    light: &PaddedLightView,
+    registry: &BlockRegistry,
+) -> [f32; 4]
+where
+    C: Chunk<Output = V>,
+    V: Voxel,
+{
+    let (front, ring) = face_neighbors(face);
+    let open = ring.map(|pos| chunk.get(pos.x, pos.y, pos.z, registry).visibility() != OPAQUE);
+    VERTEX_RING.map(|[side1, corner, side2]| {
+        let mut count = 1;
+        let mut sky = light.sky_light(front) as u32;
+        let mut block = light.block_light(front) as u32;
+        // The corner is hidden if both sides are blocked
+        let corner_visible = open[side1] || open[side2];
+        for index in [side1, corner, side2] {
+            if open[index] && (index != corner || corner_visible) {
+                count += 1;
+                sky += light.sky_light(ring[index]) as u32;
+                block += light.block_light(ring[index]) as u32;
+            }
+        }
+        light_brightness(sky.max(block) as f32 / count as f32)
+    })
+}
+
+// Each level down is 20% darker, with a little left over so caves are never pitch black
+fn light_brightness(level: f32) -> f32 {
+    0.03 + 0.97 * 0.8_f32.powf(MAX_LIGHT as f32 - level)
 }
 
 pub struct FaceWithAO<'a> {
@@ -192,12 +429,12 @@ pub struct FaceWithAO<'a> {
 }
 
 impl<'a> FaceWithAO<'a> {
-    pub fn new<C, V>(face: Face<'a>, chunk: &C, loadable_types: &LoadableTypes) -> Self
+    pub fn new<C, V>(face: Face<'a>, chunk: &C, registry: &BlockRegistry) -> Self
     where
         C: Chunk<Output = V>,
         V: Voxel,
     {
-        let aos = face_aos(&face, chunk, loadable_types);
+        let aos = face_aos(&face, chunk, registry);
         Self { face, aos }
     }
 
@@ -259,73 +496,26 @@ impl<'a> Face<'a> {
     }
 
     pub fn positions(&self, voxel_size: f32) -> [[f32; 3]; 4] {
-        let positions = match (&self.side.axis, &self.side.positive) {
-            (Axis::X, false) => [
-                [0.0, 0.0, 1.0],
-                [0.0, 0.0, 0.0],
-                [0.0, 1.0, 1.0],
-                [0.0, 1.0, 0.0],
-            ],
-            (Axis::X, true) => [
-                [1.0, 0.0, 0.0],
-                [1.0, 0.0, 1.0],
-                [1.0, 1.0, 0.0],
-                [1.0, 1.0, 1.0],
-            ],
-            (Axis::Y, false) => [
-                [0.0, 0.0, 1.0],
-                [1.0, 0.0, 1.0],
-                [0.0, 0.0, 0.0],
-                [1.0, 0.0, 0.0],
-            ],
-            (Axis::Y, true) => [
-                [0.0, 1.0, 1.0],
-                [0.0, 1.0, 0.0],
-                [1.0, 1.0, 1.0],
-                [1.0, 1.0, 0.0],
-            ],
-            (Axis::Z, false) => [
-                [0.0, 0.0, 0.0],
-                [1.0, 0.0, 0.0],
-                [0.0, 1.0, 0.0],
-                [1.0, 1.0, 0.0],
-            ],
-            (Axis::Z, true) => [
-                [1.0, 0.0, 1.0],
-                [0.0, 0.0, 1.0],
-                [1.0, 1.0, 1.0],
-                [0.0, 1.0, 1.0],
-            ],
-        };
+        let positions = self.side.unit_positions();
 
         let (x, y, z) = (
             (self.quad.voxel[0] - 1) as f32,
             (self.quad.voxel[1] - 1) as f32,
             (self.quad.voxel[2] - 1) as f32,
         );
+        // Merged faces stretch along the two axes the face lies in
+        let (u, v) = self.side.tangent_axes();
+        let mut scale = [1.0; 3];
+        scale[u] = self.quad.width as f32;
+        scale[v] = self.quad.height as f32;
 
-        [
-            [
-                x * voxel_size + positions[0][0] * voxel_size,
-                y * voxel_size + positions[0][1] * voxel_size,
-                z * voxel_size + positions[0][2] * voxel_size,
-            ],
+        positions.map(|position| {
             [
-                x * voxel_size + positions[1][0] * voxel_size,
-                y * voxel_size + positions[1][1] * voxel_size,
-                z * voxel_size + positions[1][2] * voxel_size,
-            ],
-            [
-                x * voxel_size + positions[2][0] * voxel_size,
-                y * voxel_size + positions[2][1] * voxel_size,
-                z * voxel_size + positions[2][2] * voxel_size,
-            ],
-            [
-                x * voxel_size + positions[3][0] * voxel_size,
-                y * voxel_size + positions[3][1] * voxel_size,
-                z * voxel_size + positions[3][2] * voxel_size,
-            ],
-        ]
+                x * voxel_size + position[0] * scale[0] * voxel_size,
+                y * voxel_size + position[1] * scale[1] * voxel_size,
+                z * voxel_size + position[2] * scale[2] * voxel_size,
+            ]
+        })
     }
 
     pub fn normals(&self) -> [[f32; 3]; 4] {
@@ -341,6 +531,13 @@ impl<'a> Face<'a> {
         }
     }
 
+    // Texture coordinates counted in tiles so merged faces repeat their texture once per voxel instead of
+    // stretching it. Vertex 0 is the bottom left of the texture
+    pub fn tile_uvs(&self) -> [[f32; 2]; 4] {
+        let (width, height) = (self.quad.width as f32, self.quad.height as f32);
+        [[0.0, height], [width, height], [0.0, 0.0], [width, 0.0]]
+    }
+
     pub fn voxel(&self) -> [usize; 3] {
         self.quad.voxel
     }
@@ -350,11 +547,7 @@ pub struct MeshChunkEvent {
     pub pos: IVec3,
 }
 
-pub fn generate_mesh<C, T>(
-    chunk: &C,
-    loadable_types: &LoadableTypes,
-    solid_pass: bool,
-) -> QuadGroups
+pub fn generate_mesh<C, T>(chunk: &C, registry: &BlockRegistry, solid_pass: bool) -> QuadGroups
 where
     C: Chunk<Output = T>,
     T: Voxel,
@@ -369,18 +562,18 @@ where
         for y in 1..C::Y - 1 {
             for x in 1..C::X - 1 {
                 let (x, y, z) = (x as u32, y as u32, z as u32);
-                let voxel = chunk.get(x, y, z, loadable_types);
+                let voxel = chunk.get(x, y, z, registry);
 
                 match voxel.visibility() {
                     EMPTY => continue,
                     visibility => {
                         let neighbors = [
-                            chunk.get(x - 1, y, z, loadable_types),
-                            chunk.get(x + 1, y, z, loadable_types),
-                            chunk.get(x, y - 1, z, loadable_types),
-                            chunk.get(x, y + 1, z, loadable_types),
-                            chunk.get(x, y, z - 1, loadable_types),
-                            chunk.get(x, y, z + 1, loadable_types),
+                            chunk.get(x - 1, y, z, registry),
+                            chunk.get(x + 1, y, z, registry),
+                            chunk.get(x, y - 1, z, registry),
+                            chunk.get(x, y + 1, z, registry),
+                            chunk.get(x, y, z - 1, registry),
+                            chunk.get(x, y, z + 1, registry),
                         ];
 
                         for (i, neighbor) in neighbors.into_iter().enumerate() {
@@ -420,6 +613,154 @@ where
     buffer
 }
 
+// One face of a slab or stairs block, already culled. Cuboid is the box inside the voxel it belongs to
+pub struct ShapeFace {
+    pub voxel: [usize; 3],
+    pub side: Side,
+    pub cuboid: Cuboid,
+    pub light: f32,
+}
+
+impl ShapeFace {
+    pub fn indices(&self, start: u32) -> [u32; 6] {
+        [start, start + 2, start + 1, start + 1, start + 2, start + 3]
+    }
+
+    pub fn positions(&self) -> [[f32; 3]; 4] {
+        let origin = Vec3::new(
+            (self.voxel[0] - 1) as f32,
+            (self.voxel[1] - 1) as f32,
+            (self.voxel[2] - 1) as f32,
+        );
+        let size = self.cuboid.max - self.cuboid.min;
+        self.side
+            .unit_positions()
+            .map(|unit| (origin + self.cuboid.min + Vec3::from_array(unit) * size).to_array())
+    }
+
+    // Only the part of the texture the face covers so a slab shows the bottom half instead of a squashed copy
+    pub fn uvs(&self) -> [[f32; 2]; 4] {
+        let (u, v) = self.side.tangent_axes();
+        let units = self.side.unit_positions();
+        let corners = [[0.0, 1.0], [1.0, 1.0], [0.0, 0.0], [1.0, 0.0]];
+        let size = self.cuboid.max - self.cuboid.min;
+        std::array::from_fn(|vertex| {
+            [(u, 0), (v, 1)].map(|(axis, coord)| {
+                let position = self.cuboid.min[axis] + units[vertex][axis] * size[axis];
+                // The texture runs against the axis on some sides
+                if corners[vertex][coord] == units[vertex][axis] {
+                    position
+                } else {
+                    1.0 - position
+                }
+            })
+        })
+    }
+}
+
+// Faces for every slab and stairs block. Boxes hide faces of other boxes in the same block, and faces on
+// the edge of the voxel get culled by full opaque cubes or by a neighbouring shape that covers them
+pub fn generate_shapes(
+    chunk: &PaddedChunkView,
+    light: &PaddedLightView,
+    registry: &BlockRegistry,
+    solid_pass: bool,
+) -> Vec<ShapeFace> {
+    let shapes: Vec<Option<(VoxelVisibility, Vec<Cuboid>)>> = chunk
+        .palette()
+        .iter()
+        .enumerate()
+        .map(|(palette_index, state)| {
+            let block_type = registry.get(chunk.block_id(palette_index as u16))?;
+            if block_type.is_full_cube()
+                || block_type.block_model.is_some()
+                || block_type.visibility == EMPTY
+            {
+                return None;
+            }
+            let block_state = BlockState::parse_typed(state, block_type)
+                .unwrap_or_else(|_| BlockState::new(BlockState::block_id_of(state)));
+            Some((
+                block_type.visibility,
+                block_type.block_geometry.cuboids(&block_state),
+            ))
+        })
+        .collect();
+    if shapes.iter().all(|shape| shape.is_none()) {
+        return Vec::new();
+    }
+
+    let mut faces = Vec::new();
+    for z in 1..PaddedChunkView::Z - 1 {
+        for y in 1..PaddedChunkView::Y - 1 {
+            for x in 1..PaddedChunkView::X - 1 {
+                let pos = UVec3::new(x as u32, y as u32, z as u32);
+                let palette_index = chunk.palette_index(pos);
+                let Some((visibility, cuboids)) = &shapes[palette_index as usize] else {
+                    continue;
+                };
+                if (*visibility == OPAQUE) != solid_pass {
+                    continue;
+                }
+                for (index, cuboid) in cuboids.iter().enumerate() {
+                    for (side_index, offset) in NEIGHBOR_OFFSETS.iter().enumerate() {
+                        let side = Side::from(side_index);
+                        let axis = side.normal_axis();
+                        let plane = cuboid.plane(axis, side.positive);
+                        if cuboids.iter().enumerate().any(|(other_index, other)| {
+                            other_index != index
+                                && other.covers(axis, !side.positive, plane, cuboid)
+                        }) {
+                            continue;
+                        }
+
+                        let on_edge = plane == if side.positive { 1.0 } else { 0.0 };
+                        let front = (pos.as_ivec3() + *offset).as_uvec3();
+                        if on_edge {
+                            let neighbor_index = chunk.palette_index(front);
+                            let hidden =
+                                match chunk.get(front.x, front.y, front.z, registry).visibility() {
+                                    OPAQUE => true,
+                                    TRANSPARENT => false,
+                                    EMPTY => match &shapes[neighbor_index as usize] {
+                                        Some((neighbor_visibility, neighbor_cuboids)) => {
+                                            (*neighbor_visibility == OPAQUE
+                                                || neighbor_index == palette_index)
+                                                && neighbor_cuboids.iter().any(|other| {
+                                                    other.covers(
+                                                        axis,
+                                                        !side.positive,
+                                                        1.0 - plane,
+                                                        cuboid,
+                                                    )
+                                                })
+                                        }
+                                        None => false,
+                                    },
+                                };
+                            if hidden {
+                                continue;
+                            }
+                        }
+
+                        // Faces inside the voxel are lit by the voxel itself
+                        let light_pos = if on_edge { front } else { pos };
+                        faces.push(ShapeFace {
+                            voxel: [x, y, z],
+                            side,
+                            cuboid: *cuboid,
+                            light: light_brightness(
+                                light.sky_light(light_pos).max(light.block_light(light_pos)) as f32,
+                            ),
+                        });
+                    }
+                }
+            }
+        }
+    }
+    faces
+}
+
 pub fn build_mesh(
     mut event: EventReader<MeshChunkEvent>,
     mut chunk_queue: ResMut<ChunkQueue>,
@@ -427,9 +768,9 @@ pub fn build_mesh(
     view_distance: Res<ViewDistance>,
     chunks: Query<&ChunkComp>,
     current_chunks: Res<CurrentChunks>,
+    block_registry: Res<BlockRegistry>,
+    lights: Query<&LightChunk>,
 ) {
-    // let block_atlas = texture_atlas.get(&loadable_assets.block_atlas).unwrap();
-    // 0 and CHUNK_SIZE_PADDED dont get built into the mesh itself its data for meshing from other chunks this is just one solution
     // TODO: Redo a lot of this code but for now just want a working implementation. The ao and custom geometry are the things I think need the most looking at
     let mut checked = HashSet::new();
     for evt in event.iter() {
@@ -440,14 +781,31 @@ pub fn build_mesh(
                 IVec2::new(-view_distance.horizontal, -view_distance.vertical),
                 IVec2::new(view_distance.horizontal, view_distance.vertical),
             ) {
-                chunk_queue.mesh.push((
-                    evt.pos,
-                    chunks
-                        .get(current_chunks.get_entity(evt.pos).unwrap())
-                        .unwrap()
-                        .chunk_data
-                        .clone(),
-                ));
+                let (Some(chunk_entity), Some(neighbor_entities)) = (
+                    current_chunks.get_entity(evt.pos),
+                    current_chunks.get_neighbors(evt.pos),
+                ) else {
+                    continue;
+                };
+                // Chunks without light yet get meshed once they are lit
+                if let (Ok(chunk), Ok(neighbors), Ok(light)) = (
+                    chunks.get(chunk_entity),
+                    chunks.get_many(neighbor_entities),
+                    lights.get(chunk_entity),
+                ) {
+                    chunk_queue.mesh.push((
+                        evt.pos,
+                        PaddedChunkView::new(
+                            &chunk.chunk_data,
+                            neighbors.map(|neighbor| &neighbor.chunk_data),
+                            &block_registry,
+                        ),
+                        PaddedLightView::new(
+                            light,
+                            neighbor_entities.map(|neighbor| lights.get(neighbor).ok()),
+                        ),
+                    ));
+                }
             }
         }
     }
@@ -459,6 +817,7 @@ pub struct MeshedChunk {
     transparent_mesh: Mesh,
     collider: Collider,
     transparent_collider: Collider,
+    models: Vec<(Handle<Scene>, Transform)>,
     pos: IVec3,
 }
 
@@ -467,44 +826,88 @@ pub struct ChunkGenTask(Task<MeshedChunk>);
 
 #[derive(Resource, Default)]
 pub struct ChunkMaterial {
-    opaque: Handle<StandardMaterial>,
-    transparent: Handle<StandardMaterial>,
+    opaque: Handle<BlockMaterial>,
+    transparent: Handle<BlockMaterial>,
 }
 
 pub fn create_chunk_material(
-    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut materials: ResMut<Assets<BlockMaterial>>,
     mut chunk_material: ResMut<ChunkMaterial>,
-    texture_atlas: Res<Assets<TextureAtlas>>,
     loadable_assets: ResMut<LoadableAssets>,
 ) {
-    chunk_material.transparent = materials.add(StandardMaterial {
-        base_color: Color::WHITE,
-        base_color_texture: Some(
-            texture_atlas
-                .get(&loadable_assets.block_atlas)
-                .unwrap()
-                .texture
-                .clone(),
-        ),
-        perceptual_roughness: 1.0,
+    let texture = loadable_assets.block_array.texture.clone();
+    chunk_material.transparent = materials.add(BlockMaterial {
+        texture: texture.clone(),
         alpha_mode: AlphaMode::Blend,
-        ..default()
     });
-    chunk_material.opaque = materials.add(StandardMaterial {
-        base_color: Color::WHITE,
-        base_color_texture: Some(
-            texture_atlas
-                .get(&loadable_assets.block_atlas)
-                .unwrap()
-                .texture
-                .clone(),
-        ),
-        perceptual_roughness: 1.0,
+    chunk_material.opaque = materials.add(BlockMaterial {
+        texture,
         alpha_mode: AlphaMode::Opaque,
-        ..default()
     });
 }
 
+// Rebuilds the texture array when block textures are edited or the content changes in game. Every chunk
+// gets remeshed since the layers get numbered again
+#[allow(clippy::too_many_arguments)]
+pub fn reload_block_textures(
+    mut image_events: EventReader<AssetEvent<Image>>,
+    asset_server: Res<AssetServer>,
+    mut loadable_assets: ResMut<LoadableAssets>,
+    mut textures: ResMut<Assets<Image>>,
+    chunk_material: Res<ChunkMaterial>,
+    mut materials: ResMut<Assets<BlockMaterial>>,
+    current_chunks: Res<CurrentChunks>,
+    mut mesh_event: EventWriter<MeshChunkEvent>,
+    render_device: Res<RenderDevice>,
+) {
+    for event in image_events.iter() {
+        if let AssetEvent::Modified { handle } = event {
+            if loadable_assets
+                .block_textures
+                .values()
+                .flatten()
+                .any(|texture| texture == handle)
+            {
+                loadable_assets.textures_outdated = true;
+            }
+        }
+    }
+    if !loadable_assets.textures_outdated {
+        return;
+    }
+    let handles: Vec<HandleId> = loadable_assets
+        .block_textures
+        .values()
+        .flatten()
+        .map(|texture| texture.id)
+        .collect();
+    match asset_server.get_group_load_state(handles) {
+        LoadState::Loaded => {}
+        LoadState::Failed => {
+            warn!("Couldn't load the block textures, keeping the old ones");
+            loadable_assets.textures_outdated = false;
+            return;
+        }
+        _ => return,
+    }
+    let block_array = build_block_textures(
+        &loadable_assets,
+        &asset_server,
+        &mut textures,
+        render_device.limits().max_texture_array_layers,
+    );
+    for handle in [&chunk_material.opaque, &chunk_material.transparent] {
+        if let Some(material) = materials.get_mut(handle) {
+            material.texture = block_array.texture.clone();
+        }
+    }
+    loadable_assets.block_array = block_array;
+    loadable_assets.textures_outdated = false;
+    for pos in current_chunks.chunks.keys() {
+        mesh_event.send(MeshChunkEvent { pos: *pos });
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn process_task(
     mut commands: Commands,
@@ -568,7 +971,7 @@ pub fn process_task(
                                     (CHUNK_SIZE / 2) as f32,
                                 ),
                             },
-                            mesh: PbrBundle {
+                            mesh: MaterialMeshBundle {
                                 mesh: meshes.add(chunk.transparent_mesh.clone()),
                                 material: chunk_material.transparent.clone(),
                                 ..Default::default()
@@ -594,7 +997,7 @@ pub fn process_task(
                                 (CHUNK_SIZE / 2) as f32,
                             ),
                         },
-                        mesh: PbrBundle {
+                        mesh: MaterialMeshBundle {
                             mesh: meshes.add(chunk.chunk_mesh.clone()),
                             material: chunk_material.opaque.clone(),
                             transform: Transform::from_translation(chunk_pos),
@@ -606,7 +1009,23 @@ pub fn process_task(
                     },
                 ));
 
+                // Models get respawned with the mesh so they follow block edits
+                let model_entities: Vec<Entity> = chunk
+                    .models
+                    .iter()
+                    .map(|(scene, transform)| {
+                        commands
+                            .spawn(SceneBundle {
+                                scene: scene.clone(),
+                                transform: *transform,
+                                ..default()
+                            })
+                            .id()
+                    })
+                    .collect();
+
                 commands.entity(chunk_entity).push_children(&[trans_entity]);
+                commands.entity(chunk_entity).push_children(&model_entities);
                 commands.entity(entity).despawn_recursive();
             } else {
                 commands.entity(entity).despawn_recursive();
@@ -620,179 +1039,59 @@ pub fn process_queue(
     mut chunk_queue: ResMut<ChunkQueue>,
     mut commands: Commands,
     loadable_assets: ResMut<LoadableAssets>,
-    loadable_types: Res<LoadableTypes>,
-    texture_atlas: Res<Assets<TextureAtlas>>,
+    block_registry: Res<BlockRegistry>,
     _meshes: ResMut<Assets<Mesh>>,
     _materials: ResMut<Assets<StandardMaterial>>,
     _current_chunks: ResMut<CurrentChunks>,
 ) {
     //TODO: Look into some other way to do this and profile it. Lots of clones for every chunk
     let task_pool = AsyncComputeTaskPool::get();
-    let block_atlas: TextureAtlas = texture_atlas
-        .get(&loadable_assets.block_atlas)
-        .unwrap()
-        .clone();
     chunk_queue
         .mesh
         .drain(..)
-        .map(|(chunk_pos, raw_chunk)| {
-            let cloned_types: LoadableTypes = loadable_types.clone();
+        .map(|(chunk_pos, padded_chunk, padded_light)| {
+            let cloned_registry: BlockRegistry = block_registry.clone();
             let cloned_assets: LoadableAssets = loadable_assets.clone();
-            let clone_atlas: TextureAtlas = block_atlas.clone();
             (
                 chunk_pos,
                 ChunkGenTask(task_pool.spawn(async move {
-                    let mesh_result = generate_mesh(&raw_chunk, &cloned_types, true);
-                    let mut positions = Vec::new();
-                    let mut indices = Vec::new();
-                    let mut normals = Vec::new();
-                    let mut uvs = Vec::new();
-                    let mut ao = Vec::new();
-                    for face in mesh_result.iter_with_ao(&raw_chunk, &cloned_types) {
-                        indices.extend_from_slice(&face.indices(positions.len() as u32));
-                        positions.extend_from_slice(&face.positions(1.0)); // Voxel size is 1m
-                        normals.extend_from_slice(&face.normals());
-                        ao.extend_from_slice(&face.aos());
-
-                        let matched_index = match (face.side.axis, face.side.positive) {
-                            (Axis::X, false) => 2,
-                            (Axis::X, true) => 3,
-                            (Axis::Y, false) => 1,
-                            (Axis::Y, true) => 0,
-                            (Axis::Z, false) => 5,
-                            (Axis::Z, true) => 4,
-                        };
-                        if let Some(texture_index) = clone_atlas.get_texture_index(
-                            &cloned_assets
-                                .block_textures
-                                .get(
-                                    &raw_chunk
-                                        .get_state_for_index(
-                                            raw_chunk.voxels[RawChunk::linearize(UVec3::new(
-                                                face.voxel()[0] as u32,
-                                                face.voxel()[1] as u32,
-                                                face.voxel()[2] as u32,
-                                            ))]
-                                                as usize,
-                                        )
-                                        .unwrap(),
-                                )
-                                .unwrap()[matched_index],
-                        ) {
-                            let face_coords = calculate_coords(
-                                texture_index,
-                                Vec2::new(16.0, 16.0),
-                                clone_atlas.size,
-                            );
-                            uvs.push(face_coords[0]);
-                            uvs.push(face_coords[1]);
-                            uvs.push(face_coords[2]);
-                            uvs.push(face_coords[3]);
-                        } else {
-                            uvs.extend_from_slice(&face.uvs(false, false));
-                        }
-                    }
-                    let col_vertices = positions
-                        .iter()
-                        .cloned()
-                        .map(Vec3::from_array)
-                        .collect::<Vec<_>>();
-
-                    let col_indices = indices
-                        .iter()
-                        .cloned()
-                        .tuples::<(u32, u32, u32)>()
-                        .map(|(x, y, z)| [x, y, z])
-                        .collect::<Vec<_>>();
-                    let final_ao = ao_convert(ao);
-                    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
-                    let collider = if !indices.is_empty() {
-                        Collider::trimesh(col_vertices, col_indices)
-                    } else {
-                        Collider::cuboid(0.0, 0.0, 0.0)
-                    };
-                    mesh.set_indices(Some(Indices::U32(indices)));
-                    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions.clone());
-                    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
-                    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
-                    mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, final_ao);
+                    // Opaque faces get merged, transparent ones are kept one per voxel so they can be sorted
+                    let mesh_result = generate_mesh(&padded_chunk, &cloned_registry, true).merge(
+                        &padded_chunk,
+                        &padded_light,
+                        &cloned_registry,
+                    );
+                    let shapes =
+                        generate_shapes(&padded_chunk, &padded_light, &cloned_registry, true);
+                    let (mesh, collider) = build_pass_mesh(
+                        &mesh_result,
+                        &shapes,
+                        &padded_chunk,
+                        &padded_light,
+                        &cloned_registry,
+                        &cloned_assets,
+                    );
 
                     //Transparent Mesh
-                    let mesh_result = generate_mesh(&raw_chunk, &cloned_types, false);
-                    let mut positions = Vec::new();
-                    let mut indices = Vec::new();
-                    let mut normals = Vec::new();
-                    let mut uvs = Vec::new();
-                    for face in mesh_result.iter() {
-                        indices.extend_from_slice(&face.indices(positions.len() as u32));
-                        positions.extend_from_slice(&face.positions(1.0)); // Voxel size is 1m
-                        normals.extend_from_slice(&face.normals());
-
-                        let matched_index = match (face.side.axis, face.side.positive) {
-                            (Axis::X, false) => 2,
-                            (Axis::X, true) => 3,
-                            (Axis::Y, false) => 1,
-                            (Axis::Y, true) => 0,
-                            (Axis::Z, false) => 5,
-                            (Axis::Z, true) => 4,
-                        };
-                        if let Some(texture_index) = clone_atlas.get_texture_index(
-                            &cloned_assets
-                                .block_textures
-                                .get(
-                                    &raw_chunk
-                                        .get_state_for_index(
-                                            raw_chunk.voxels[RawChunk::linearize(UVec3::new(
-                                                face.voxel()[0] as u32,
-                                                face.voxel()[1] as u32,
-                                                face.voxel()[2] as u32,
-                                            ))]
-                                                as usize,
-                                        )
-                                        .unwrap(),
-                                )
-                                .unwrap()[matched_index],
-                        ) {
-                            let face_coords = calculate_coords(
-                                texture_index,
-                                Vec2::new(16.0, 16.0),
-                                clone_atlas.size,
-                            );
-                            uvs.push(face_coords[0]);
-                            uvs.push(face_coords[1]);
-                            uvs.push(face_coords[2]);
-                            uvs.push(face_coords[3]);
-                        } else {
-                            uvs.extend_from_slice(&face.uvs(false, false));
-                        }
-                    }
-                    let col_vertices = positions
-                        .iter()
-                        .cloned()
-                        .map(Vec3::from_array)
-                        .collect::<Vec<_>>();
-
-                    let col_indices = indices
-                        .iter()
-                        .cloned()
-                        .tuples::<(u32, u32, u32)>()
-                        .map(|(x, y, z)| [x, y, z])
-                        .collect::<Vec<_>>();
-                    let mut transparent_mesh = Mesh::new(PrimitiveTopology::TriangleList);
-                    let transparent_collider = if !indices.is_empty() {
-                        Collider::trimesh(col_vertices, col_indices)
-                    } else {
-                        Collider::cuboid(0.0, 0.0, 0.0)
-                    };
-                    transparent_mesh.set_indices(Some(Indices::U32(indices)));
-                    transparent_mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions.clone());
-                    transparent_mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
-                    transparent_mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+                    let mesh_result = generate_mesh(&padded_chunk, &cloned_registry, false);
+                    let shapes =
+                        generate_shapes(&padded_chunk, &padded_light, &cloned_registry, false);
+                    let (transparent_mesh, transparent_collider) = build_pass_mesh(
+                        &mesh_result,
+                        &shapes,
+                        &padded_chunk,
+                        &padded_light,
+                        &cloned_registry,
+                        &cloned_assets,
+                    );
+
+                    let models = block_models(&padded_chunk, &cloned_registry, &cloned_assets);
 
                     MeshedChunk {
                         transparent_mesh,
                         transparent_collider,
                         chunk_mesh: mesh,
+                        models,
                         pos: chunk_pos,
                         collider,
                     }
@@ -804,37 +1103,155 @@ pub fn process_queue(
         });
 }
 
-pub fn calculate_coords(index: usize, tile_size: Vec2, tilesheet_size: Vec2) -> [[f32; 2]; 4] {
-    let mut face_tex = [[0.0; 2]; 4];
-    let mut index = index as f32;
-    // We need to start at 1.0 for calculations
-    index += 1.0;
-    let max_y = (tile_size.y) / tilesheet_size.y;
-    face_tex[2][0] = ((index - 1.0) * tile_size.x) / tilesheet_size.x;
-    // face_tex[0][1] = ((index - 1.0) * tile_size.x) / tilesheet_size.x;
-    face_tex[2][1] = 0.0;
-    face_tex[3][0] = (index * tile_size.x) / tilesheet_size.x;
-    // face_tex[1][1] = ((index - 1.0) * tile_size.x) / tilesheet_size.x;
-    face_tex[3][1] = 0.0;
-    face_tex[0][0] = ((index - 1.0) * tile_size.x) / tilesheet_size.x;
-    // face_tex[2][1] = (index * tile_size.x) / tilesheet_size.x;
-    face_tex[0][1] = max_y;
-    face_tex[1][0] = (index * tile_size.x) / tilesheet_size.x;
-    // face_tex[3][1] = (index * tile_size.x) / tilesheet_size.x;
-    face_tex[1][1] = max_y;
-    face_tex
-}
-
-fn ao_convert(ao: Vec<u32>) -> Vec<[f32; 4]> {
-    let mut res = Vec::new();
-    for value in ao {
-        match value {
-            0 => res.extend_from_slice(&[[0.1, 0.1, 0.1, 1.0]]),
-            1 => res.extend_from_slice(&[[0.25, 0.25, 0.25, 1.0]]),
-            2 => res.extend_from_slice(&[[0.5, 0.5, 0.5, 1.0]]),
-            _ => res.extend_from_slice(&[[1., 1., 1., 1.0]]),
+fn build_pass_mesh(
+    mesh_result: &QuadGroups,
+    shapes: &[ShapeFace],
+    padded_chunk: &PaddedChunkView,
+    padded_light: &PaddedLightView,
+    registry: &BlockRegistry,
+    assets: &LoadableAssets,
+) -> (Mesh, Collider) {
+    let mut positions = Vec::new();
+    let mut indices = Vec::new();
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    let mut layers = Vec::new();
+    let mut ao = Vec::new();
+    let mut lights = Vec::new();
+    for face in mesh_result.iter_with_ao(padded_chunk, registry) {
+        indices.extend_from_slice(&face.indices(positions.len() as u32));
+        positions.extend_from_slice(&face.positions(1.0)); // Voxel size is 1m
+        normals.extend_from_slice(&face.normals());
+        uvs.extend_from_slice(&face.tile_uvs());
+        ao.extend_from_slice(&face.aos());
+        lights.extend_from_slice(&face_lights(&face, padded_chunk, padded_light, registry));
+        let layer = texture_layer(face.side, face.voxel(), padded_chunk, assets);
+        layers.extend_from_slice(&[layer; 4]);
+    }
+    // Shapes go in the same mesh so the collider matches them too
+    for face in shapes.iter() {
+        indices.extend_from_slice(&face.indices(positions.len() as u32));
+        positions.extend_from_slice(&face.positions());
+        normals.extend_from_slice(&face.side.normals());
+        uvs.extend_from_slice(&face.uvs());
+        ao.extend_from_slice(&[3; 4]);
+        lights.extend_from_slice(&[face.light; 4]);
+        let layer = texture_layer(face.side, face.voxel, padded_chunk, assets);
+        layers.extend_from_slice(&[layer; 4]);
+    }
+    let col_vertices = positions
+        .iter()
+        .cloned()
+        .map(Vec3::from_array)
+        .collect::<Vec<_>>();
+
+    let col_indices = indices
+        .iter()
+        .cloned()
+        .tuples::<(u32, u32, u32)>()
+        .map(|(x, y, z)| [x, y, z])
+        .collect::<Vec<_>>();
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    let collider = if !indices.is_empty() {
+        Collider::trimesh(col_vertices, col_indices)
+    } else {
+        Collider::cuboid(0.0, 0.0, 0.0)
+    };
+    mesh.set_indices(Some(Indices::U32(indices)));
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, vertex_colors(ao, lights));
+    mesh.insert_attribute(ATTRIBUTE_LAYER, layers);
+    (mesh, collider)
+}
+
+// Every block in the chunk drawn with a model, as the scene and where it goes relative to the chunk. Models
+// sit on the bottom middle of their voxel and face north unless their state has another facing
+fn block_models(
+    chunk: &PaddedChunkView,
+    registry: &BlockRegistry,
+    assets: &LoadableAssets,
+) -> Vec<(Handle<Scene>, Transform)> {
+    let models: Vec<Option<(Handle<Scene>, Quat)>> = chunk
+        .palette()
+        .iter()
+        .enumerate()
+        .map(|(palette_index, state)| {
+            let block_id = chunk.block_id(palette_index as u16);
+            let scene = assets.block_models.get(registry.name(block_id)?)?;
+            let block_state =
+                BlockState::parse_typed(state, registry.get(block_id)?).unwrap_or_default();
+            let angle = match block_state.get_enum("facing") {
+                Some("east") => -FRAC_PI_2,
+                Some("south") => PI,
+                Some("west") => FRAC_PI_2,
+                _ => 0.0,
+            };
+            Some((scene.clone(), Quat::from_rotation_y(angle)))
+        })
+        .collect();
+    if models.iter().all(|model| model.is_none()) {
+        return Vec::new();
+    }
+
+    let mut result = Vec::new();
+    for z in 1..PaddedChunkView::Z - 1 {
+        for y in 1..PaddedChunkView::Y - 1 {
+            for x in 1..PaddedChunkView::X - 1 {
+                let pos = UVec3::new(x as u32, y as u32, z as u32);
+                if let Some((scene, rotation)) = &models[chunk.palette_index(pos) as usize] {
+                    result.push((
+                        scene.clone(),
+                        Transform::from_xyz(x as f32 - 0.5, y as f32 - 1.0, z as f32 - 0.5)
+                            .with_rotation(*rotation),
+                    ));
+                }
+            }
         }
     }
+    result
+}
+
+// The texture array layer for one side of a voxel
+fn texture_layer(
+    side: Side,
+    voxel: [usize; 3],
+    padded_chunk: &PaddedChunkView,
+    assets: &LoadableAssets,
+) -> u32 {
+    let matched_index = match (side.axis, side.positive) {
+        (Axis::X, false) => 2,
+        (Axis::X, true) => 3,
+        (Axis::Y, false) => 1,
+        (Axis::Y, true) => 0,
+        (Axis::Z, false) => 5,
+        (Axis::Z, true) => 4,
+    };
+    // Blocks that failed to load have no textures, they get the missing texture instead of taking the mesher down
+    padded_chunk
+        .get_block(UVec3::new(
+            voxel[0] as u32,
+            voxel[1] as u32,
+            voxel[2] as u32,
+        ))
+        .and_then(|block| assets.block_textures.get(BlockState::block_id_of(&block)))
+        .map(|textures| assets.block_array.layer(&textures[matched_index]))
+        .unwrap_or(MISSING_LAYER)
+}
+
+// AO and light are both baked into the vertex color, one entry in each per vertex
+fn vertex_colors(ao: Vec<u32>, lights: Vec<f32>) -> Vec<[f32; 4]> {
+    let mut res = Vec::new();
+    for (value, light) in ao.into_iter().zip(lights) {
+        let ao = match value {
+            0 => 0.1,
+            1 => 0.25,
+            2 => 0.5,
+            _ => 1.0,
+        };
+        res.push([ao * light, ao * light, ao * light, 1.0]);
+    }
     res
 }
 
@@ -959,3 +1376,60 @@ pub fn sort_chunks(
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bevy::prelude::*;
+    use common::game::{
+        storage::BlockType,
+        world::{
+            chunk::{PaddedChunkView, RawChunk, VoxelVisibility, CHUNK_SIZE},
+            light::{LightChunk, PaddedLightView},
+            registry::BlockRegistry,
+        },
+    };
+
+    use super::{generate_mesh, QuadGroups};
+
+    fn area(quads: &QuadGroups) -> u32 {
+        quads
+            .iter()
+            .map(|face| face.quad.width * face.quad.height)
+            .sum()
+    }
+
+    #[test]
+    fn greedy_flat_chunk() {
+        let stone = BlockType::test("stone", VoxelVisibility::Opaque, 0);
+        let registry =
+            BlockRegistry::from_blocks(&HashMap::from([("vinox:stone".to_string(), stone)]));
+
+        // Bottom half stone with nothing around it
+        let mut chunk = RawChunk::new();
+        chunk.add_block_state("vinox:stone");
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE / 2 {
+                for z in 0..CHUNK_SIZE {
+                    chunk.set_block(UVec3::new(x, y, z), "vinox:stone".to_string());
+                }
+            }
+        }
+        let air = RawChunk::new();
+        let padded_chunk = PaddedChunkView::new(&chunk, [&air; 6], &registry);
+        let mut light = LightChunk::default();
+        light.calculate_light(&chunk, [None; 6], &registry);
+        let padded_light = PaddedLightView::new(&light, [None; 6]);
+
+        let naive = generate_mesh(&padded_chunk, &registry, true);
+        let greedy = naive.merge(&padded_chunk, &padded_light, &registry);
+        let (naive_triangles, greedy_triangles) = (naive.len() * 2, greedy.len() * 2);
+
+        let side = CHUNK_SIZE as usize;
+        assert_eq!(naive_triangles, (2 * side * side + 4 * side * side / 2) * 2);
+        assert!(greedy_triangles * 10 <= naive_triangles);
+        // Merging can't lose or double up any surface
+        assert_eq!(area(&naive), area(&greedy));
+    }
+}
@@ -1,2 +1,4 @@
+pub mod material;
 pub mod meshing;
 pub mod plugin;
+pub mod texture_array;
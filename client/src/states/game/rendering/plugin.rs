@@ -1,21 +1,32 @@
 use crate::components::GameState;
-use bevy::prelude::*;
+use bevy::{asset::load_internal_asset, prelude::*};
 use bevy_atmosphere::prelude::AtmospherePlugin;
 use iyes_loopless::prelude::*;
 
-use super::meshing::{
-    create_chunk_material, process_queue, process_task, sort_chunks, sort_faces, ChunkMaterial,
-    MeshChunkEvent, SortFaces,
+use super::{
+    material::{BlockMaterial, CHUNK_SHADER_HANDLE},
+    meshing::{
+        create_chunk_material, process_queue, process_task, reload_block_textures, sort_chunks,
+        sort_faces, ChunkMaterial, MeshChunkEvent, SortFaces,
+    },
 };
 
 pub struct RenderingPlugin;
 
 impl Plugin for RenderingPlugin {
     fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            CHUNK_SHADER_HANDLE,
+            "../../../../assets/shaders/chunk.wgsl",
+            Shader::from_wgsl
+        );
         app.add_plugin(AtmospherePlugin)
+            .add_plugin(MaterialPlugin::<BlockMaterial>::default())
             .insert_resource(ChunkMaterial::default())
             .add_enter_system(GameState::Game, create_chunk_material)
             .add_system(process_queue.run_in_state(GameState::Game))
+            .add_system(reload_block_textures.run_in_state(GameState::Game))
             .add_system(process_task.run_in_state(GameState::Game))
             .add_system(sort_faces.run_in_state(GameState::Game))
             .add_system(sort_chunks.run_in_state(GameState::Game))
@@ -0,0 +1,253 @@
+use std::collections::{HashMap, HashSet};
+
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{
+            AddressMode, Extent3d, FilterMode, SamplerDescriptor, TextureDescriptor,
+            TextureDimension, TextureFormat, TextureUsages, TextureViewDescriptor,
+            TextureViewDimension,
+        },
+        texture::ImageSampler,
+    },
+};
+
+use crate::states::loading::LoadableAssets;
+
+// Layer 0 is for faces whose texture didn't load
+pub const MISSING_LAYER: u32 = 0;
+const MISSING_COLORS: [[u8; 4]; 2] = [[255, 0, 255, 255], [0, 0, 0, 255]];
+
+// Every block texture as a layer of one texture array. Faces pick their layer per vertex and the sampler
+// repeats each layer on its own, so merged faces tile and mips never bleed into another texture
+#[derive(Default, Clone)]
+pub struct BlockTextureArray {
+    pub texture: Handle<Image>,
+    pub layers: HashMap<Handle<Image>, u32>,
+}
+
+impl BlockTextureArray {
+    pub fn layer(&self, texture: &Handle<Image>) -> u32 {
+        self.layers.get(texture).copied().unwrap_or(MISSING_LAYER)
+    }
+}
+
+// Nearest neighbour so pixel art stays sharp when textures of different sizes end up in the same array
+fn resample(image: &Image, size: u32) -> Vec<u8> {
+    let (width, height) = (
+        image.texture_descriptor.size.width,
+        image.texture_descriptor.size.height,
+    );
+    let mut data = Vec::with_capacity((size * size * 4) as usize);
+    for y in 0..size {
+        for x in 0..size {
+            let pixel = ((y * height / size) * width + x * width / size) as usize * 4;
+            data.extend_from_slice(&image.data[pixel..pixel + 4]);
+        }
+    }
+    data
+}
+
+// Averages every 2x2 block of pixels, size has to be a power of two
+fn next_mip(data: &[u8], size: u32) -> Vec<u8> {
+    let half = size / 2;
+    let mut mip = Vec::with_capacity((half * half * 4) as usize);
+    for y in 0..half {
+        for x in 0..half {
+            for channel in 0..4 {
+                let sum: u32 = [(0, 0), (1, 0), (0, 1), (1, 1)]
+                    .iter()
+                    .map(|(dx, dy)| {
+                        data[(((y * 2 + dy) * size + x * 2 + dx) * 4 + channel) as usize] as u32
+                    })
+                    .sum();
+                mip.push((sum / 4) as u8);
+            }
+        }
+    }
+    mip
+}
+
+// Every mip of one layer from biggest to smallest, down to 1x1
+fn mip_chain(layer: Vec<u8>, size: u32) -> Vec<Vec<u8>> {
+    let mut mips = vec![layer];
+    let mut mip_size = size;
+    while mip_size > 1 {
+        mips.push(next_mip(mips.last().unwrap(), mip_size));
+        mip_size /= 2;
+    }
+    mips
+}
+
+// Whether one more texture fits after the ones already taken, layer 0 always goes to the missing texture
+fn fits_another(taken: usize, max_layers: u32) -> bool {
+    taken as u32 + 2 <= max_layers
+}
+
+fn missing_texture(size: u32) -> Vec<u8> {
+    let mut data = Vec::with_capacity((size * size * 4) as usize);
+    for y in 0..size {
+        for x in 0..size {
+            let checker = (x * 2 / size + y * 2 / size) % 2;
+            data.extend_from_slice(&MISSING_COLORS[checker as usize]);
+        }
+    }
+    data
+}
+
+// Builds the array out of every loaded block texture. They all get resampled to the biggest size (rounded
+// up to a power of two) and get a full mip chain. Max layers is the device limit, textures past it show up
+// as missing
+pub fn build_block_textures(
+    loadable_assets: &LoadableAssets,
+    asset_server: &AssetServer,
+    textures: &mut Assets<Image>,
+    max_layers: u32,
+) -> BlockTextureArray {
+    let mut layers = HashMap::new();
+    let mut sources = Vec::new();
+    let mut skipped = HashSet::new();
+    for handle in loadable_assets.block_textures.values().flatten() {
+        if layers.contains_key(handle) || skipped.contains(handle) {
+            continue;
+        }
+        if !fits_another(sources.len(), max_layers) {
+            skipped.insert(handle.clone());
+            continue;
+        }
+        let Some(texture) = textures
+            .get(handle)
+            .and_then(|texture| texture.convert(TextureFormat::Rgba8UnormSrgb))
+        else {
+            warn!(
+                "{:?} did not resolve to an `Image` asset.",
+                asset_server.get_handle_path(handle)
+            );
+            continue;
+        };
+        layers.insert(handle.clone(), sources.len() as u32 + 1);
+        sources.push(texture);
+    }
+    if !skipped.is_empty() {
+        warn!(
+            "{} block textures didn't fit in the texture array ({max_layers} layers at most)",
+            skipped.len()
+        );
+    }
+
+    let size = sources
+        .iter()
+        .map(|texture| {
+            let size = texture.texture_descriptor.size;
+            size.width.max(size.height)
+        })
+        .max()
+        .unwrap_or(16)
+        .next_power_of_two();
+    let mip_level_count = size.trailing_zeros() + 1;
+    // Layer by layer, each with all its mips from biggest to smallest
+    let mut data = Vec::new();
+    for layer in [missing_texture(size)]
+        .into_iter()
+        .chain(sources.iter().map(|texture| resample(texture, size)))
+    {
+        for mip in mip_chain(layer, size) {
+            data.extend_from_slice(&mip);
+        }
+    }
+
+    let image = Image {
+        data,
+        texture_descriptor: TextureDescriptor {
+            label: Some("block_textures"),
+            size: Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: sources.len() as u32 + 1,
+            },
+            mip_level_count,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Rgba8UnormSrgb,
+            usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+        },
+        sampler_descriptor: ImageSampler::Descriptor(SamplerDescriptor {
+            address_mode_u: AddressMode::Repeat,
+            address_mode_v: AddressMode::Repeat,
+            mag_filter: FilterMode::Nearest,
+            min_filter: FilterMode::Nearest,
+            mipmap_filter: FilterMode::Linear,
+            ..default()
+        }),
+        // Has to be an array view even with a single layer
+        texture_view_descriptor: Some(TextureViewDescriptor {
+            dimension: Some(TextureViewDimension::D2Array),
+            ..default()
+        }),
+    };
+    BlockTextureArray {
+        texture: textures.add(image),
+        layers,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{
+        prelude::*,
+        render::render_resource::{Extent3d, TextureDimension, TextureFormat},
+    };
+
+    use super::{fits_another, mip_chain, resample};
+
+    #[test]
+    fn resamples_non_square() {
+        // 4x2, one gray value per pixel so they're easy to follow
+        let data: Vec<u8> = (0..8u8)
+            .flat_map(|value| [value, value, value, 255])
+            .collect();
+        let image = Image::new(
+            Extent3d {
+                width: 4,
+                height: 2,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            data,
+            TextureFormat::Rgba8UnormSrgb,
+        );
+        let resampled = resample(&image, 4);
+        assert_eq!(resampled.len(), 4 * 4 * 4);
+        let values: Vec<u8> = resampled.chunks(4).map(|pixel| pixel[0]).collect();
+        // Rows get stretched, columns stay the same
+        assert_eq!(values, [0, 1, 2, 3, 0, 1, 2, 3, 4, 5, 6, 7, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn full_mip_chain() {
+        let size = 16;
+        let layer: Vec<u8> = (0..size * size)
+            .flat_map(|pixel| [(pixel % 2) as u8 * 200, 0, 0, 255])
+            .collect();
+        let mips = mip_chain(layer, size);
+        // Same as the mip_level_count the array is made with
+        assert_eq!(mips.len() as u32, size.trailing_zeros() + 1);
+        for (level, mip) in mips.iter().enumerate() {
+            let mip_size = (size >> level) as usize;
+            assert_eq!(mip.len(), mip_size * mip_size * 4);
+        }
+        // Stripes average out to the middle
+        assert_eq!(mips[1][..4], [100, 0, 0, 255]);
+        assert_eq!(mips.last().unwrap()[..], [100, 0, 0, 255]);
+    }
+
+    #[test]
+    fn fills_every_layer() {
+        // The missing texture and one more
+        assert!(fits_another(0, 2));
+        assert!(!fits_another(1, 2));
+        // 255 textures and the missing one is exactly the default limit
+        assert!(fits_another(254, 256));
+        assert!(!fits_another(255, 256));
+    }
+}
@@ -1,23 +1,31 @@
 use bevy::{ecs::schedule::ShouldRun, prelude::*, render::primitives::Aabb, utils::FloatOrd};
 use bevy_rapier3d::prelude::Collider;
 
-use common::game::world::chunk::{
-    world_to_chunk, ChunkComp, ChunkPos, CurrentChunks, LoadableTypes, RawChunk, RemoveChunk,
-    SimulationDistance, ViewDistance, CHUNK_BOUND, CHUNK_SIZE,
+use common::game::world::{
+    chunk::{
+        world_to_chunk, ChunkComp, ChunkPos, CurrentChunks, LoadableTypes, PaddedChunkView,
+        RawChunk, RemoveChunk, SimulationDistance, ViewDistance, CHUNK_EDGE,
+    },
+    light::PaddedLightView,
 };
 
 use crate::states::game::{
     networking::components::ControlledPlayer,
-    rendering::meshing::{build_mesh, MeshChunkEvent},
+    rendering::{
+        material::BlockMaterial,
+        meshing::{build_mesh, MeshChunkEvent},
+    },
 };
 
+use super::lighting::{update_light, LightQueue};
+
 #[derive(Component)]
 pub struct DirtyChunk;
 
 #[derive(Bundle)]
 pub struct RenderedChunk {
     #[bundle]
-    pub mesh: PbrBundle,
+    pub mesh: MaterialMeshBundle<BlockMaterial>,
     pub aabb: Aabb,
     // pub collider: Collider,
 }
@@ -43,7 +51,7 @@ pub struct UpdateChunkEvent {
 
 #[derive(Default, Resource)]
 pub struct ChunkQueue {
-    pub mesh: Vec<(IVec3, RawChunk)>,
+    pub mesh: Vec<(IVec3, PaddedChunkView, PaddedLightView)>,
     pub remove: Vec<IVec3>,
 }
 
@@ -106,6 +114,7 @@ pub fn set_block(
     mut event: EventReader<SetBlockEvent>,
     current_chunks: Res<CurrentChunks>,
     mut chunks: Query<&mut ChunkComp>,
+    mut light_queue: ResMut<LightQueue>,
 ) {
     for evt in event.iter() {
         if let Some(chunk_entity) = current_chunks.get_entity(evt.chunk_pos) {
@@ -114,16 +123,17 @@ pub fn set_block(
                 chunk
                     .chunk_data
                     .set_block(evt.voxel_pos, evt.block_type.clone());
+                light_queue.blocks.push((evt.chunk_pos, evt.voxel_pos));
 
                 match evt.voxel_pos.x {
-                    1 => {
+                    0 => {
                         if let Some(neighbor_chunk) =
                             current_chunks.get_entity(evt.chunk_pos + IVec3::new(-1, 0, 0))
                         {
                             commands.entity(neighbor_chunk).insert(DirtyChunk);
                         }
                     }
-                    CHUNK_SIZE => {
+                    CHUNK_EDGE => {
                         if let Some(neighbor_chunk) =
                             current_chunks.get_entity(evt.chunk_pos + IVec3::new(1, 0, 0))
                         {
@@ -133,14 +143,14 @@ pub fn set_block(
                     _ => {}
                 }
                 match evt.voxel_pos.y {
-                    1 => {
+                    0 => {
                         if let Some(neighbor_chunk) =
                             current_chunks.get_entity(evt.chunk_pos + IVec3::new(0, -1, 0))
                         {
                             commands.entity(neighbor_chunk).insert(DirtyChunk);
                         }
                     }
-                    CHUNK_SIZE => {
+                    CHUNK_EDGE => {
                         if let Some(neighbor_chunk) =
                             current_chunks.get_entity(evt.chunk_pos + IVec3::new(0, 1, 0))
                         {
@@ -150,14 +160,14 @@ pub fn set_block(
                     _ => {}
                 }
                 match evt.voxel_pos.z {
-                    1 => {
+                    0 => {
                         if let Some(neighbor_chunk) =
                             current_chunks.get_entity(evt.chunk_pos + IVec3::new(0, 0, -1))
                         {
                             commands.entity(neighbor_chunk).insert(DirtyChunk);
                         }
                     }
-                    CHUNK_SIZE => {
+                    CHUNK_EDGE => {
                         if let Some(neighbor_chunk) =
                             current_chunks.get_entity(evt.chunk_pos + IVec3::new(0, 0, 1))
                         {
@@ -194,148 +204,42 @@ pub fn clear_unloaded_chunks(
 }
 
 // Dirty chunks get marked in the following cases. A new neighbor spawns by them, the terrain is modified, or if a neighbor disapears
-// This runs first then we remesh
-#[allow(clippy::type_complexity)]
-pub fn update_borders(
+// Once all of a dirty chunks neighbors are loaded it gets sent off to be meshed with their borders
+pub fn queue_dirty_chunks(
     mut commands: Commands,
-    current_chunks: ResMut<CurrentChunks>,
-    mut chunk_set: ParamSet<(
-        Query<(&ChunkComp, Entity), With<DirtyChunk>>,
-        Query<&mut ChunkComp>,
-    )>,
+    current_chunks: Res<CurrentChunks>,
+    dirty_chunks: Query<&ChunkComp, With<DirtyChunk>>,
     mut mesh_event: EventWriter<MeshChunkEvent>,
-    view_distance: Res<ViewDistance>,
     player_chunk: Res<PlayerChunk>,
 ) {
-    let mut dirty_chunk_positions = Vec::new();
     let mut sorted_chunk_positions = Vec::new();
-    for dirty_chunk in chunk_set.p0().iter() {
-        sorted_chunk_positions.push(dirty_chunk.0.pos.0);
+    for dirty_chunk in dirty_chunks.iter() {
+        sorted_chunk_positions.push(dirty_chunk.pos.0);
     }
 
     sorted_chunk_positions.sort_unstable_by_key(|key| {
         FloatOrd(key.as_vec3().distance(player_chunk.chunk_pos.as_vec3()))
     });
 
-    for dirty_chunk in sorted_chunk_positions.iter() {
-        if dirty_chunk_positions.len() > 24 {
-            break;
-        }
-        dirty_chunk_positions.push(*dirty_chunk);
-    }
-
-    for dirty_chunk_pos in dirty_chunk_positions.iter() {
-        if current_chunks.get_entity(*dirty_chunk_pos).is_some() {
-            if current_chunks.all_neighbors_exist(
-                *dirty_chunk_pos,
-                IVec2::new(-view_distance.horizontal, -view_distance.vertical),
-                IVec2::new(view_distance.horizontal, view_distance.vertical),
-            ) {
-                let dirty_entity = current_chunks.get_entity(*dirty_chunk_pos).unwrap();
-                let neighbor_entities = [
-                    current_chunks.get_entity(*dirty_chunk_pos).unwrap(),
-                    current_chunks
-                        .get_entity(*dirty_chunk_pos + IVec3::new(0, -1, 0))
-                        .unwrap(),
-                    current_chunks
-                        .get_entity(*dirty_chunk_pos + IVec3::new(0, 1, 0))
-                        .unwrap(),
-                    current_chunks
-                        .get_entity(*dirty_chunk_pos + IVec3::new(-1, 0, 0))
-                        .unwrap(),
-                    current_chunks
-                        .get_entity(*dirty_chunk_pos + IVec3::new(1, 0, 0))
-                        .unwrap(),
-                    current_chunks
-                        .get_entity(*dirty_chunk_pos + IVec3::new(0, 0, -1))
-                        .unwrap(),
-                    current_chunks
-                        .get_entity(*dirty_chunk_pos + IVec3::new(0, 0, 1))
-                        .unwrap(),
-                ];
-                let mut new_chunk: RawChunk = RawChunk::default();
-                if let Ok(chunk_data) = chunk_set.p1().get_many_mut(neighbor_entities) {
-                    if chunk_data[0].chunk_data.palette == vec!["air".to_string()] {
-                        commands.entity(dirty_entity).remove::<DirtyChunk>();
-                        continue;
-                    }
-                    // TODO: Try to figure out a better way to do this
-                    let mut chunk_data_cloned = chunk_data.map(|x| x.chunk_data.clone());
-                    for z in 0..=CHUNK_BOUND {
-                        for y in 0..=CHUNK_BOUND {
-                            for x in 0..=CHUNK_BOUND {
-                                match (x, y, z) {
-                                    (1..=CHUNK_SIZE, CHUNK_BOUND, 1..=CHUNK_SIZE) => {
-                                        let block_string = chunk_data_cloned[2]
-                                            .get_block(UVec3::new(x, 1, z))
-                                            .unwrap();
-                                        chunk_data_cloned[0].add_block_state(&block_string);
-                                        chunk_data_cloned[0]
-                                            .set_block(UVec3::new(x, y, z), block_string);
-                                    }
-                                    (1..=CHUNK_SIZE, 0, 1..=CHUNK_SIZE) => {
-                                        let block_string = chunk_data_cloned[1]
-                                            .get_block(UVec3::new(x, CHUNK_SIZE, z))
-                                            .unwrap();
-                                        chunk_data_cloned[0].add_block_state(&block_string);
-                                        chunk_data_cloned[0]
-                                            .set_block(UVec3::new(x, y, z), block_string);
-                                    }
-                                    (0, 1..=CHUNK_SIZE, 1..=CHUNK_SIZE) => {
-                                        let block_string = chunk_data_cloned[3]
-                                            .get_block(UVec3::new(CHUNK_SIZE, y, z))
-                                            .unwrap();
-                                        chunk_data_cloned[0].add_block_state(&block_string);
-                                        chunk_data_cloned[0]
-                                            .set_block(UVec3::new(x, y, z), block_string);
-                                    }
-                                    (CHUNK_BOUND, 1..=CHUNK_SIZE, 1..=CHUNK_SIZE) => {
-                                        let block_string = chunk_data_cloned[4]
-                                            .get_block(UVec3::new(1, y, z))
-                                            .unwrap();
-                                        chunk_data_cloned[0].add_block_state(&block_string);
-                                        chunk_data_cloned[0]
-                                            .set_block(UVec3::new(x, y, z), block_string);
-                                    }
-                                    (1..=CHUNK_SIZE, 1..=CHUNK_SIZE, 0) => {
-                                        let block_string = chunk_data_cloned[5]
-                                            .get_block(UVec3::new(x, y, CHUNK_SIZE))
-                                            .unwrap();
-                                        chunk_data_cloned[0].add_block_state(&block_string);
-                                        chunk_data_cloned[0]
-                                            .set_block(UVec3::new(x, y, z), block_string);
-                                    }
-                                    (1..=CHUNK_SIZE, 1..=CHUNK_SIZE, CHUNK_BOUND) => {
-                                        let block_string = chunk_data_cloned[6]
-                                            .get_block(UVec3::new(x, y, 1))
-                                            .unwrap();
-                                        chunk_data_cloned[0].add_block_state(&block_string);
-                                        chunk_data_cloned[0]
-                                            .set_block(UVec3::new(x, y, z), block_string);
-                                    }
-                                    (_, _, _) => {}
-                                };
-                            }
-                        }
-                    }
-                    new_chunk = chunk_data_cloned[0].clone();
+    for dirty_chunk_pos in sorted_chunk_positions.iter().take(25) {
+        if let Some(dirty_entity) = current_chunks.get_entity(*dirty_chunk_pos) {
+            if current_chunks.get_neighbors(*dirty_chunk_pos).is_some() {
+                if !dirty_chunks
+                    .get(dirty_entity)
+                    .unwrap()
+                    .chunk_data
+                    .is_empty()
+                {
+                    mesh_event.send(MeshChunkEvent {
+                        pos: *dirty_chunk_pos,
+                    });
                 }
-                let mut chunk_set = chunk_set.p1();
-                let mut chunk_data = chunk_set.get_mut(neighbor_entities[0]).unwrap();
-                chunk_data.chunk_data = new_chunk.to_owned();
-                mesh_event.send(MeshChunkEvent {
-                    pos: *dirty_chunk_pos,
-                });
                 commands.entity(dirty_entity).remove::<DirtyChunk>();
             }
-        } else {
-            // let dirty_entity = current_chunks.get_entity(*dirty_chunk_pos).unwrap();
-            // commands.entity(dirty_entity).remove::<DirtyChunk>();
         }
     }
 }
 
-#[allow(clippy::nonminimal_bool)]
 pub fn receive_chunks(
     mut current_chunks: ResMut<CurrentChunks>,
     mut commands: Commands,
@@ -344,6 +248,7 @@ pub fn receive_chunks(
     player_chunk: Res<PlayerChunk>,
     view_distance: Res<ViewDistance>,
     _loadable_types: Res<LoadableTypes>,
+    mut light_queue: ResMut<LightQueue>,
 ) {
     for evt in event.iter() {
         if player_chunk.is_in_radius(
@@ -351,6 +256,7 @@ pub fn receive_chunks(
             IVec2::new(-view_distance.horizontal, -view_distance.vertical),
             IVec2::new(view_distance.horizontal, view_distance.vertical),
         ) {
+            light_queue.relight.insert(evt.pos);
             if let Some(chunk_id) = current_chunks.get_entity(evt.pos) {
                 commands.entity(chunk_id).insert(ChunkComp {
                     pos: ChunkPos(evt.pos),
@@ -358,7 +264,7 @@ pub fn receive_chunks(
                     saved_entities: Vec::new(),
                     entities: Vec::new(),
                 });
-                if !(evt.raw_chunk.palette == vec!["air".to_string()]) {
+                if !evt.raw_chunk.is_empty() {
                     commands.entity(chunk_id).insert(DirtyChunk);
                 }
             } else {
@@ -371,7 +277,7 @@ pub fn receive_chunks(
                     })
                     .id();
                 current_chunks.insert_entity(evt.pos, chunk_id);
-                if !(evt.raw_chunk.palette == vec!["air".to_string()]) {
+                if !evt.raw_chunk.is_empty() {
                     commands.entity(chunk_id).insert(DirtyChunk);
                 }
             }
@@ -385,6 +291,7 @@ impl Plugin for ChunkHandling {
     fn build(&self, app: &mut App) {
         app.insert_resource(CurrentChunks::default())
             .insert_resource(ChunkQueue::default())
+            .insert_resource(LightQueue::default())
             .insert_resource(PlayerChunk::default())
             .insert_resource(PlayerChangedPos::default())
             .insert_resource(ViewDistance {
@@ -397,9 +304,10 @@ impl Plugin for ChunkHandling {
                 depth: 4,
             })
             .add_system(update_player_location)
-            .add_system(update_borders.after(update_player_location))
-            .add_system(receive_chunks.after(update_borders))
-            .add_system(set_block.after(update_borders))
+            .add_system(queue_dirty_chunks.after(update_player_location))
+            .add_system(receive_chunks.after(queue_dirty_chunks))
+            .add_system(set_block.after(queue_dirty_chunks))
+            .add_system(update_light.after(receive_chunks).after(set_block))
             .add_system(
                 clear_unloaded_chunks
                     .after(receive_chunks)
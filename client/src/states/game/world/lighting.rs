@@ -0,0 +1,114 @@
+use bevy::{prelude::*, utils::FloatOrd};
+
+use common::game::world::{
+    chunk::{ChunkComp, CurrentChunks, NEIGHBOR_OFFSETS},
+    light::LightChunk,
+    registry::BlockRegistry,
+};
+use std::collections::{HashMap, HashSet};
+
+use super::chunk::{DirtyChunk, PlayerChunk};
+
+// Chunks waiting on light. Whole chunks get relit when they arrive or a neighbours border light changes,
+// single blocks that were placed or broken are relit incrementally
+#[derive(Default, Resource)]
+pub struct LightQueue {
+    pub relight: HashSet<IVec3>,
+    pub blocks: Vec<(IVec3, UVec3)>,
+}
+
+pub fn update_light(
+    mut commands: Commands,
+    mut light_queue: ResMut<LightQueue>,
+    current_chunks: Res<CurrentChunks>,
+    chunks: Query<&ChunkComp>,
+    lights: Query<&LightChunk>,
+    block_registry: Res<BlockRegistry>,
+    player_chunk: Res<PlayerChunk>,
+) {
+    // Inserting through commands doesn't happen until the end of the frame so keep anything relit this frame
+    // around to be read back by its neighbours
+    let mut updated: HashMap<IVec3, LightChunk> = HashMap::new();
+    let mut changed_faces = Vec::new();
+
+    let block_updates: Vec<(IVec3, UVec3)> = light_queue.blocks.drain(..).collect();
+    for (chunk_pos, voxel_pos) in block_updates {
+        let Some(chunk_entity) = current_chunks.get_entity(chunk_pos) else {
+            continue;
+        };
+        let Ok(chunk) = chunks.get(chunk_entity) else {
+            continue;
+        };
+        let Some(mut light) = updated
+            .remove(&chunk_pos)
+            .or_else(|| lights.get(chunk_entity).ok().cloned())
+        else {
+            // Never been lit so there is nothing to update
+            light_queue.relight.insert(chunk_pos);
+            continue;
+        };
+        let neighbors = NEIGHBOR_OFFSETS.map(|offset| {
+            updated.get(&(chunk_pos + offset)).or_else(|| {
+                current_chunks
+                    .get_entity(chunk_pos + offset)
+                    .and_then(|entity| lights.get(entity).ok())
+            })
+        });
+        let faces = light.update_block(voxel_pos, &chunk.chunk_data, neighbors, &block_registry);
+        changed_faces.push((chunk_pos, faces));
+        updated.insert(chunk_pos, light);
+    }
+
+    let mut sorted_chunk_positions: Vec<IVec3> = light_queue.relight.iter().copied().collect();
+    sorted_chunk_positions.sort_unstable_by_key(|key| {
+        FloatOrd(key.as_vec3().distance(player_chunk.chunk_pos.as_vec3()))
+    });
+    for chunk_pos in sorted_chunk_positions.into_iter().take(25) {
+        let Some(chunk_entity) = current_chunks.get_entity(chunk_pos) else {
+            light_queue.relight.remove(&chunk_pos);
+            continue;
+        };
+        // Chunks received this frame aren't spawned yet, leave them for next frame
+        let Ok(chunk) = chunks.get(chunk_entity) else {
+            continue;
+        };
+        light_queue.relight.remove(&chunk_pos);
+        // Start from the old light so only faces that really changed get reported
+        let mut light = updated
+            .remove(&chunk_pos)
+            .or_else(|| lights.get(chunk_entity).ok().cloned())
+            .unwrap_or_default();
+        let neighbors = NEIGHBOR_OFFSETS.map(|offset| {
+            updated.get(&(chunk_pos + offset)).or_else(|| {
+                current_chunks
+                    .get_entity(chunk_pos + offset)
+                    .and_then(|entity| lights.get(entity).ok())
+            })
+        });
+        let faces = light.calculate_light(&chunk.chunk_data, neighbors, &block_registry);
+        changed_faces.push((chunk_pos, faces));
+        updated.insert(chunk_pos, light);
+    }
+
+    // Light spilled over a border so the neighbour on that side has to take it in. Its mesh samples
+    // light across the border too so it gets remeshed either way
+    for (chunk_pos, faces) in changed_faces {
+        for (offset, changed) in NEIGHBOR_OFFSETS.iter().zip(faces) {
+            if let (true, Some(neighbor_entity)) =
+                (changed, current_chunks.get_entity(chunk_pos + *offset))
+            {
+                light_queue.relight.insert(chunk_pos + *offset);
+                commands.entity(neighbor_entity).insert(DirtyChunk);
+            }
+        }
+    }
+
+    for (chunk_pos, light) in updated {
+        if let Some(chunk_entity) = current_chunks.get_entity(chunk_pos) {
+            if lights.get(chunk_entity).ok() != Some(&light) {
+                commands.entity(chunk_entity).insert(DirtyChunk);
+            }
+            commands.entity(chunk_entity).insert(light);
+        }
+    }
+}
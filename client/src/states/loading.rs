@@ -1,4 +1,9 @@
-use bevy::{asset::LoadState, math::Vec3A, prelude::*, render::primitives::Aabb};
+use bevy::{
+    asset::LoadState,
+    math::Vec3A,
+    prelude::*,
+    render::{primitives::Aabb, renderer::RenderDevice},
+};
 
 use bevy_quinnet::client::{
     certificate::CertificateVerificationMode,
@@ -8,11 +13,14 @@ use bevy_quinnet::client::{
 use common::{
     game::{
         bundles::{AssetsLoading, PlayerBundleBuilder},
-        scripting::{block::load::load_all_blocks, entity::load::load_all_entities},
+        scripting::content::{load_all_content, load_packs, Content},
         storage::{convert_block, convert_entity},
-        world::chunk::LoadableTypes,
+        world::{chunk::LoadableTypes, registry::BlockRegistry},
+    },
+    networking::{
+        components::{ClientMessage, NetworkIP, ServerMessage},
+        content::{hash_bytes, safe_relative_path, server_content_dir, ContentManifest},
     },
-    networking::components::NetworkIP,
 };
 use iyes_loopless::{prelude::AppLooplessStateExt, state::NextState};
 
@@ -21,11 +29,19 @@ use crate::{
     systems::despawn_with,
 };
 
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use iyes_loopless::prelude::*;
 
-use super::game::networking::components::ClientData;
+use super::game::{
+    networking::components::ClientData,
+    rendering::texture_array::{build_block_textures, BlockTextureArray},
+};
 extern crate common;
 
 //TODO: Right now we are building the client only as a multiplayer client. This is fine but eventually we need to have singleplayer.
@@ -42,38 +58,27 @@ pub fn new_client(ip_res: Res<NetworkIP>, mut client: ResMut<Client>) {
 #[allow(clippy::too_many_arguments)]
 pub fn switch(
     mut commands: Commands,
-    mut client: ResMut<Client>,
     loading: Res<AssetsLoading>,
     asset_server: Res<AssetServer>,
     mut loadable_assets: ResMut<LoadableAssets>,
-    mut texture_atlases: ResMut<Assets<TextureAtlas>>,
     mut textures: ResMut<Assets<Image>>,
-    mut connected_event: EventReader<ConnectionEvent>,
+    content_sync: Res<ContentSync>,
+    client_data: Res<ClientData>,
+    render_device: Res<RenderDevice>,
 ) {
     match asset_server.get_group_load_state(loading.0.iter().map(|h| h.id)) {
         LoadState::Failed => {
             commands.insert_resource(NextState(GameState::Menu));
         }
         LoadState::Loaded => {
-            for _ in connected_event.iter() {
-                client.connection_mut().set_default_channel(
-                    bevy_quinnet::shared::channel::ChannelId::UnorderedReliable,
+            if content_sync.state == SyncState::Synced && client_data.0 != 0 {
+                loadable_assets.block_array = build_block_textures(
+                    &loadable_assets,
+                    &asset_server,
+                    &mut textures,
+                    render_device.limits().max_texture_array_layers,
                 );
-                let mut texture_atlas_builder = TextureAtlasBuilder::default();
-                for handle in loadable_assets.block_textures.values() {
-                    for item in handle {
-                        let Some(texture) = textures.get(item) else {
-            warn!("{:?} did not resolve to an `Image` asset.", asset_server.get_handle_path(item));
-            continue;
-        };
-
-                        texture_atlas_builder.add_texture(item.clone(), texture);
-                    }
-                }
-
-                let texture_atlas = texture_atlas_builder.finish(&mut textures).unwrap();
-                let atlas_handle = texture_atlases.add(texture_atlas);
-                loadable_assets.block_atlas = atlas_handle;
+                loadable_assets.textures_outdated = false;
                 commands.insert_resource(NextState(GameState::Game));
                 // remove the resource to drop the tracking handles
                 // commands.remove_resource::<AssetsLoading>();
@@ -87,9 +92,21 @@ pub fn switch(
     }
 }
 
-pub fn timeout(mut commands: Commands, mut timer: Local<Timer>, time: Res<Time>) {
+pub const DOWNLOAD_TIMEOUT: f32 = 60.0;
+
+pub fn timeout(
+    mut commands: Commands,
+    mut timer: Local<Timer>,
+    time: Res<Time>,
+    content_sync: Res<ContentSync>,
+) {
     timer.set_mode(TimerMode::Repeating);
-    timer.set_duration(Duration::from_secs_f32(5.));
+    // Downloading the server's content gets longer before giving up
+    if content_sync.state == SyncState::Downloading {
+        timer.set_duration(Duration::from_secs_f32(DOWNLOAD_TIMEOUT));
+    } else {
+        timer.set_duration(Duration::from_secs_f32(5.));
+    }
 
     timer.tick(time.delta());
     if timer.just_finished() {
@@ -102,6 +119,9 @@ pub fn setup_resources(
     asset_server: Res<AssetServer>,
     mut loading: ResMut<AssetsLoading>,
     mut loadable_types: ResMut<LoadableTypes>,
+    mut block_registry: ResMut<BlockRegistry>,
+    mut content_sync: ResMut<ContentSync>,
+    mut client_data: ResMut<ClientData>,
 ) {
     let player_handle = asset_server.load("base_player.gltf#Scene0");
     loading.0.push(player_handle.clone_untyped());
@@ -115,8 +135,324 @@ pub fn setup_resources(
             ..default()
         },
     });
-    loadable_types.blocks = convert_block(load_all_blocks());
-    loadable_types.entities = convert_entity(load_all_entities());
+    // Local content until the server says what it has
+    let content = load_local_content(&mut content_sync);
+    client_data.0 = 0;
+    loadable_types.blocks = convert_block(content.blocks);
+    loadable_types.entities = convert_entity(content.entities);
+    *block_registry = BlockRegistry::from_blocks(&loadable_types.blocks);
+}
+
+// Remembers what the local content is so it can be compared with the server's
+fn load_local_content(content_sync: &mut ContentSync) -> Content {
+    let content = load_all_content();
+    content.report.log();
+    *content_sync = ContentSync {
+        local: ContentManifest::from_packs(&content.packs),
+        local_roots: content
+            .packs
+            .iter()
+            .map(|pack| (pack.manifest.namespace.clone(), pack.root.clone()))
+            .collect(),
+        ..default()
+    };
+    content
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncState {
+    #[default]
+    Waiting,
+    Downloading,
+    Synced,
+}
+
+// Making sure the client has exactly the content the server has before joining. Anything missing is
+// downloaded into a folder for that server so local content is never touched
+#[derive(Resource, Default)]
+pub struct ContentSync {
+    pub state: SyncState,
+    pub local: ContentManifest,
+    // Local pack namespace to its folder
+    pub local_roots: HashMap<String, PathBuf>,
+    pub server: ContentManifest,
+    pub block_table: Vec<(u16, String)>,
+    pub entities: Vec<String>,
+    // Pack namespace and path of files that haven't come in yet
+    pub pending: HashSet<(String, String)>,
+    pub directory: PathBuf,
+}
+
+impl ContentSync {
+    // Every file from the server that isn't already in the server's folder gets copied over from local
+    // content if it's the same there, anything left has to be downloaded
+    fn missing_files(&self) -> Result<HashSet<(String, String)>, String> {
+        let mut missing = HashSet::new();
+        for pack in self.server.packs.iter() {
+            for file in pack.files.iter() {
+                let target = self.target(&pack.namespace, &file.path)?;
+                if fs::read(&target).is_ok_and(|data| hash_bytes(&data) == file.hash) {
+                    continue;
+                }
+                let local_copy = self
+                    .local
+                    .file(&pack.namespace, &file.path)
+                    .filter(|local_file| local_file.hash == file.hash)
+                    .and_then(|_| self.local_roots.get(&pack.namespace))
+                    .zip(safe_relative_path(&file.path))
+                    .map(|(root, relative)| root.join(relative));
+                match local_copy {
+                    Some(local_copy) => copy_file(&local_copy, &target)?,
+                    None => {
+                        missing.insert((pack.namespace.clone(), file.path.clone()));
+                    }
+                }
+            }
+        }
+        Ok(missing)
+    }
+
+    fn pack_root(&self, namespace: &str) -> Result<PathBuf, String> {
+        safe_relative_path(namespace)
+            .map(|namespace| self.directory.join(namespace))
+            .ok_or_else(|| format!("the server sent a bad namespace {namespace}"))
+    }
+
+    fn target(&self, namespace: &str, path: &str) -> Result<PathBuf, String> {
+        safe_relative_path(path)
+            .ok_or_else(|| format!("the server sent a bad path {namespace}/{path}"))
+            .and_then(|path| Ok(self.pack_root(namespace)?.join(path)))
+    }
+
+    fn save_file(&mut self, namespace: String, path: String, data: &[u8]) -> Result<(), String> {
+        let expected = self
+            .server
+            .file(&namespace, &path)
+            .map(|file| file.hash)
+            .ok_or_else(|| format!("the server sent {namespace}/{path} which isn't its content"))?;
+        if hash_bytes(data) != expected {
+            return Err(format!("{namespace}/{path} didn't match the server's hash"));
+        }
+        let target = self.target(&namespace, &path)?;
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).map_err(|error| error.to_string())?;
+        }
+        fs::write(&target, data).map_err(|error| error.to_string())?;
+        self.pending.remove(&(namespace, path));
+        Ok(())
+    }
+}
+
+fn copy_file(from: &Path, to: &Path) -> Result<(), String> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent).map_err(|error| error.to_string())?;
+    }
+    fs::copy(from, to)
+        .map(|_| ())
+        .map_err(|error| error.to_string())
+}
+
+// Loads the server's copy of its content in the same order the server did. Fails if it doesn't come out
+// the same as the server's
+fn load_server_content(
+    content_sync: &ContentSync,
+    loadable_types: &mut LoadableTypes,
+) -> Result<(), String> {
+    let roots: Vec<PathBuf> = content_sync
+        .server
+        .packs
+        .iter()
+        .map(|pack| content_sync.pack_root(&pack.namespace))
+        .collect::<Result<_, _>>()?;
+    let Some((base, packs)) = roots.split_first() else {
+        return Err("the server has no content".to_string());
+    };
+    let content = load_packs(base, packs);
+    content.report.log();
+    if ContentManifest::from_packs(&content.packs).hash != content_sync.server.hash {
+        return Err("the server's content didn't load the same here".to_string());
+    }
+    loadable_types.blocks = convert_block(content.blocks);
+    loadable_types.entities = convert_entity(content.entities);
+    Ok(())
+}
+
+// Checks the server's block and entity ids against what was loaded. Blocks get the server's numeric ids
+fn use_server_registry(
+    content_sync: &ContentSync,
+    loadable_types: &LoadableTypes,
+    block_registry: &mut BlockRegistry,
+) -> Result<(), String> {
+    *block_registry = BlockRegistry::from_table(&content_sync.block_table, &loadable_types.blocks);
+    let missing: Vec<&str> = content_sync
+        .block_table
+        .iter()
+        .map(|(_, name)| name.as_str())
+        .chain(content_sync.entities.iter().map(String::as_str))
+        .filter(|name| {
+            !loadable_types.blocks.contains_key(*name)
+                && !loadable_types.entities.contains_key(*name)
+        })
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("missing {}", missing.join(", ")))
+    }
+}
+
+fn start_download(
+    content_sync: &mut ContentSync,
+    manifest: ContentManifest,
+    client: &mut Client,
+) -> Result<(), String> {
+    content_sync.directory = server_content_dir(manifest.hash)
+        .ok_or_else(|| "there's no folder to keep the server's content in".to_string())?;
+    content_sync.server = manifest;
+    content_sync.pending = content_sync.missing_files()?;
+    content_sync.state = SyncState::Downloading;
+    if !content_sync.pending.is_empty() {
+        info!(
+            "Downloading {} files from the server",
+            content_sync.pending.len()
+        );
+        client
+            .connection_mut()
+            .try_send_message(ClientMessage::RequestContent {
+                files: content_sync.pending.iter().cloned().collect(),
+            });
+    }
+    Ok(())
+}
+
+fn finish_sync(
+    content_sync: &ContentSync,
+    loadable_types: &mut LoadableTypes,
+    block_registry: &mut BlockRegistry,
+) -> Result<(), String> {
+    load_server_content(content_sync, loadable_types)?;
+    use_server_registry(content_sync, loadable_types, block_registry)
+}
+
+// ContentInfo and ContentFile are handled the same while loading and in game
+fn handle_content_message(
+    message: ServerMessage,
+    client: &mut Client,
+    content_sync: &mut ContentSync,
+    loadable_types: &mut LoadableTypes,
+    block_registry: &mut BlockRegistry,
+) -> Result<(), String> {
+    match message {
+        ServerMessage::ContentInfo {
+            manifest,
+            block_table,
+            entities,
+        } => {
+            content_sync.block_table = block_table;
+            content_sync.entities = entities;
+            if manifest.hash == content_sync.local.hash {
+                content_sync.server = manifest;
+                content_sync.state = SyncState::Synced;
+                use_server_registry(content_sync, loadable_types, block_registry)?;
+            } else {
+                info!("The server has different content, syncing it");
+                start_download(content_sync, manifest, client)?;
+            }
+        }
+        ServerMessage::ContentFile {
+            namespace,
+            path,
+            data,
+        } => {
+            content_sync.save_file(namespace, path, &data)?;
+        }
+        _ => {}
+    }
+    if content_sync.state == SyncState::Downloading && content_sync.pending.is_empty() {
+        content_sync.state = SyncState::Synced;
+        finish_sync(content_sync, loadable_types, block_registry)?;
+    }
+    Ok(())
+}
+
+pub fn sync_content(
+    mut commands: Commands,
+    mut client: ResMut<Client>,
+    mut client_data: ResMut<ClientData>,
+    mut content_sync: ResMut<ContentSync>,
+    mut loadable_types: ResMut<LoadableTypes>,
+    mut block_registry: ResMut<BlockRegistry>,
+    mut connected_event: EventReader<ConnectionEvent>,
+) {
+    for _ in connected_event.iter() {
+        client
+            .connection_mut()
+            .set_default_channel(bevy_quinnet::shared::channel::ChannelId::UnorderedReliable);
+    }
+    let mut result = Ok(());
+    while let Some(message) = client
+        .connection_mut()
+        .try_receive_message::<ServerMessage>()
+    {
+        if let ServerMessage::ClientId { id } = message {
+            client_data.0 = id;
+            continue;
+        }
+        result = handle_content_message(
+            message,
+            &mut client,
+            &mut content_sync,
+            &mut loadable_types,
+            &mut block_registry,
+        );
+        if result.is_err() {
+            break;
+        }
+    }
+    // Better to not join at all than to join and crash on a block that doesn't exist here
+    if let Err(error) = result {
+        warn!("Can't join the server: {error}");
+        client.close_all_connections().ok();
+        content_sync.state = SyncState::Waiting;
+        commands.insert_resource(NextState(GameState::Menu));
+    }
+}
+
+// Sent on by the game's networking when the server's content changes while playing
+pub struct ContentMessage(pub ServerMessage);
+
+// The server sends its content info again after a reload, syncing it works the same as while loading
+pub fn resync_content(
+    mut commands: Commands,
+    mut client: ResMut<Client>,
+    mut content_messages: EventReader<ContentMessage>,
+    mut content_sync: ResMut<ContentSync>,
+    mut loadable_types: ResMut<LoadableTypes>,
+    mut block_registry: ResMut<BlockRegistry>,
+) {
+    for ContentMessage(message) in content_messages.iter() {
+        if let ServerMessage::ContentInfo { manifest, .. } = message {
+            // Local content might have been edited too
+            let content = load_local_content(&mut content_sync);
+            if manifest.hash == content_sync.local.hash {
+                loadable_types.blocks = convert_block(content.blocks);
+                loadable_types.entities = convert_entity(content.entities);
+            }
+        }
+        if let Err(error) = handle_content_message(
+            message.clone(),
+            &mut client,
+            &mut content_sync,
+            &mut loadable_types,
+            &mut block_registry,
+        ) {
+            warn!("Lost sync with the server's content: {error}");
+            client.close_all_connections().ok();
+            content_sync.state = SyncState::Waiting;
+            commands.insert_resource(NextState(GameState::Menu));
+            break;
+        }
+    }
 }
 
 #[derive(Resource, Default, Clone)]
@@ -124,7 +460,9 @@ pub struct LoadableAssets {
     pub block_models: HashMap<String, Handle<Scene>>,
     pub block_textures: HashMap<String, [Handle<Image>; 6]>,
     pub entity_models: HashMap<String, Handle<Scene>>,
-    pub block_atlas: Handle<TextureAtlas>,
+    pub block_array: BlockTextureArray,
+    // Block textures changed since the texture array was built
+    pub textures_outdated: bool,
 }
 
 pub fn load_blocks(
@@ -133,23 +471,20 @@ pub fn load_blocks(
     mut loading: ResMut<AssetsLoading>,
     loadable_types: Res<LoadableTypes>,
     mut loadable_assets: ResMut<LoadableAssets>,
-    mut has_ran: Local<bool>,
 ) {
-    if !(*has_ran) && loadable_types.is_changed() {
-        for block_pair in &loadable_types.blocks {
-            let block = block_pair.1;
+    // Runs again if the server's content replaces the local content or it's reloaded in game
+    if loadable_types.is_changed() {
+        loadable_assets.textures_outdated = true;
+        loadable_assets.block_textures.clear();
+        loadable_assets.block_models.clear();
+        for (block_identifier, block) in &loadable_types.blocks {
             let mut texture_array: Vec<Handle<Image>> = Vec::with_capacity(6);
             texture_array.resize(6, Handle::default());
-            let mut block_identifier = String::new();
+            // Texture and model paths are full paths so blocks from packs load from their own folder
             for texture_path_and_type in block.textures.iter() {
-                let mut path = "blocks/".to_string();
-                path.push_str(block.block_name.as_str());
-                path.push('/');
-                path.push_str(texture_path_and_type.1);
-                let texture_handle: Handle<Image> = asset_server.load(path.as_str());
+                let texture_handle: Handle<Image> =
+                    asset_server.load(texture_path_and_type.1.as_str());
                 loading.0.push(texture_handle.clone_untyped());
-                block_identifier = block.namespace.to_owned();
-                block_identifier.push_str(&block.block_name.to_owned());
                 match texture_path_and_type.0.as_str() {
                     "up" => {
                         texture_array[0] = texture_handle;
@@ -184,9 +519,16 @@ pub fn load_blocks(
                     });
             loadable_assets
                 .block_textures
-                .insert(block_identifier, texture_array);
+                .insert(block_identifier.to_owned(), texture_array);
+            if let Some(model) = &block.block_model {
+                let model_handle: Handle<Scene> =
+                    asset_server.load(format!("{model}#Scene0").as_str());
+                loading.0.push(model_handle.clone_untyped());
+                loadable_assets
+                    .block_models
+                    .insert(block_identifier.to_owned(), model_handle);
+            }
         }
-        *has_ran = true;
     }
 }
 
@@ -207,10 +549,16 @@ impl Plugin for LoadingPlugin {
         app.insert_resource(ClientData::default())
             .insert_resource(AssetsLoading::default())
             .insert_resource(LoadableTypes::default())
+            .insert_resource(BlockRegistry::default())
             .insert_resource(LoadableAssets::default())
-            .add_system(switch.run_in_state(GameState::Loading))
+            .insert_resource(ContentSync::default())
+            .add_system(switch.run_in_state(GameState::Loading).after(load_blocks))
+            .add_event::<ContentMessage>()
             .add_enter_system(GameState::Loading, setup_resources)
-            .add_system(load_blocks.run_in_state(GameState::Loading))
+            .add_system(sync_content.run_in_state(GameState::Loading))
+            .add_system(resync_content.run_in_state(GameState::Game))
+            // Not tied to a state so a content change is only seen once
+            .add_system(load_blocks.after(sync_content).after(resync_content))
             .add_system(timeout.run_in_state(GameState::Loading))
             .add_enter_system(GameState::Loading, load_entities)
             .add_enter_system(GameState::Loading, load_sounds)
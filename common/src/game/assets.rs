@@ -0,0 +1,141 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use bevy::prelude::*;
+use directories::ProjectDirs;
+use include_dir::{include_dir, Dir};
+use walkdir::WalkDir;
+
+use crate::networking::content::hash_bytes;
+
+// Everything in client/assets is built in so a fresh install or a clean checkout always has the base game
+static BUILT_IN_ASSETS: Dir = include_dir!("$CARGO_MANIFEST_DIR/../client/assets");
+
+// Kept in the installed assets folder, the hash of the built in assets they were last set up from
+const VERSION_FILE: &str = ".built_in_version";
+
+static ASSET_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+pub fn project_dirs() -> Option<ProjectDirs> {
+    ProjectDirs::from("com", "vinox", "vinox")
+}
+
+// An assets folder shipped next to the binary, eg in a release zip
+fn bundled_asset_dir() -> Option<PathBuf> {
+    let dir = env::current_exe().ok()?.parent()?.join("assets");
+    dir.is_dir().then_some(dir)
+}
+
+fn copy_files(from: &Path, to: &Path) -> io::Result<()> {
+    for entry in WalkDir::new(from).into_iter().filter_map(|e| e.ok()) {
+        let Ok(relative) = entry.path().strip_prefix(from) else {
+            continue;
+        };
+        let target = to.join(relative);
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+fn extract_files(dir: &Dir, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to.join(dir.path()))?;
+    for file in dir.files() {
+        fs::write(to.join(file.path()), file.contents())?;
+    }
+    for dir in dir.dirs() {
+        extract_files(dir, to)?;
+    }
+    Ok(())
+}
+
+fn file_hashes(dir: &Dir, hashes: &mut String) {
+    for file in dir.files() {
+        hashes.push_str(&format!(
+            "{}:{}\n",
+            file.path().display(),
+            hash_bytes(file.contents())
+        ));
+    }
+    for dir in dir.dirs() {
+        file_hashes(dir, hashes);
+    }
+}
+
+// Changes whenever any built in file does, so an updated game knows to install its assets again
+fn built_in_version() -> String {
+    let mut hashes = String::new();
+    file_hashes(&BUILT_IN_ASSETS, &mut hashes);
+    hash_bytes(hashes.as_bytes()).to_string()
+}
+
+// Only does anything on the first run and after an update. Then every built in file is written out again,
+// with the ones next to the binary on top. Files a pack or the user added are left alone
+fn install_assets(target: &Path) -> io::Result<()> {
+    let version = built_in_version();
+    let version_file = target.join(VERSION_FILE);
+    if fs::read_to_string(&version_file).ok().as_deref() == Some(version.as_str()) {
+        return Ok(());
+    }
+    fs::create_dir_all(target)?;
+    extract_files(&BUILT_IN_ASSETS, target)?;
+    if let Some(bundled) = bundled_asset_dir() {
+        copy_files(&bundled, target)?;
+    }
+    fs::write(version_file, version)
+}
+
+// The folder every asset and the base game's content loads from, assets in the user data dir. It gets set
+// up the first time this is called and the same folder is handed out after that, so content reloads never
+// bring back files that were deleted
+pub fn asset_dir() -> PathBuf {
+    ASSET_DIR
+        .get_or_init(|| {
+            let data_dir = project_dirs().map(|proj_dirs| proj_dirs.data_dir().join("assets"));
+            // Somewhere to put them if the data dir can't be used
+            let temp_dir = env::temp_dir().join("vinox").join("assets");
+            for target in data_dir.into_iter().chain([temp_dir.clone()]) {
+                match install_assets(&target) {
+                    Ok(()) => return target,
+                    Err(error) => {
+                        warn!("Couldn't set up assets in {}: {error}", target.display())
+                    }
+                }
+            }
+            temp_dir
+        })
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::{install_assets, VERSION_FILE};
+
+    #[test]
+    fn installs_once_per_version() {
+        let target = env::temp_dir().join(format!("vinox-assets-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&target);
+        install_assets(&target).unwrap();
+        let pack = target.join("blocks/cobblestone/cobblestone.ron");
+        let original = fs::read_to_string(&pack).unwrap();
+
+        // Same version, whatever the user did stays
+        fs::remove_file(&pack).unwrap();
+        install_assets(&target).unwrap();
+        assert!(!pack.exists());
+
+        // An update puts every built in file back how it ships
+        fs::write(target.join(VERSION_FILE), "old").unwrap();
+        install_assets(&target).unwrap();
+        assert_eq!(fs::read_to_string(&pack).unwrap(), original);
+        fs::remove_dir_all(&target).unwrap();
+    }
+}
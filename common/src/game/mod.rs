@@ -1,3 +1,4 @@
+pub mod assets;
 pub mod bundles;
 pub mod scripting;
 pub mod storage;
@@ -0,0 +1,56 @@
+use serde::{Deserialize, Serialize};
+
+fn default_subsurface_depth() -> u32 {
+    3
+}
+
+fn default_octaves() -> usize {
+    4
+}
+
+fn default_caves() -> f64 {
+    1.0
+}
+
+fn default_cave_entrances() -> bool {
+    true
+}
+
+// Shape of the ground in a biome. The height at a column is base + noise * amplitude, frequency is per
+// block so 0.01 means a hill about every 100 blocks
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HeightNoise {
+    pub base: f64,
+    pub amplitude: f64,
+    pub frequency: f64,
+    #[serde(default = "default_octaves")]
+    pub octaves: usize,
+    // Sharp ridges instead of rolling hills, good for mountains
+    #[serde(default)]
+    pub ridged: bool,
+}
+
+// Where a biome shows up and what it's made of. Temperature and humidity come from noise that goes from
+// -1 to 1, a biome can be picked anywhere both are inside its ranges. Blocks are full ids like "vinox:grass"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BiomeDescriptor {
+    pub namespace: String,
+    pub biome_name: String,
+    pub temperature: (f64, f64),
+    pub humidity: (f64, f64),
+    // The top block of the ground
+    pub surface_block: String,
+    // Blocks under the surface, subsurface_depth deep
+    pub subsurface_block: String,
+    #[serde(default = "default_subsurface_depth")]
+    pub subsurface_depth: u32,
+    // Everything further down
+    pub stone_block: String,
+    pub height: HeightNoise,
+    // How much gets carved out under the biome, 0 for no caves at all and 2 for a lot more
+    #[serde(default = "default_caves")]
+    pub caves: f64,
+    // Whether tunnels can break through the ground, without them caves stay sealed under a few blocks
+    #[serde(default = "default_cave_entrances")]
+    pub cave_entrances: bool,
+}
@@ -0,0 +1,9 @@
+use std::{fs, path::Path};
+
+use super::biome_descriptor::BiomeDescriptor;
+
+// Reads one biome descriptor, errors are ready to go in a ContentLoadReport
+pub fn load_biome(path: &Path) -> Result<BiomeDescriptor, String> {
+    let ron_string = fs::read_to_string(path).map_err(|error| error.to_string())?;
+    ron::from_str::<BiomeDescriptor>(&ron_string).map_err(|error| error.to_string())
+}
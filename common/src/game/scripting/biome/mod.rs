@@ -0,0 +1,2 @@
+pub mod biome_descriptor;
+pub mod load;
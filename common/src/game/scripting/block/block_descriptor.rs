@@ -1,7 +1,12 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
 
 use serde::{Deserialize, Serialize};
 
+use crate::game::world::block_state::StateProperty;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct BlockDescriptor {
     pub namespace: String,
@@ -16,5 +21,13 @@ pub struct BlockDescriptor {
     pub block_script: Option<String>,
     pub visibility: String,
     pub block_geometry: String,
+    // A gltf next to the ron file, eg Some("lantern.gltf"). Drawn instead of any block geometry
+    #[serde(default)]
+    pub block_model: Option<String>,
     pub light_val: u8,
+    #[serde(default)]
+    pub block_states: BTreeMap<String, StateProperty>,
+    // Folder the ron file was loaded from, scripts are relative to it
+    #[serde(skip)]
+    pub directory: PathBuf,
 }
@@ -1,25 +1,12 @@
-use directories::ProjectDirs;
-use std::fs::{self};
-
-use walkdir::WalkDir;
+use std::{fs, path::Path};
 
 use super::block_descriptor::BlockDescriptor;
 
-pub fn load_all_blocks() -> Vec<BlockDescriptor> {
-    let mut result = Vec::new();
-    if let Some(proj_dirs) = ProjectDirs::from("com", "vinox", "vinox") {
-        for entry in WalkDir::new(proj_dirs.data_dir().join("assets"))
-            .into_iter()
-            .filter_map(|e| e.ok())
-        {
-            if entry.path().extension().unwrap_or_default() == "ron" {
-                if let Ok(ron_string) = fs::read_to_string(entry.path()) {
-                    if let Ok(block) = ron::from_str(ron_string.as_str()) {
-                        result.push(block);
-                    }
-                }
-            }
-        }
-    }
-    result
+// Reads one block descriptor, errors are ready to go in a ContentLoadReport
+pub fn load_block(path: &Path) -> Result<BlockDescriptor, String> {
+    let ron_string = fs::read_to_string(path).map_err(|error| error.to_string())?;
+    let mut block =
+        ron::from_str::<BlockDescriptor>(&ron_string).map_err(|error| error.to_string())?;
+    block.directory = path.parent().unwrap_or(path).to_owned();
+    Ok(block)
 }
@@ -1,2 +1,3 @@
+pub mod block_descriptor;
 pub mod load;
-pub mod block_descriptor;
\ No newline at end of file
+pub mod script;
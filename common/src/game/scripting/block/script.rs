@@ -0,0 +1,244 @@
+use std::{cell::RefCell, collections::HashMap, fs};
+
+use bevy::prelude::*;
+use mlua::{Function, RegistryKey, Table, Value};
+
+use crate::game::{
+    scripting::sandbox::{load_script, LimitedLua, ScriptError},
+    storage::BlockType,
+    world::block_state::BlockState,
+};
+
+// Hooks a block script can define as globals. Each one gets the block position and its full block state,
+// eg `function on_place(x, y, z, block) end`. on_interact also gets the id of the player
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockHook {
+    Place,
+    Break,
+    Interact { player: u64 },
+    RandomTick,
+}
+
+impl BlockHook {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BlockHook::Place => "on_place",
+            BlockHook::Break => "on_break",
+            BlockHook::Interact { .. } => "on_interact",
+            BlockHook::RandomTick => "on_random_tick",
+        }
+    }
+}
+
+// Everything scripts can touch in the world. Positions are global voxel positions. The server goes through
+// the loaded chunks, anything else (like tests) can just use a HashMap
+pub trait BlockWorld {
+    fn get_block(&self, pos: IVec3) -> Option<String>;
+    // False if the block couldn't be set, eg the chunk isn't loaded or the block doesn't exist
+    fn set_block(&mut self, pos: IVec3, block: &str) -> bool;
+    // Entity type is a full id like "vinox:golem". False if it doesn't exist
+    fn spawn_entity(&mut self, entity_type: &str, pos: Vec3) -> bool;
+}
+
+impl BlockWorld for HashMap<IVec3, String> {
+    fn get_block(&self, pos: IVec3) -> Option<String> {
+        Some(self.get(&pos).cloned().unwrap_or_else(|| "air".to_string()))
+    }
+
+    fn set_block(&mut self, pos: IVec3, block: &str) -> bool {
+        self.insert(pos, block.to_string());
+        true
+    }
+
+    // Just blocks in here
+    fn spawn_entity(&mut self, _entity_type: &str, _pos: Vec3) -> bool {
+        false
+    }
+}
+
+// One Lua state shared by every block script. Each script runs in its own environment table so their
+// globals don't mix, hooks are looked up in there
+pub struct BlockScripts {
+    lua: LimitedLua,
+    // Block id to the script's name and environment
+    scripts: HashMap<String, (String, RegistryKey)>,
+}
+
+impl Default for BlockScripts {
+    fn default() -> BlockScripts {
+        BlockScripts::new()
+    }
+}
+
+impl BlockScripts {
+    pub fn new() -> BlockScripts {
+        BlockScripts {
+            lua: LimitedLua::new(),
+            scripts: HashMap::new(),
+        }
+    }
+
+    // Loads the script of every block that has one. A broken script only takes out its own block
+    pub fn load_blocks(&mut self, blocks: &HashMap<String, BlockType>) {
+        for (block_id, block_type) in blocks.iter() {
+            let Some(path) = &block_type.block_script else {
+                continue;
+            };
+            match fs::read_to_string(path) {
+                Ok(source) => {
+                    if let Err(error) = self.load(block_id, path, &source) {
+                        warn!("Couldn't load the script for {block_id}: {error}");
+                    }
+                }
+                Err(error) => warn!("Couldn't read {path}: {error}"),
+            }
+        }
+    }
+
+    // Swaps every script for a fresh copy from disk, blocks that lost their script stop running one
+    pub fn reload(&mut self, blocks: &HashMap<String, BlockType>) {
+        for (_, (_, key)) in self.scripts.drain() {
+            self.lua.remove_registry_value(key).ok();
+        }
+        self.load_blocks(blocks);
+    }
+
+    // Name shows up in error messages, usually the path of the script
+    pub fn load(&mut self, block_id: &str, name: &str, source: &str) -> Result<(), ScriptError> {
+        let key = load_script(&self.lua, name, source)?;
+        if let Some((_, old_key)) = self
+            .scripts
+            .insert(block_id.to_owned(), (name.to_owned(), key))
+        {
+            self.lua.remove_registry_value(old_key).ok();
+        }
+        Ok(())
+    }
+
+    fn env(&self, block_state: &str) -> Option<(&str, Table<'_>)> {
+        let (name, key) = self.scripts.get(BlockState::block_id_of(block_state))?;
+        let env = self.lua.registry_value(key).ok()?;
+        Some((name, env))
+    }
+
+    // Takes a block id or a full block state
+    pub fn has_hook(&self, block_state: &str, hook_name: &str) -> bool {
+        match self.env(block_state) {
+            Some((_, env)) => matches!(env.get::<_, Option<Function>>(hook_name), Ok(Some(_))),
+            None => false,
+        }
+    }
+
+    // Runs a hook for the block at pos if its script has it. Scripts only get at the world through the
+    // `world` table while the hook is running
+    pub fn call(
+        &self,
+        world: &mut dyn BlockWorld,
+        hook: BlockHook,
+        pos: IVec3,
+        block_state: &str,
+    ) -> Result<(), ScriptError> {
+        let Some((name, env)) = self.env(block_state) else {
+            return Ok(());
+        };
+        self.lua.run(name, hook.name(), |lua| {
+            let Some(function) = env.get::<_, Option<Function>>(hook.name())? else {
+                return Ok(());
+            };
+            let world = RefCell::new(world);
+            lua.scope(|scope| {
+                let api = lua.create_table()?;
+                api.set(
+                    "get_block",
+                    scope.create_function(|_, (x, y, z): (i32, i32, i32)| {
+                        Ok(world.borrow().get_block(IVec3::new(x, y, z)))
+                    })?,
+                )?;
+                api.set(
+                    "set_block",
+                    scope.create_function(|_, (x, y, z, block): (i32, i32, i32, String)| {
+                        Ok(world.borrow_mut().set_block(IVec3::new(x, y, z), &block))
+                    })?,
+                )?;
+                api.set(
+                    "spawn_entity",
+                    scope.create_function(|_, (entity, x, y, z): (String, f32, f32, f32)| {
+                        Ok(world.borrow_mut().spawn_entity(&entity, Vec3::new(x, y, z)))
+                    })?,
+                )?;
+                env.set("world", api)?;
+                let result = match hook {
+                    BlockHook::Interact { player } => {
+                        function.call::<_, ()>((pos.x, pos.y, pos.z, block_state, player))
+                    }
+                    _ => function.call::<_, ()>((pos.x, pos.y, pos.z, block_state)),
+                };
+                env.set("world", Value::Nil)?;
+                result
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bevy::prelude::*;
+
+    use super::{BlockHook, BlockScripts, BlockWorld};
+
+    #[test]
+    fn hooks_change_world() {
+        let mut scripts = BlockScripts::new();
+        scripts
+            .load(
+                "vinox:grass",
+                "grass.lua",
+                r#"
+                function on_place(x, y, z, block)
+                    if world.get_block(x, y - 1, z) == "vinox:grass" then
+                        world.set_block(x, y - 1, z, "vinox:dirt")
+                    end
+                end
+                function on_random_tick(x, y, z, block)
+                    if io ~= nil or os.execute ~= nil or debug ~= nil then
+                        error("sandbox leaked")
+                    end
+                end
+                "#,
+            )
+            .unwrap();
+
+        let mut world: HashMap<IVec3, String> = HashMap::new();
+        world.set_block(IVec3::new(0, 0, 0), "vinox:grass");
+        world.set_block(IVec3::new(0, 1, 0), "vinox:grass");
+        scripts
+            .call(
+                &mut world,
+                BlockHook::Place,
+                IVec3::new(0, 1, 0),
+                "vinox:grass",
+            )
+            .unwrap();
+        assert_eq!(
+            world.get_block(IVec3::new(0, 0, 0)).as_deref(),
+            Some("vinox:dirt")
+        );
+
+        assert!(scripts.has_hook("vinox:grass", "on_random_tick"));
+        assert!(!scripts.has_hook("vinox:grass", "on_break"));
+        scripts
+            .call(
+                &mut world,
+                BlockHook::RandomTick,
+                IVec3::new(0, 1, 0),
+                "vinox:grass",
+            )
+            .unwrap();
+        // Blocks without a script are skipped
+        scripts
+            .call(&mut world, BlockHook::Break, IVec3::ZERO, "vinox:dirt")
+            .unwrap();
+    }
+}
@@ -0,0 +1,670 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::game::{
+    assets::{asset_dir, project_dirs},
+    world::{
+        block_state::StateProperty,
+        registry::{biome_identifier, block_identifier, entity_identifier, structure_identifier},
+    },
+};
+
+use super::{
+    biome::{biome_descriptor::BiomeDescriptor, load::load_biome},
+    block::{block_descriptor::BlockDescriptor, load::load_block},
+    entity::{entity_descriptor::EntityDescriptor, load::load_entity},
+    structure::{load::load_structure, structure_descriptor::StructureDescriptor},
+};
+
+pub const PACK_MANIFEST: &str = "pack.ron";
+// The game's own content, every other pack loads after it
+pub const BASE_NAMESPACE: &str = "vinox";
+// Folder in the user data dir that mod packs go in, either as folders or zip files
+pub const PACKS_FOLDER: &str = "packs";
+
+fn default_blocks() -> Vec<String> {
+    vec!["blocks".to_string()]
+}
+
+fn default_entities() -> Vec<String> {
+    vec!["entities".to_string()]
+}
+
+fn default_biomes() -> Vec<String> {
+    vec!["biomes".to_string()]
+}
+
+fn default_structures() -> Vec<String> {
+    vec!["structures".to_string()]
+}
+
+// Changes a pack makes to a block from a pack loaded before it. Anything left out stays how it was,
+// textures are swapped per side and states are added to the block's own. Paths are relative to the
+// overriding pack
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct BlockOverride {
+    #[serde(default)]
+    pub textures: HashMap<String, String>,
+    pub interactable: Option<bool>,
+    pub friction: Option<f32>,
+    pub break_time: Option<f32>,
+    pub break_tool: Option<String>,
+    pub walk_sound: Option<String>,
+    pub break_sound: Option<String>,
+    pub block_script: Option<String>,
+    pub visibility: Option<String>,
+    pub block_geometry: Option<String>,
+    pub block_model: Option<String>,
+    pub light_val: Option<u8>,
+    // A property the block already has gets replaced
+    #[serde(default)]
+    pub block_states: BTreeMap<String, StateProperty>,
+}
+
+impl BlockOverride {
+    pub fn apply(&self, block: &mut BlockDescriptor, pack_root: &Path) {
+        let in_pack = |path: &String| pack_root.join(path).to_string_lossy().to_string();
+        for (side, texture) in self.textures.iter() {
+            block.textures.insert(side.clone(), in_pack(texture));
+        }
+        if let Some(interactable) = self.interactable {
+            block.interactable = interactable;
+        }
+        if let Some(friction) = self.friction {
+            block.friction = friction;
+        }
+        if let Some(break_time) = self.break_time {
+            block.break_time = break_time;
+        }
+        if let Some(break_tool) = &self.break_tool {
+            block.break_tool = break_tool.clone();
+        }
+        if let Some(walk_sound) = &self.walk_sound {
+            block.walk_sound = Some(walk_sound.clone());
+        }
+        if let Some(break_sound) = &self.break_sound {
+            block.break_sound = Some(break_sound.clone());
+        }
+        if let Some(block_script) = &self.block_script {
+            block.block_script = Some(in_pack(block_script));
+        }
+        if let Some(visibility) = &self.visibility {
+            block.visibility = visibility.clone();
+        }
+        if let Some(block_geometry) = &self.block_geometry {
+            block.block_geometry = block_geometry.clone();
+        }
+        if let Some(block_model) = &self.block_model {
+            block.block_model = Some(in_pack(block_model));
+        }
+        if let Some(light_val) = self.light_val {
+            block.light_val = light_val;
+        }
+        for (name, property) in self.block_states.iter() {
+            block.block_states.insert(name.clone(), property.clone());
+        }
+    }
+}
+
+// pack.ron at the root of a content pack. Every block, entity, biome and structure in the pack has to use
+// its namespace. Lists the folders each kind of descriptor lives in, every ron file in a block folder has to
+// be a block and so on. The base game can leave it out, then it's vinox with a folder named after each kind
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PackManifest {
+    pub namespace: String,
+    #[serde(default)]
+    pub version: String,
+    // Namespaces of packs that have to load before this one, the base game always does
+    #[serde(default)]
+    pub dependencies: Vec<String>,
+    // Block id to what to change, eg {"vinox:grass": (textures: {"up": "grass_top.png"})}
+    #[serde(default)]
+    pub overrides: HashMap<String, BlockOverride>,
+    #[serde(default = "default_blocks")]
+    pub blocks: Vec<String>,
+    #[serde(default = "default_entities")]
+    pub entities: Vec<String>,
+    #[serde(default = "default_biomes")]
+    pub biomes: Vec<String>,
+    #[serde(default = "default_structures")]
+    pub structures: Vec<String>,
+}
+
+impl Default for PackManifest {
+    fn default() -> PackManifest {
+        PackManifest {
+            namespace: BASE_NAMESPACE.to_string(),
+            version: String::new(),
+            dependencies: Vec::new(),
+            overrides: HashMap::new(),
+            blocks: default_blocks(),
+            entities: default_entities(),
+            biomes: default_biomes(),
+            structures: default_structures(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ContentPack {
+    pub root: PathBuf,
+    pub manifest: PackManifest,
+}
+
+impl ContentPack {
+    // pack.ron, everything in its content folders and anything its overrides point at
+    pub fn files(&self) -> Vec<PathBuf> {
+        let root = &self.root;
+        let manifest = &self.manifest;
+        let mut files = vec![root.join(PACK_MANIFEST)];
+        for folder in manifest
+            .blocks
+            .iter()
+            .chain(manifest.entities.iter())
+            .chain(manifest.biomes.iter())
+            .chain(manifest.structures.iter())
+        {
+            for entry in WalkDir::new(root.join(folder))
+                .into_iter()
+                .filter_map(|e| e.ok())
+            {
+                if entry.file_type().is_file() {
+                    files.push(entry.path().to_owned());
+                }
+            }
+        }
+        for block_override in manifest.overrides.values() {
+            for texture in block_override.textures.values() {
+                files.push(root.join(texture));
+            }
+            for file in [&block_override.block_script, &block_override.block_model]
+                .into_iter()
+                .flatten()
+            {
+                files.push(root.join(file));
+            }
+        }
+        files.retain(|file| file.is_file());
+        files.sort();
+        files.dedup();
+        files
+    }
+}
+
+// Content is read straight from disk instead of through the asset server so changes are found by checking
+// when every file was last modified
+#[derive(Resource, Default)]
+pub struct ContentWatcher {
+    packs: Vec<ContentPack>,
+    archives: Vec<PathBuf>,
+    modified: HashMap<PathBuf, SystemTime>,
+}
+
+impl ContentWatcher {
+    pub fn new(packs: &[ContentPack], archives: &[PathBuf]) -> ContentWatcher {
+        ContentWatcher {
+            packs: packs.to_vec(),
+            archives: archives.to_vec(),
+            modified: ContentWatcher::snapshot(packs, archives),
+        }
+    }
+
+    // Zip packs are only read through their unpacked copy so the zips get checked on their own
+    fn snapshot(packs: &[ContentPack], archives: &[PathBuf]) -> HashMap<PathBuf, SystemTime> {
+        packs
+            .iter()
+            .flat_map(|pack| pack.files())
+            .chain(archives.iter().cloned())
+            .filter_map(|file| {
+                let modified = fs::metadata(&file).and_then(|meta| meta.modified()).ok()?;
+                Some((file, modified))
+            })
+            .collect()
+    }
+
+    // True if a file in one of the packs was changed, added or removed since they were loaded
+    pub fn changed(&self) -> bool {
+        ContentWatcher::snapshot(&self.packs, &self.archives) != self.modified
+    }
+}
+
+// What happened to every ron file that was looked at, with a reason for anything that didn't load
+#[derive(Default, Debug, Clone)]
+pub struct ContentLoadReport {
+    pub loaded: Vec<PathBuf>,
+    pub skipped: Vec<(PathBuf, String)>,
+    pub malformed: Vec<(PathBuf, String)>,
+}
+
+impl ContentLoadReport {
+    pub fn log(&self) {
+        for (path, reason) in self.skipped.iter() {
+            info!("Skipped {}: {reason}", path.display());
+        }
+        for (path, reason) in self.malformed.iter() {
+            warn!("{}: {reason}", path.display());
+        }
+        info!(
+            "Loaded {} content files, skipped {} and {} were malformed",
+            self.loaded.len(),
+            self.skipped.len(),
+            self.malformed.len()
+        );
+    }
+}
+
+#[derive(Default)]
+pub struct Content {
+    pub blocks: Vec<BlockDescriptor>,
+    pub entities: Vec<EntityDescriptor>,
+    pub biomes: Vec<BiomeDescriptor>,
+    pub structures: Vec<StructureDescriptor>,
+    // Every pack that loaded, in the order it loaded in
+    pub packs: Vec<ContentPack>,
+    // Zip files found in the packs folder, even ones that couldn't be unpacked
+    pub archives: Vec<PathBuf>,
+    pub report: ContentLoadReport,
+}
+
+fn load_manifest(path: &Path) -> Result<PackManifest, String> {
+    let ron_string = fs::read_to_string(path).map_err(|error| error.to_string())?;
+    ron::from_str(&ron_string).map_err(|error| error.to_string())
+}
+
+// Only the base game gets to skip pack.ron
+fn read_pack(root: &Path, base: bool) -> Result<ContentPack, (PathBuf, String)> {
+    let manifest_path = root.join(PACK_MANIFEST);
+    let manifest = if manifest_path.exists() {
+        load_manifest(&manifest_path).map_err(|error| (manifest_path.clone(), error))?
+    } else if base {
+        PackManifest::default()
+    } else {
+        return Err((root.to_owned(), format!("no {PACK_MANIFEST} in the pack")));
+    };
+    if manifest.namespace.is_empty() {
+        return Err((manifest_path, "namespace can't be empty".to_string()));
+    }
+    Ok(ContentPack {
+        root: root.to_owned(),
+        manifest,
+    })
+}
+
+// Base game first, then every pack once everything it depends on has loaded. Ties go by namespace so the
+// order is the same every time. Packs with a missing dependency or in a cycle are left out
+fn order_packs(
+    base: ContentPack,
+    mut packs: Vec<ContentPack>,
+    report: &mut ContentLoadReport,
+) -> Vec<ContentPack> {
+    packs.sort_by(|a, b| a.manifest.namespace.cmp(&b.manifest.namespace));
+    let mut loaded: HashSet<String> = HashSet::from([base.manifest.namespace.clone()]);
+    let mut ordered = vec![base];
+    let mut waiting = Vec::new();
+    for pack in packs {
+        if loaded.contains(&pack.manifest.namespace)
+            || waiting
+                .iter()
+                .any(|other: &ContentPack| other.manifest.namespace == pack.manifest.namespace)
+        {
+            report.malformed.push((
+                pack.root.join(PACK_MANIFEST),
+                format!(
+                    "namespace {} is already used by another pack",
+                    pack.manifest.namespace
+                ),
+            ));
+        } else {
+            waiting.push(pack);
+        }
+    }
+    let namespaces: HashSet<String> = waiting
+        .iter()
+        .map(|pack| pack.manifest.namespace.clone())
+        .collect();
+    while let Some(index) = waiting.iter().position(|pack| {
+        pack.manifest
+            .dependencies
+            .iter()
+            .all(|dependency| loaded.contains(dependency))
+    }) {
+        let pack = waiting.remove(index);
+        loaded.insert(pack.manifest.namespace.clone());
+        ordered.push(pack);
+    }
+    for pack in waiting {
+        let missing: Vec<&str> = pack
+            .manifest
+            .dependencies
+            .iter()
+            .filter(|dependency| !loaded.contains(*dependency) && !namespaces.contains(*dependency))
+            .map(String::as_str)
+            .collect();
+        let reason = if missing.is_empty() {
+            "its dependencies couldn't load or depend on it".to_string()
+        } else {
+            format!("missing dependencies {}", missing.join(", "))
+        };
+        report.skipped.push((pack.root.join(PACK_MANIFEST), reason));
+    }
+    ordered
+}
+
+// Which file every id came from. Each kind is kept apart so a block and a biome can share a name
+#[derive(Default)]
+struct ContentIds {
+    blocks: HashMap<String, PathBuf>,
+    entities: HashMap<String, PathBuf>,
+    biomes: HashMap<String, PathBuf>,
+    structures: HashMap<String, PathBuf>,
+}
+
+// Loads every descriptor in one pack into content. Ids already taken by an earlier pack are left out,
+// packs change those with overrides instead
+fn load_pack(pack: &ContentPack, content: &mut Content, ids: &mut ContentIds) {
+    let root = &pack.root;
+    let manifest = &pack.manifest;
+    let manifest_path = root.join(PACK_MANIFEST);
+    let block_folders: Vec<PathBuf> = manifest.blocks.iter().map(|dir| root.join(dir)).collect();
+    let entity_folders: Vec<PathBuf> = manifest.entities.iter().map(|dir| root.join(dir)).collect();
+    let biome_folders: Vec<PathBuf> = manifest.biomes.iter().map(|dir| root.join(dir)).collect();
+    let structure_folders: Vec<PathBuf> = manifest
+        .structures
+        .iter()
+        .map(|dir| root.join(dir))
+        .collect();
+    let claim = |ids: &mut HashMap<String, PathBuf>,
+                 namespace: &str,
+                 id: String,
+                 path: &Path,
+                 report: &mut ContentLoadReport|
+     -> bool {
+        if namespace != manifest.namespace {
+            report.malformed.push((
+                path.to_owned(),
+                format!(
+                    "namespace {namespace} doesn't match the pack's {}",
+                    manifest.namespace
+                ),
+            ));
+            false
+        } else if let Some(first) = ids.get(&id) {
+            report.malformed.push((
+                path.to_owned(),
+                format!("{id} is already defined by {}", first.display()),
+            ));
+            false
+        } else {
+            ids.insert(id, path.to_owned());
+            true
+        }
+    };
+
+    for entry in WalkDir::new(root)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        let path = entry.path();
+        if path.extension().unwrap_or_default() != "ron" || path == manifest_path {
+            continue;
+        }
+        if block_folders.iter().any(|folder| path.starts_with(folder)) {
+            match load_block(path) {
+                Ok(block) => {
+                    let id = block_identifier(&block.namespace, &block.block_name);
+                    if claim(
+                        &mut ids.blocks,
+                        &block.namespace,
+                        id,
+                        path,
+                        &mut content.report,
+                    ) {
+                        content.blocks.push(block);
+                        content.report.loaded.push(path.to_owned());
+                    }
+                }
+                Err(error) => content.report.malformed.push((path.to_owned(), error)),
+            }
+        } else if entity_folders.iter().any(|folder| path.starts_with(folder)) {
+            match load_entity(path) {
+                Ok(entity) => {
+                    let id = entity_identifier(&entity.namespace, &entity.entity_name);
+                    if claim(
+                        &mut ids.entities,
+                        &entity.namespace,
+                        id,
+                        path,
+                        &mut content.report,
+                    ) {
+                        content.entities.push(entity);
+                        content.report.loaded.push(path.to_owned());
+                    }
+                }
+                Err(error) => content.report.malformed.push((path.to_owned(), error)),
+            }
+        } else if biome_folders.iter().any(|folder| path.starts_with(folder)) {
+            match load_biome(path) {
+                Ok(biome) => {
+                    let id = biome_identifier(&biome.namespace, &biome.biome_name);
+                    if claim(
+                        &mut ids.biomes,
+                        &biome.namespace,
+                        id,
+                        path,
+                        &mut content.report,
+                    ) {
+                        content.biomes.push(biome);
+                        content.report.loaded.push(path.to_owned());
+                    }
+                }
+                Err(error) => content.report.malformed.push((path.to_owned(), error)),
+            }
+        } else if structure_folders
+            .iter()
+            .any(|folder| path.starts_with(folder))
+        {
+            match load_structure(path) {
+                Ok(structure) => {
+                    let id = structure_identifier(&structure.namespace, &structure.structure_name);
+                    if claim(
+                        &mut ids.structures,
+                        &structure.namespace,
+                        id,
+                        path,
+                        &mut content.report,
+                    ) {
+                        content.structures.push(structure);
+                        content.report.loaded.push(path.to_owned());
+                    }
+                }
+                Err(error) => content.report.malformed.push((path.to_owned(), error)),
+            }
+        } else {
+            content
+                .report
+                .skipped
+                .push((path.to_owned(), "not in a content folder".to_string()));
+        }
+    }
+
+    // Overrides only reach blocks from packs that already loaded, so dependencies should be listed
+    let mut overrides: Vec<(&String, &BlockOverride)> = manifest.overrides.iter().collect();
+    overrides.sort_by(|a, b| a.0.cmp(b.0));
+    for (block_id, block_override) in overrides {
+        match content
+            .blocks
+            .iter_mut()
+            .find(|block| &block_identifier(&block.namespace, &block.block_name) == block_id)
+        {
+            Some(block) => block_override.apply(block, root),
+            None => content.report.malformed.push((
+                manifest_path.clone(),
+                format!("can't override {block_id}, no pack before this one has it"),
+            )),
+        }
+    }
+}
+
+// The base game's content and then each pack on top of it
+pub fn load_packs(base_root: &Path, pack_roots: &[PathBuf]) -> Content {
+    let mut content = Content::default();
+    let base = match read_pack(base_root, true) {
+        Ok(base) => base,
+        Err(error) => {
+            content.report.malformed.push(error);
+            return content;
+        }
+    };
+    let mut packs = Vec::new();
+    for root in pack_roots {
+        match read_pack(root, false) {
+            Ok(pack) => packs.push(pack),
+            Err(error) => content.report.malformed.push(error),
+        }
+    }
+    let mut ids = ContentIds::default();
+    for pack in order_packs(base, packs, &mut content.report) {
+        load_pack(&pack, &mut content, &mut ids);
+        content.packs.push(pack);
+    }
+    content
+}
+
+// Just the content in one folder
+pub fn load_content(root: &Path) -> Content {
+    load_packs(root, &[])
+}
+
+// Zip packs get unpacked fresh every time so they're loaded the same way as folders
+fn unpack_zip(zip_path: &Path, destination: &Path) -> Result<(), String> {
+    if destination.exists() {
+        fs::remove_dir_all(destination).map_err(|error| error.to_string())?;
+    }
+    let file = fs::File::open(zip_path).map_err(|error| error.to_string())?;
+    let mut archive = zip::ZipArchive::new(file).map_err(|error| error.to_string())?;
+    archive
+        .extract(destination)
+        .map_err(|error| error.to_string())
+}
+
+// Folders and zip files in the packs folder. A zip is unpacked into unpacked_dir first. Gives back the pack
+// roots and every zip that was found
+pub fn find_packs(
+    packs_dir: &Path,
+    unpacked_dir: &Path,
+    report: &mut ContentLoadReport,
+) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(packs_dir) else {
+        return (Vec::new(), Vec::new());
+    };
+    let mut paths: Vec<PathBuf> = entries.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+    paths.sort();
+    let mut roots = Vec::new();
+    let mut archives = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            roots.push(path);
+        } else if path.extension().unwrap_or_default() == "zip" {
+            archives.push(path.clone());
+            let destination = unpacked_dir.join(path.file_stem().unwrap_or_default());
+            match unpack_zip(&path, &destination) {
+                Ok(()) => roots.push(destination),
+                Err(error) => report.malformed.push((path, error)),
+            }
+        }
+    }
+    (roots, archives)
+}
+
+// The assets folder of the user data dir and every pack in the packs folder next to it
+pub fn load_all_content() -> Content {
+    let asset_dir = asset_dir();
+    let mut report = ContentLoadReport::default();
+    let (packs, archives) = match project_dirs() {
+        Some(proj_dirs) => find_packs(
+            &proj_dirs.data_dir().join(PACKS_FOLDER),
+            &proj_dirs.cache_dir().join(PACKS_FOLDER),
+            &mut report,
+        ),
+        None => (Vec::new(), Vec::new()),
+    };
+    let mut content = load_packs(&asset_dir, &packs);
+    content.archives = archives;
+    report.loaded.append(&mut content.report.loaded);
+    report.skipped.append(&mut content.report.skipped);
+    report.malformed.append(&mut content.report.malformed);
+    content.report = report;
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::game::{
+        scripting::block::block_descriptor::BlockDescriptor, world::block_state::StateProperty,
+    };
+
+    use super::BlockOverride;
+
+    #[test]
+    fn override_makes_a_slab() {
+        let mut block: BlockDescriptor = ron::from_str(
+            r#"BlockDescriptor(
+                namespace: "vinox",
+                block_name: "cobblestone",
+                textures: {"up": "cobblestone.png"},
+                interactable: false,
+                friction: 0.5,
+                break_time: 5.0,
+                break_tool: "pickaxe",
+                walk_sound: None,
+                break_sound: None,
+                block_script: None,
+                visibility: "opaque",
+                block_geometry: "block",
+                light_val: 0,
+                block_states: {"mossy": Bool},
+            )"#,
+        )
+        .unwrap();
+        let block_override: BlockOverride = ron::from_str(
+            r#"(
+                block_geometry: Some("horizontalslab"),
+                block_model: Some("models/rubble.gltf"),
+                block_states: {"half": Enum(["bottom", "top"]), "mossy": Int(0, 3)},
+            )"#,
+        )
+        .unwrap();
+        block_override.apply(&mut block, Path::new("packs/rubble"));
+
+        assert_eq!(block.block_geometry, "horizontalslab");
+        assert_eq!(
+            block.block_model.map(|model| Path::new(&model).to_owned()),
+            Some(Path::new("packs/rubble").join("models/rubble.gltf"))
+        );
+        assert_eq!(
+            block.block_states.get("half"),
+            Some(&StateProperty::Enum(vec![
+                "bottom".to_string(),
+                "top".to_string()
+            ]))
+        );
+        assert_eq!(
+            block.block_states.get("mossy"),
+            Some(&StateProperty::Int(0, 3))
+        );
+        // Everything the override leaves out stays
+        assert_eq!(block.friction, 0.5);
+        assert_eq!(block.textures["up"], "cobblestone.png");
+    }
+}
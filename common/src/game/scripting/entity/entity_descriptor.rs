@@ -1,14 +1,33 @@
-use serde::{Serialize, Deserialize};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
+fn default_friction() -> f32 {
+    0.5
+}
+
+fn default_ai_type() -> String {
+    "walk".to_string()
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct EntityDescriptor {
     pub namespace: String,
     pub entity_name: String,
     pub model: String, // We will allow someone to specify a gltf however i want to build in a few types such as slabs
+    #[serde(default = "default_friction")]
     pub friction: f32,
+    // Blocks per second when moving on its own
+    #[serde(default)]
+    pub speed: f32,
+    #[serde(default)]
     pub attack: u32,
+    #[serde(default)]
     pub entity_script: Option<String>,
+    #[serde(default)]
     pub interactable: bool,
-    pub ai_type: String
-}
\ No newline at end of file
+    #[serde(default = "default_ai_type")]
+    pub ai_type: String,
+    // The ron file this was loaded from, scripts are relative to its folder
+    #[serde(skip)]
+    pub file: PathBuf,
+}
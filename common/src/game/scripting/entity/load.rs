@@ -1,25 +1,12 @@
-use directories::ProjectDirs;
-use std::fs::{self};
-
-use walkdir::WalkDir;
+use std::{fs, path::Path};
 
 use super::entity_descriptor::EntityDescriptor;
 
-pub fn load_all_entities() -> Vec<EntityDescriptor> {
-    let mut result = Vec::new();
-    if let Some(proj_dirs) = ProjectDirs::from("com", "vinox", "vinox") {
-        for entry in WalkDir::new(proj_dirs.data_dir().join("assets"))
-            .into_iter()
-            .filter_map(|e| e.ok())
-        {
-            if entry.path().extension().unwrap_or_default() == "ron" {
-                if let Ok(ron_string) = fs::read_to_string(entry.path()) {
-                    if let Ok(entity) = ron::from_str(ron_string.as_str()) {
-                        result.push(entity);
-                    }
-                }
-            }
-        }
-    }
-    result
+// Reads one entity descriptor, errors are ready to go in a ContentLoadReport
+pub fn load_entity(path: &Path) -> Result<EntityDescriptor, String> {
+    let ron_string = fs::read_to_string(path).map_err(|error| error.to_string())?;
+    let mut entity =
+        ron::from_str::<EntityDescriptor>(&ron_string).map_err(|error| error.to_string())?;
+    entity.file = path.to_owned();
+    Ok(entity)
 }
@@ -1,2 +1,3 @@
+pub mod entity_descriptor;
 pub mod load;
-pub mod entity_descriptor;
\ No newline at end of file
+pub mod script;
@@ -0,0 +1,330 @@
+use std::{cell::RefCell, collections::HashMap, fs};
+
+use bevy::prelude::*;
+use mlua::{Function, LuaSerdeExt, RegistryKey, Table, Value};
+
+use crate::game::{
+    scripting::sandbox::{load_script, LimitedLua, ScriptError},
+    storage::EntityType,
+};
+
+// Callbacks an entity script can define as globals. Every one gets the entity's own state table first,
+// eg `function on_tick(self, delta) self.age = (self.age or 0) + delta end`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EntityHook {
+    Spawn,
+    Tick { delta: f32 },
+    // Source is the player that did the damage if there was one
+    Damage { amount: f32, source: Option<u64> },
+    Interact { player: u64 },
+    Death,
+}
+
+impl EntityHook {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EntityHook::Spawn => "on_spawn",
+            EntityHook::Tick { .. } => "on_tick",
+            EntityHook::Damage { .. } => "on_damage",
+            EntityHook::Interact { .. } => "on_interact",
+            EntityHook::Death => "on_death",
+        }
+    }
+}
+
+// What a script can do to the entity it belongs to and the world around it
+pub trait EntityWorld {
+    fn position(&self) -> Vec3;
+    fn move_to(&mut self, pos: Vec3);
+    // Player ids and positions within radius of the entity
+    fn nearby_players(&self, radius: f32) -> Vec<(u64, Vec3)>;
+    fn emit(&mut self, name: String, data: ron::Value);
+}
+
+// One Lua state for every entity script, same as blocks. Scripts are loaded once per entity type and each
+// spawned entity gets its own state table that lives until it's despawned
+pub struct EntityScripts {
+    lua: LimitedLua,
+    // Entity type to the script's name and environment
+    scripts: HashMap<String, (String, RegistryKey)>,
+    states: HashMap<Entity, (String, RegistryKey)>,
+}
+
+impl Default for EntityScripts {
+    fn default() -> EntityScripts {
+        EntityScripts::new()
+    }
+}
+
+impl EntityScripts {
+    pub fn new() -> EntityScripts {
+        EntityScripts {
+            lua: LimitedLua::new(),
+            scripts: HashMap::new(),
+            states: HashMap::new(),
+        }
+    }
+
+    // Loads the script of every entity type that has one. A broken script only takes out its own type
+    pub fn load_entities(&mut self, entities: &HashMap<String, EntityType>) {
+        for (entity_id, entity_type) in entities.iter() {
+            let Some(path) = &entity_type.entity_script else {
+                continue;
+            };
+            match fs::read_to_string(path) {
+                Ok(source) => {
+                    if let Err(error) = self.load(entity_id, path, &source) {
+                        warn!("Couldn't load the script for {entity_id}: {error}");
+                    }
+                }
+                Err(error) => warn!("Couldn't read {path}: {error}"),
+            }
+        }
+    }
+
+    // Swaps every script for a fresh copy from disk. Entities that are already spawned keep their state
+    pub fn reload(&mut self, entities: &HashMap<String, EntityType>) {
+        for (_, (_, key)) in self.scripts.drain() {
+            self.lua.remove_registry_value(key).ok();
+        }
+        self.load_entities(entities);
+    }
+
+    // Name shows up in error messages, usually the path of the script
+    pub fn load(&mut self, entity_id: &str, name: &str, source: &str) -> Result<(), ScriptError> {
+        let key = load_script(&self.lua, name, source)?;
+        if let Some((_, old_key)) = self
+            .scripts
+            .insert(entity_id.to_owned(), (name.to_owned(), key))
+        {
+            self.lua.remove_registry_value(old_key).ok();
+        }
+        Ok(())
+    }
+
+    pub fn has_script(&self, entity_id: &str) -> bool {
+        self.scripts.contains_key(entity_id)
+    }
+
+    // Gives the entity a fresh state table and runs on_spawn. Entities without a script are ignored
+    pub fn spawn(
+        &mut self,
+        entity: Entity,
+        entity_id: &str,
+        world: &mut dyn EntityWorld,
+    ) -> Result<(), ScriptError> {
+        let Some((name, _)) = self.scripts.get(entity_id) else {
+            return Ok(());
+        };
+        let state = self.lua.run(name, "spawn", |lua| {
+            lua.create_registry_value(lua.create_table()?)
+        })?;
+        if let Some((_, old_state)) = self.states.insert(entity, (entity_id.to_owned(), state)) {
+            self.lua.remove_registry_value(old_state).ok();
+        }
+        self.call(entity, world, EntityHook::Spawn)
+    }
+
+    // Drops the state table, on_death should already have been called if the entity died
+    pub fn despawn(&mut self, entity: Entity) {
+        if let Some((_, state)) = self.states.remove(&entity) {
+            self.lua.remove_registry_value(state).ok();
+        }
+    }
+
+    pub fn call(
+        &self,
+        entity: Entity,
+        world: &mut dyn EntityWorld,
+        hook: EntityHook,
+    ) -> Result<(), ScriptError> {
+        let Some((entity_id, state)) = self.states.get(&entity) else {
+            return Ok(());
+        };
+        let Some((name, key)) = self.scripts.get(entity_id) else {
+            return Ok(());
+        };
+        self.lua.run(name, hook.name(), |lua| {
+            let env: Table = lua.registry_value(key)?;
+            let Some(function) = env.get::<_, Option<Function>>(hook.name())? else {
+                return Ok(());
+            };
+            let state: Table = lua.registry_value(state)?;
+            let world = RefCell::new(world);
+            lua.scope(|scope| {
+                let api = lua.create_table()?;
+                api.set(
+                    "position",
+                    scope.create_function(|_, ()| {
+                        let pos = world.borrow().position();
+                        Ok((pos.x, pos.y, pos.z))
+                    })?,
+                )?;
+                api.set(
+                    "move_to",
+                    scope.create_function(|_, (x, y, z): (f32, f32, f32)| {
+                        world.borrow_mut().move_to(Vec3::new(x, y, z));
+                        Ok(())
+                    })?,
+                )?;
+                // A list of { id = ..., x = ..., y = ..., z = ... }
+                api.set(
+                    "nearby_players",
+                    scope.create_function(|lua, radius: f32| {
+                        let players = lua.create_table()?;
+                        for (index, (id, pos)) in
+                            world.borrow().nearby_players(radius).iter().enumerate()
+                        {
+                            let player = lua.create_table()?;
+                            player.set("id", *id)?;
+                            player.set("x", pos.x)?;
+                            player.set("y", pos.y)?;
+                            player.set("z", pos.z)?;
+                            players.set(index + 1, player)?;
+                        }
+                        Ok(players)
+                    })?,
+                )?;
+                // Data can be anything plain, tables turn into maps or lists
+                api.set(
+                    "emit",
+                    scope.create_function(|lua, (name, data): (String, Value)| {
+                        let data = lua.from_value::<ron::Value>(data)?;
+                        world.borrow_mut().emit(name, data);
+                        Ok(())
+                    })?,
+                )?;
+                env.set("entity", api)?;
+                let result = match hook {
+                    EntityHook::Spawn | EntityHook::Death => function.call::<_, ()>(state),
+                    EntityHook::Tick { delta } => function.call::<_, ()>((state, delta)),
+                    EntityHook::Damage { amount, source } => {
+                        function.call::<_, ()>((state, amount, source))
+                    }
+                    EntityHook::Interact { player } => function.call::<_, ()>((state, player)),
+                };
+                env.set("entity", Value::Nil)?;
+                result
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use super::{EntityHook, EntityScripts, EntityWorld};
+    use crate::game::scripting::sandbox::ScriptError;
+
+    #[derive(Default)]
+    struct TestEntity {
+        pos: Vec3,
+        events: Vec<(String, ron::Value)>,
+    }
+
+    impl EntityWorld for TestEntity {
+        fn position(&self) -> Vec3 {
+            self.pos
+        }
+
+        fn move_to(&mut self, pos: Vec3) {
+            self.pos = pos;
+        }
+
+        fn nearby_players(&self, radius: f32) -> Vec<(u64, Vec3)> {
+            [
+                (1, Vec3::new(3.0, 0.0, 0.0)),
+                (2, Vec3::new(30.0, 0.0, 0.0)),
+            ]
+            .into_iter()
+            .filter(|(_, pos)| pos.distance(self.pos) <= radius)
+            .collect()
+        }
+
+        fn emit(&mut self, name: String, data: ron::Value) {
+            self.events.push((name, data));
+        }
+    }
+
+    #[test]
+    fn entities_keep_their_own_state() {
+        let mut scripts = EntityScripts::new();
+        scripts
+            .load(
+                "vinox:golem",
+                "golem.lua",
+                r#"
+                function on_spawn(self)
+                    self.hits = 0
+                end
+                function on_damage(self, amount, source)
+                    self.hits = self.hits + 1
+                    local x, y, z = entity.position()
+                    entity.move_to(x, y + amount, z)
+                    entity.emit("hits", self.hits)
+                end
+                function on_interact(self, player)
+                    local players = entity.nearby_players(10)
+                    error("can't talk to " .. #players .. " players")
+                end
+                "#,
+            )
+            .unwrap();
+
+        let first = Entity::from_raw(1);
+        let second = Entity::from_raw(2);
+        let mut first_world = TestEntity::default();
+        let mut second_world = TestEntity::default();
+        scripts
+            .spawn(first, "vinox:golem", &mut first_world)
+            .unwrap();
+        scripts
+            .spawn(second, "vinox:golem", &mut second_world)
+            .unwrap();
+
+        let damage = EntityHook::Damage {
+            amount: 2.0,
+            source: None,
+        };
+        scripts.call(first, &mut first_world, damage).unwrap();
+        scripts.call(first, &mut first_world, damage).unwrap();
+        scripts.call(second, &mut second_world, damage).unwrap();
+        let hits = |world: &TestEntity| -> Vec<String> {
+            world
+                .events
+                .iter()
+                .map(|(_, data)| ron::to_string(data).unwrap())
+                .collect()
+        };
+        assert_eq!(hits(&first_world), ["1", "2"]);
+        assert_eq!(hits(&second_world), ["1"]);
+        assert_eq!(first_world.pos, Vec3::new(0.0, 4.0, 0.0));
+
+        // Errors point at the script and line and don't break the entity
+        let error = scripts
+            .call(first, &mut first_world, EntityHook::Interact { player: 1 })
+            .unwrap_err();
+        let ScriptError::Lua {
+            script,
+            hook,
+            message,
+        } = error
+        else {
+            panic!("expected a Lua error, got {error}");
+        };
+        assert_eq!(script, "golem.lua");
+        assert_eq!(hook, "on_interact");
+        assert!(message.contains("golem.lua:13:"), "{message}");
+        assert!(message.contains("can't talk to 1 players"), "{message}");
+        scripts.call(first, &mut first_world, damage).unwrap();
+        assert_eq!(hits(&first_world), ["1", "2", "3"]);
+
+        // Despawned entities have no state left so hooks do nothing
+        scripts.despawn(first);
+        scripts.call(first, &mut first_world, damage).unwrap();
+        assert_eq!(first_world.events.len(), 3);
+        scripts.call(second, &mut second_world, damage).unwrap();
+        assert_eq!(hits(&second_world), ["1", "2"]);
+    }
+}
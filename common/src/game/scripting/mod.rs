@@ -1,2 +1,6 @@
+pub mod biome;
 pub mod block;
-pub mod entity;
\ No newline at end of file
+pub mod content;
+pub mod entity;
+pub mod sandbox;
+pub mod structure;
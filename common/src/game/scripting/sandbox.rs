@@ -0,0 +1,326 @@
+use std::{
+    cell::Cell,
+    fmt, fs,
+    ops::Deref,
+    path::{Component, Path, PathBuf},
+    rc::Rc,
+};
+
+use bevy::prelude::*;
+use mlua::{Function, HookTriggers, Lua, MultiValue, RegistryKey, Table, Value, Variadic};
+
+// Globals scripts get from the standard library. Nothing in here can reach files, the os or other scripts
+const SAFE_GLOBALS: [&str; 14] = [
+    "assert",
+    "error",
+    "ipairs",
+    "next",
+    "pairs",
+    "rawequal",
+    "rawget",
+    "rawlen",
+    "rawset",
+    "select",
+    "setmetatable",
+    "tonumber",
+    "tostring",
+    "type",
+];
+// Copied per script so one script can't swap out functions another one uses
+const SAFE_LIBRARIES: [&str; 3] = ["math", "string", "table"];
+// The parts of os that only tell the time
+const SAFE_OS: [&str; 4] = ["clock", "date", "difftime", "time"];
+
+// Every hook call (and every script load) gets this many instructions before it's aborted
+pub const INSTRUCTION_LIMIT: u32 = 1_000_000;
+// How often the instruction hook runs, the budget is spent in steps of this
+const INSTRUCTION_STEP: u32 = 1000;
+// Shared by every script in one Lua state
+pub const MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptError {
+    InstructionLimit {
+        script: String,
+        hook: String,
+    },
+    MemoryLimit {
+        script: String,
+        hook: String,
+    },
+    // Anything Lua raised itself, the message has the file and line in it
+    Lua {
+        script: String,
+        hook: String,
+        message: String,
+    },
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::InstructionLimit { script, hook } => {
+                write!(
+                    f,
+                    "{script} {hook} ran over {INSTRUCTION_LIMIT} instructions"
+                )
+            }
+            ScriptError::MemoryLimit { script, hook } => {
+                write!(f, "{script} {hook} ran out of memory")
+            }
+            ScriptError::Lua {
+                script,
+                hook,
+                message,
+            } => write!(f, "{script} {hook} failed: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+fn is_memory_error(error: &mlua::Error) -> bool {
+    match error {
+        mlua::Error::MemoryError(_) => true,
+        mlua::Error::CallbackError { cause, .. } => is_memory_error(cause),
+        _ => false,
+    }
+}
+
+// A Lua state with the instruction and memory limits hooked in. Anything that runs script code should
+// go through run so the budget gets reset and errors come out as ScriptErrors
+pub struct LimitedLua {
+    lua: Lua,
+    budget: Rc<Cell<u32>>,
+}
+
+impl Default for LimitedLua {
+    fn default() -> LimitedLua {
+        LimitedLua::new()
+    }
+}
+
+impl Deref for LimitedLua {
+    type Target = Lua;
+
+    fn deref(&self) -> &Lua {
+        &self.lua
+    }
+}
+
+impl LimitedLua {
+    pub fn new() -> LimitedLua {
+        let lua = Lua::new();
+        lua.set_memory_limit(MEMORY_LIMIT).unwrap();
+        let budget = Rc::new(Cell::new(0_u32));
+        let hook_budget = budget.clone();
+        // Once the budget is gone this keeps erroring so a script can't pcall its way past it
+        lua.set_hook(
+            HookTriggers {
+                every_nth_instruction: Some(INSTRUCTION_STEP),
+                ..Default::default()
+            },
+            move |_, _| {
+                let left = hook_budget.get().saturating_sub(INSTRUCTION_STEP);
+                hook_budget.set(left);
+                if left == 0 {
+                    Err(mlua::Error::RuntimeError(
+                        "instruction limit reached".to_string(),
+                    ))
+                } else {
+                    Ok(())
+                }
+            },
+        )
+        .unwrap();
+        LimitedLua { lua, budget }
+    }
+
+    pub fn run<'lua, R>(
+        &'lua self,
+        script: &str,
+        hook: &str,
+        f: impl FnOnce(&'lua Lua) -> mlua::Result<R>,
+    ) -> Result<R, ScriptError> {
+        self.budget.set(INSTRUCTION_LIMIT);
+        f(&self.lua).map_err(|error| {
+            let script = script.to_string();
+            let hook = hook.to_string();
+            if self.budget.get() == 0 {
+                ScriptError::InstructionLimit { script, hook }
+            } else if is_memory_error(&error) {
+                ScriptError::MemoryLimit { script, hook }
+            } else {
+                ScriptError::Lua {
+                    script,
+                    hook,
+                    message: error.to_string(),
+                }
+            }
+        })
+    }
+}
+
+// Only plain module names under the script's own folder, eg require("util") or require("ai.walk")
+fn module_path(directory: &Path, module: &str) -> Option<PathBuf> {
+    let relative = PathBuf::from(module.replace('.', "/")).with_extension("lua");
+    relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+        .then(|| directory.join(relative))
+}
+
+// A fresh environment table for one script. Block and entity scripts share a Lua state per kind but
+// never each others globals
+fn sandbox<'lua>(
+    lua: &'lua Lua,
+    budget: &Rc<Cell<u32>>,
+    directory: &Path,
+) -> mlua::Result<Table<'lua>> {
+    let globals = lua.globals();
+    let env = lua.create_table()?;
+    for name in SAFE_GLOBALS {
+        env.set(name, globals.get::<_, Value>(name)?)?;
+    }
+    for name in SAFE_LIBRARIES {
+        let library = lua.create_table()?;
+        for pair in globals.get::<_, Table>(name)?.pairs::<Value, Value>() {
+            let (key, value) = pair?;
+            library.set(key, value)?;
+        }
+        env.set(name, library)?;
+    }
+    let os = lua.create_table()?;
+    let global_os = globals.get::<_, Table>("os")?;
+    for name in SAFE_OS {
+        os.set(name, global_os.get::<_, Value>(name)?)?;
+    }
+    env.set("os", os)?;
+    // The real pcall would catch hitting a limit and let the script carry on, this one lets those through
+    let pcall_budget = budget.clone();
+    env.set(
+        "pcall",
+        lua.create_function(move |lua, (function, args): (Function, MultiValue)| {
+            match function.call::<_, MultiValue>(args) {
+                Ok(values) => Ok(MultiValue::from_vec(
+                    std::iter::once(Value::Boolean(true))
+                        .chain(values)
+                        .collect(),
+                )),
+                Err(error) if pcall_budget.get() == 0 || is_memory_error(&error) => Err(error),
+                Err(error) => Ok(MultiValue::from_vec(vec![
+                    Value::Boolean(false),
+                    Value::String(lua.create_string(&error.to_string())?),
+                ])),
+            }
+        })?,
+    )?;
+    // Print ends up in the server log
+    env.set(
+        "print",
+        lua.create_function(|lua, values: Variadic<Value>| {
+            let mut message = Vec::new();
+            for value in values {
+                match lua.coerce_string(value)? {
+                    Some(string) => message.push(string.to_str()?.to_owned()),
+                    None => message.push("nil".to_string()),
+                }
+            }
+            info!("[script] {}", message.join("\t"));
+            Ok(())
+        })?,
+    )?;
+    // Modules run in the environment of the script that required them and aren't cached
+    let env_key = lua.create_registry_value(env.clone())?;
+    let directory = directory.to_owned();
+    env.set(
+        "require",
+        lua.create_function(move |lua, module: String| {
+            let Some(path) = module_path(&directory, &module) else {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "can't require {module}, only modules next to the script can be loaded"
+                )));
+            };
+            let source = fs::read_to_string(&path).map_err(|error| {
+                mlua::Error::RuntimeError(format!("can't require {module}: {error}"))
+            })?;
+            lua.load(&source)
+                .set_name(&format!("@{}", path.display()))?
+                .set_environment(lua.registry_value::<Table>(&env_key)?)?
+                .call::<_, MultiValue>(())
+        })?,
+    )?;
+    Ok(env)
+}
+
+// Runs a script in its own sandbox and hands back the environment with whatever it defined. Name shows
+// up in error messages along with the line, usually it's the path of the script
+pub fn load_script(lua: &LimitedLua, name: &str, source: &str) -> Result<RegistryKey, ScriptError> {
+    lua.run(name, "load", |inner| {
+        let directory = Path::new(name).parent().unwrap_or(Path::new(""));
+        let env = sandbox(inner, &lua.budget, directory)?;
+        inner
+            .load(source)
+            .set_name(&format!("@{name}"))?
+            .set_environment(env.clone())?
+            .exec()?;
+        inner.create_registry_value(env)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bevy::prelude::*;
+
+    use super::ScriptError;
+    use crate::game::scripting::block::script::{BlockHook, BlockScripts};
+
+    #[test]
+    fn runaway_scripts_are_aborted() {
+        let mut scripts = BlockScripts::new();
+        scripts
+            .load(
+                "vinox:grass",
+                "grass.lua",
+                r#"
+                function on_place(x, y, z, block)
+                    while true do end
+                end
+                function on_break(x, y, z, block)
+                    -- Catching the error doesn't buy any more instructions
+                    while true do pcall(function() while true do end end) end
+                end
+                function on_random_tick(x, y, z, block)
+                    local hoard = {}
+                    while true do hoard[#hoard + 1] = string.rep("x", 1024 * 1024) end
+                end
+                function on_interact(x, y, z, block, player)
+                    if io ~= nil or require("../../escape") then error("sandbox leaked") end
+                end
+                "#,
+            )
+            .unwrap();
+
+        let mut world: HashMap<IVec3, String> = HashMap::new();
+        let mut call = |hook| scripts.call(&mut world, hook, IVec3::ZERO, "vinox:grass");
+        assert!(matches!(
+            call(BlockHook::Place),
+            Err(ScriptError::InstructionLimit { .. })
+        ));
+        assert!(matches!(
+            call(BlockHook::Break),
+            Err(ScriptError::InstructionLimit { .. })
+        ));
+        assert!(matches!(
+            call(BlockHook::RandomTick),
+            Err(ScriptError::MemoryLimit { .. })
+        ));
+        // Fails on the require, not the io check
+        match call(BlockHook::Interact { player: 0 }) {
+            Err(ScriptError::Lua { message, .. }) => assert!(message.contains("can't require")),
+            result => panic!("expected a Lua error, got {result:?}"),
+        }
+    }
+}
@@ -0,0 +1,9 @@
+use std::{fs, path::Path};
+
+use super::structure_descriptor::StructureDescriptor;
+
+// Reads one structure descriptor, errors are ready to go in a ContentLoadReport
+pub fn load_structure(path: &Path) -> Result<StructureDescriptor, String> {
+    let ron_string = fs::read_to_string(path).map_err(|error| error.to_string())?;
+    ron::from_str::<StructureDescriptor>(&ron_string).map_err(|error| error.to_string())
+}
@@ -0,0 +1,89 @@
+use std::collections::BTreeMap;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// A box of one block from min to max, both included
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StructureBox {
+    pub min: (i32, i32, i32),
+    pub max: (i32, i32, i32),
+    pub block: String,
+}
+
+// Anything bigger than a block placed on top of the terrain, like trees and boulders. Positions are relative
+// to the origin, which is the first air block above the ground. Blocks are full block states
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StructureDescriptor {
+    pub namespace: String,
+    pub structure_name: String,
+    // Filled in first, later boxes win where they overlap
+    #[serde(default)]
+    pub boxes: Vec<StructureBox>,
+    // Single blocks placed over the boxes
+    #[serde(default)]
+    pub blocks: Vec<((i32, i32, i32), String)>,
+    // Biome ids it can generate in, empty is every biome
+    #[serde(default)]
+    pub biomes: Vec<String>,
+    // Tries per region and the chance each one places a structure
+    pub attempts: u32,
+    pub chance: f64,
+}
+
+impl StructureDescriptor {
+    // Every block of the structure, sorted by position
+    pub fn block_list(&self) -> Vec<(IVec3, String)> {
+        let mut blocks = BTreeMap::new();
+        for structure_box in self.boxes.iter() {
+            let (min, max) = (structure_box.min, structure_box.max);
+            for x in min.0..=max.0 {
+                for y in min.1..=max.1 {
+                    for z in min.2..=max.2 {
+                        blocks.insert((x, y, z), structure_box.block.clone());
+                    }
+                }
+            }
+        }
+        for (pos, block) in self.blocks.iter() {
+            blocks.insert(*pos, block.clone());
+        }
+        blocks
+            .into_iter()
+            .map(|((x, y, z), block)| (IVec3::new(x, y, z), block))
+            .collect()
+    }
+
+    // Copies everything that isn't air between min and max, both included. The origin ends up on the bottom
+    // middle, write it out with ron to use it as a template
+    pub fn capture(
+        namespace: &str,
+        structure_name: &str,
+        min: IVec3,
+        max: IVec3,
+        get_block: impl Fn(IVec3) -> Option<String>,
+    ) -> StructureDescriptor {
+        let origin = IVec3::new((min.x + max.x) / 2, min.y, (min.z + max.z) / 2);
+        let mut blocks = Vec::new();
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                for z in min.z..=max.z {
+                    let pos = IVec3::new(x, y, z);
+                    if let Some(block) = get_block(pos).filter(|block| block != "air") {
+                        let offset = pos - origin;
+                        blocks.push(((offset.x, offset.y, offset.z), block));
+                    }
+                }
+            }
+        }
+        StructureDescriptor {
+            namespace: namespace.to_string(),
+            structure_name: structure_name.to_string(),
+            boxes: Vec::new(),
+            blocks,
+            biomes: Vec::new(),
+            attempts: 1,
+            chance: 1.0,
+        }
+    }
+}
@@ -1,10 +1,19 @@
+use crate::game::scripting::biome::biome_descriptor::BiomeDescriptor;
 use crate::game::scripting::block::block_descriptor::BlockDescriptor;
 use crate::game::scripting::entity::entity_descriptor::EntityDescriptor;
-use std::collections::HashMap;
+use crate::game::scripting::structure::structure_descriptor::StructureDescriptor;
+use bevy::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::PathBuf;
 use std::str::FromStr;
 use strum_macros::EnumString;
 
+use super::world::block_state::StateProperty;
 use super::world::chunk::{GeometryType, VoxelVisibility};
+use super::world::registry::{
+    biome_identifier, block_identifier, entity_identifier, structure_identifier,
+};
 
 #[derive(Debug, PartialEq, EnumString, Default, Clone)]
 pub enum AiType {
@@ -32,6 +41,7 @@ pub enum BreakTool {
 pub struct BlockType {
     pub namespace: String,
     pub block_name: String,
+    // Side to the full path of its texture, packs can swap them out so they aren't all in one folder
     pub textures: HashMap<String, String>,
     pub interactable: bool,
     pub friction: f32,
@@ -39,10 +49,42 @@ pub struct BlockType {
     pub break_tool: BreakTool,
     pub walk_sound: Option<String>,
     pub break_sound: Option<String>,
+    // Full paths like textures, the descriptor's are relative to its ron file
     pub block_script: Option<String>,
     pub visibility: VoxelVisibility,
     pub block_geometry: GeometryType,
+    pub block_model: Option<String>,
     pub light_val: u8,
+    pub block_states: BTreeMap<String, StateProperty>,
+}
+
+impl BlockType {
+    // Blocks drawn with a model or as a slab or stairs don't fill their whole voxel
+    pub fn is_full_cube(&self) -> bool {
+        self.block_geometry.is_full() && self.block_model.is_none()
+    }
+
+    // A plain "vinox:<name>" cube for tests. The client's tests turn on test-blocks to get it too
+    #[cfg(any(test, feature = "test-blocks"))]
+    pub fn test(name: &str, visibility: VoxelVisibility, light_val: u8) -> BlockType {
+        BlockType {
+            namespace: "vinox".to_string(),
+            block_name: name.to_string(),
+            textures: HashMap::new(),
+            interactable: false,
+            friction: 0.0,
+            break_time: 0.0,
+            break_tool: BreakTool::Pickaxe,
+            walk_sound: None,
+            break_sound: None,
+            block_script: None,
+            visibility,
+            block_geometry: GeometryType::Block,
+            block_model: None,
+            light_val,
+            block_states: BTreeMap::new(),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -51,39 +93,148 @@ pub struct EntityType {
     pub entity_name: String,
     pub model: String, // We will allow someone to specify a gltf however i want to build in a few types such as slabs
     pub friction: f32,
+    pub speed: f32,
     pub attack: u32,
+    // Full path to the script, the descriptor's is relative to its ron file
     pub entity_script: Option<String>,
     pub interactable: bool,
     pub ai_type: AiType,
 }
 
+// A descriptor that parsed but can't be used, field is the name it has in the ron file
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntityError {
+    pub file: PathBuf,
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl fmt::Display for EntityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} {}",
+            self.file.display(),
+            self.field,
+            self.message
+        )
+    }
+}
+
+impl std::error::Error for EntityError {}
+
 pub fn convert_block(block_descriptor: Vec<BlockDescriptor>) -> HashMap<String, BlockType> {
     let mut result = HashMap::new();
     for raw_block in block_descriptor {
+        let in_directory =
+            |path: String| raw_block.directory.join(path).to_string_lossy().to_string();
         result.insert(
-            (raw_block.namespace.to_owned() + raw_block.block_name.as_str()).to_string(),
+            block_identifier(&raw_block.namespace, &raw_block.block_name),
             BlockType {
                 namespace: raw_block.namespace,
                 block_name: raw_block.block_name,
-                textures: raw_block.textures,
+                textures: raw_block
+                    .textures
+                    .into_iter()
+                    .map(|(side, texture)| (side, in_directory(texture)))
+                    .collect(),
                 interactable: raw_block.interactable,
                 friction: raw_block.friction,
                 break_time: raw_block.break_time,
                 break_tool: BreakTool::from_str(raw_block.break_tool.as_str()).unwrap_or_default(),
                 walk_sound: raw_block.walk_sound,
                 break_sound: raw_block.break_sound,
-                block_script: raw_block.block_script,
+                block_script: raw_block.block_script.map(in_directory),
                 visibility: VoxelVisibility::from_str(raw_block.visibility.as_str())
                     .unwrap_or_default(),
                 block_geometry: GeometryType::from_str(raw_block.block_geometry.as_str())
                     .unwrap_or_default(),
+                block_model: raw_block.block_model.map(in_directory),
                 light_val: raw_block.light_val,
+                block_states: raw_block.block_states,
             },
         );
     }
     result
 }
 
-pub fn convert_entity(_entity_descriptor: Vec<EntityDescriptor>) -> HashMap<String, EntityType> {
-    HashMap::new()
+pub fn validate_entity(raw_entity: EntityDescriptor) -> Result<EntityType, EntityError> {
+    let error = |field, message: &str| EntityError {
+        file: raw_entity.file.clone(),
+        field,
+        message: message.to_string(),
+    };
+    if raw_entity.namespace.is_empty() {
+        return Err(error("namespace", "can't be empty"));
+    }
+    if raw_entity.entity_name.is_empty() {
+        return Err(error("entity_name", "can't be empty"));
+    }
+    if raw_entity.model.is_empty() {
+        return Err(error("model", "can't be empty"));
+    }
+    if !raw_entity.friction.is_finite() || raw_entity.friction < 0.0 {
+        return Err(error("friction", "has to be zero or more"));
+    }
+    if !raw_entity.speed.is_finite() || raw_entity.speed < 0.0 {
+        return Err(error("speed", "has to be zero or more"));
+    }
+    let Ok(ai_type) = AiType::from_str(&raw_entity.ai_type) else {
+        return Err(error(
+            "ai_type",
+            &format!("{} isn't one of walk, fly or swim", raw_entity.ai_type),
+        ));
+    };
+    let directory = raw_entity.file.parent().unwrap_or(&raw_entity.file);
+    Ok(EntityType {
+        entity_script: raw_entity
+            .entity_script
+            .map(|script| directory.join(script).to_string_lossy().to_string()),
+        namespace: raw_entity.namespace,
+        entity_name: raw_entity.entity_name,
+        model: raw_entity.model,
+        friction: raw_entity.friction,
+        speed: raw_entity.speed,
+        attack: raw_entity.attack,
+        interactable: raw_entity.interactable,
+        ai_type,
+    })
+}
+
+// Entities that don't validate are left out with a warning
+pub fn convert_entity(entity_descriptor: Vec<EntityDescriptor>) -> HashMap<String, EntityType> {
+    let mut result = HashMap::new();
+    for raw_entity in entity_descriptor {
+        match validate_entity(raw_entity) {
+            Ok(entity_type) => {
+                result.insert(
+                    entity_identifier(&entity_type.namespace, &entity_type.entity_name),
+                    entity_type,
+                );
+            }
+            Err(error) => warn!("{error}"),
+        }
+    }
+    result
+}
+
+pub fn convert_biome(biome_descriptor: Vec<BiomeDescriptor>) -> HashMap<String, BiomeDescriptor> {
+    biome_descriptor
+        .into_iter()
+        .map(|biome| (biome_identifier(&biome.namespace, &biome.biome_name), biome))
+        .collect()
+}
+
+pub fn convert_structure(
+    structure_descriptor: Vec<StructureDescriptor>,
+) -> HashMap<String, StructureDescriptor> {
+    structure_descriptor
+        .into_iter()
+        .map(|structure| {
+            (
+                structure_identifier(&structure.namespace, &structure.structure_name),
+                structure,
+            )
+        })
+        .collect()
 }
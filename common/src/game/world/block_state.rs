@@ -0,0 +1,340 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::game::storage::BlockType;
+
+// Palette entries look like "vinox:stairs;facing=north;half=bottom". Blocks without any properties are
+// just their id so older palettes still work
+pub const STATE_SEPARATOR: char = ';';
+
+// The values a property is allowed to take, declared per block in its ron file. For example
+// block_states: { "facing": Enum(["north", "east", "south", "west"]), "age": Int(0, 7), "open": Bool }
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum StateProperty {
+    Bool,
+    Int(i32, i32),
+    Enum(Vec<String>),
+}
+
+impl StateProperty {
+    pub fn default_value(&self) -> StateValue {
+        match self {
+            StateProperty::Bool => StateValue::Bool(false),
+            StateProperty::Int(min, _) => StateValue::Int(*min),
+            StateProperty::Enum(values) => {
+                StateValue::Enum(values.first().cloned().unwrap_or_default())
+            }
+        }
+    }
+
+    // Reads a value from a palette string as whatever type the block declared, so an enum can have
+    // values like "1" or "true"
+    pub fn parse(&self, value: &str) -> Option<StateValue> {
+        let value = match self {
+            StateProperty::Bool => StateValue::Bool(value.parse().ok()?),
+            StateProperty::Int(..) => StateValue::Int(value.parse().ok()?),
+            StateProperty::Enum(_) => StateValue::Enum(value.to_string()),
+        };
+        self.accepts(&value).then_some(value)
+    }
+
+    pub fn accepts(&self, value: &StateValue) -> bool {
+        match (self, value) {
+            (StateProperty::Bool, StateValue::Bool(_)) => true,
+            (StateProperty::Int(min, max), StateValue::Int(value)) => min <= value && value <= max,
+            (StateProperty::Enum(values), StateValue::Enum(value)) => values.contains(value),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum StateValue {
+    Bool(bool),
+    Int(i32),
+    Enum(String),
+}
+
+impl fmt::Display for StateValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateValue::Bool(value) => write!(f, "{value}"),
+            StateValue::Int(value) => write!(f, "{value}"),
+            StateValue::Enum(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockStateError {
+    Malformed(String),
+    UnknownBlock(String),
+    UnknownProperty {
+        block: String,
+        property: String,
+    },
+    InvalidValue {
+        block: String,
+        property: String,
+        value: String,
+    },
+}
+
+impl fmt::Display for BlockStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockStateError::Malformed(state) => write!(f, "malformed block state: {state}"),
+            BlockStateError::UnknownBlock(block) => write!(f, "unknown block: {block}"),
+            BlockStateError::UnknownProperty { block, property } => {
+                write!(f, "{block} has no property {property}")
+            }
+            BlockStateError::InvalidValue {
+                block,
+                property,
+                value,
+            } => write!(f, "{value} is not a valid value for {block} {property}"),
+        }
+    }
+}
+
+impl std::error::Error for BlockStateError {}
+
+// A property and its value straight out of a palette entry
+type RawProperty<'a> = (&'a str, &'a str);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct BlockState {
+    pub block_id: String,
+    pub properties: BTreeMap<String, StateValue>,
+}
+
+impl BlockState {
+    pub fn new(block_id: impl Into<String>) -> BlockState {
+        BlockState {
+            block_id: block_id.into(),
+            properties: BTreeMap::new(),
+        }
+    }
+
+    // Every property the block declares set to its default
+    pub fn with_defaults(block_id: impl Into<String>, block_type: &BlockType) -> BlockState {
+        BlockState {
+            block_id: block_id.into(),
+            properties: block_type
+                .block_states
+                .iter()
+                .map(|(name, property)| (name.to_owned(), property.default_value()))
+                .collect(),
+        }
+    }
+
+    // The id part of a palette entry without parsing the rest. Used for lookups in LoadableTypes
+    pub fn block_id_of(state: &str) -> &str {
+        state.split(STATE_SEPARATOR).next().unwrap_or(state)
+    }
+
+    pub fn with(mut self, property: impl Into<String>, value: StateValue) -> BlockState {
+        self.set(property, value);
+        self
+    }
+
+    pub fn set(&mut self, property: impl Into<String>, value: StateValue) {
+        self.properties.insert(property.into(), value);
+    }
+
+    pub fn get(&self, property: &str) -> Option<&StateValue> {
+        self.properties.get(property)
+    }
+
+    pub fn get_bool(&self, property: &str) -> Option<bool> {
+        match self.get(property) {
+            Some(StateValue::Bool(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn get_int(&self, property: &str) -> Option<i32> {
+        match self.get(property) {
+            Some(StateValue::Int(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn get_enum(&self, property: &str) -> Option<&str> {
+        match self.get(property) {
+            Some(StateValue::Enum(value)) => Some(value.as_str()),
+            _ => None,
+        }
+    }
+
+    // Splits a palette entry into its id and raw property values
+    fn split(state: &str) -> Result<(&str, Vec<RawProperty<'_>>), BlockStateError> {
+        let mut parts = state.split(STATE_SEPARATOR);
+        let block_id = parts.next().unwrap_or_default();
+        if block_id.is_empty() {
+            return Err(BlockStateError::Malformed(state.to_string()));
+        }
+        let mut properties = Vec::new();
+        for part in parts {
+            let Some((property, value)) = part.split_once('=') else {
+                return Err(BlockStateError::Malformed(state.to_string()));
+            };
+            if property.is_empty() || value.is_empty() {
+                return Err(BlockStateError::Malformed(state.to_string()));
+            }
+            properties.push((property, value));
+        }
+        Ok((block_id, properties))
+    }
+
+    // Parse a palette entry with every value read as the type its property declares
+    pub fn parse_typed(state: &str, block_type: &BlockType) -> Result<BlockState, BlockStateError> {
+        let (block_id, properties) = BlockState::split(state)?;
+        let mut block_state = BlockState::new(block_id);
+        for (property, value) in properties {
+            let Some(declared) = block_type.block_states.get(property) else {
+                return Err(BlockStateError::UnknownProperty {
+                    block: block_id.to_owned(),
+                    property: property.to_owned(),
+                });
+            };
+            let Some(typed) = declared.parse(value) else {
+                return Err(BlockStateError::InvalidValue {
+                    block: block_id.to_owned(),
+                    property: property.to_owned(),
+                    value: value.to_owned(),
+                });
+            };
+            block_state.set(property, typed);
+        }
+        Ok(block_state)
+    }
+
+    // Parse a palette entry and check it against the loaded blocks
+    pub fn parse_validated(
+        state: &str,
+        blocks: &HashMap<String, BlockType>,
+    ) -> Result<BlockState, BlockStateError> {
+        let (block_id, properties) = BlockState::split(state)?;
+        if block_id == "air" {
+            return match properties.first() {
+                Some((property, _)) => Err(BlockStateError::UnknownProperty {
+                    block: block_id.to_owned(),
+                    property: property.to_string(),
+                }),
+                None => Ok(BlockState::new(block_id)),
+            };
+        }
+        let Some(block_type) = blocks.get(block_id) else {
+            return Err(BlockStateError::UnknownBlock(block_id.to_owned()));
+        };
+        BlockState::parse_typed(state, block_type)
+    }
+
+    pub fn validate(&self, block_type: &BlockType) -> Result<(), BlockStateError> {
+        for (property, value) in self.properties.iter() {
+            let Some(declared) = block_type.block_states.get(property) else {
+                return Err(BlockStateError::UnknownProperty {
+                    block: self.block_id.to_owned(),
+                    property: property.to_owned(),
+                });
+            };
+            if !declared.accepts(value) {
+                return Err(BlockStateError::InvalidValue {
+                    block: self.block_id.to_owned(),
+                    property: property.to_owned(),
+                    value: value.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+// Properties are kept sorted so the same state always gives the same palette entry
+impl fmt::Display for BlockState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.block_id)?;
+        for (property, value) in self.properties.iter() {
+            write!(f, "{STATE_SEPARATOR}{property}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+
+    use crate::game::{storage::BlockType, world::chunk::VoxelVisibility};
+
+    use super::{BlockState, BlockStateError, StateProperty, StateValue};
+
+    fn lever() -> BlockType {
+        BlockType {
+            interactable: true,
+            block_states: BTreeMap::from([
+                ("open".to_string(), StateProperty::Bool),
+                ("power".to_string(), StateProperty::Int(0, 15)),
+                (
+                    "mode".to_string(),
+                    StateProperty::Enum(vec!["1".to_string(), "true".to_string()]),
+                ),
+            ]),
+            ..BlockType::test("lever", VoxelVisibility::Opaque, 0)
+        }
+    }
+
+    #[test]
+    fn parses_declared_types() {
+        let lever = lever();
+        let blocks = HashMap::from([("vinox:lever".to_string(), lever.clone())]);
+        let state = "vinox:lever;mode=1;open=true;power=7";
+        let block_state = BlockState::parse_validated(state, &blocks).unwrap();
+        assert_eq!(block_state.get_enum("mode"), Some("1"));
+        assert_eq!(block_state.get_bool("open"), Some(true));
+        assert_eq!(block_state.get_int("power"), Some(7));
+        // Enums that look like bools are still enums
+        let block_state = BlockState::parse_typed("vinox:lever;mode=true", &lever).unwrap();
+        assert_eq!(block_state.get_enum("mode"), Some("true"));
+
+        assert_eq!(
+            BlockState::parse_typed("vinox:lever;power=16", &lever),
+            Err(BlockStateError::InvalidValue {
+                block: "vinox:lever".to_string(),
+                property: "power".to_string(),
+                value: "16".to_string(),
+            })
+        );
+        assert!(BlockState::parse_typed("vinox:lever;open=1", &lever).is_err());
+        assert!(BlockState::parse_typed("vinox:lever;mode=2", &lever).is_err());
+        assert!(BlockState::parse_typed("vinox:lever;color=red", &lever).is_err());
+        assert!(BlockState::parse_typed("vinox:lever;open", &lever).is_err());
+        assert!(BlockState::parse_validated("air", &blocks).is_ok());
+        assert!(BlockState::parse_validated("vinox:door", &blocks).is_err());
+    }
+
+    #[test]
+    fn round_trips() {
+        let lever = lever();
+        let block_state = BlockState::with_defaults("vinox:lever", &lever)
+            .with("power", StateValue::Int(3))
+            .with("mode", StateValue::Enum("true".to_string()));
+        let state = block_state.to_string();
+        // Properties are always sorted
+        assert_eq!(state, "vinox:lever;mode=true;open=false;power=3");
+        assert_eq!(
+            BlockState::parse_typed(&state, &lever).unwrap(),
+            block_state
+        );
+        assert!(block_state.validate(&lever).is_ok());
+
+        let plain = BlockState::parse_typed("vinox:lever", &lever).unwrap();
+        assert!(plain.properties.is_empty());
+        assert_eq!(plain.to_string(), "vinox:lever");
+    }
+}
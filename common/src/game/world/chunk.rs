@@ -1,19 +1,44 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::OnceLock};
 
 use bevy::prelude::*;
 
 use serde::{Deserialize, Serialize};
 
-use serde_big_array::Array;
 use strum_macros::EnumString;
 
-use crate::game::storage::{BlockType, EntityType};
+use crate::game::{
+    scripting::{
+        biome::biome_descriptor::BiomeDescriptor,
+        structure::structure_descriptor::StructureDescriptor,
+    },
+    storage::{BlockType, EntityType},
+};
+
+use super::{
+    block_state::BlockState,
+    registry::{BlockRegistry, AIR_ID},
+    voxel_storage::{StorageError, VoxelStorage},
+};
 
 pub const CHUNK_SIZE: u32 = 32;
+pub const CHUNK_EDGE: u32 = CHUNK_SIZE - 1;
+pub const TOTAL_CHUNK_SIZE: u32 = CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE;
+pub const TOTAL_CHUNK_USIZE: usize = TOTAL_CHUNK_SIZE as usize;
+// The padded sizes are only used for meshing, see PaddedChunkView
 pub const CHUNK_SIZE_PADDED: u32 = CHUNK_SIZE + 2;
 pub const CHUNK_BOUND: u32 = CHUNK_SIZE + 1;
-pub const TOTAL_CHUNK_SIZE: u32 = CHUNK_SIZE_PADDED * CHUNK_SIZE_PADDED * CHUNK_SIZE_PADDED;
-pub const TOTAL_CHUNK_USIZE: usize = TOTAL_CHUNK_SIZE as usize;
+pub const TOTAL_PADDED_CHUNK_USIZE: usize =
+    (CHUNK_SIZE_PADDED * CHUNK_SIZE_PADDED * CHUNK_SIZE_PADDED) as usize;
+
+// Order neighbours are passed around in. Matches the side order used by the mesher
+pub const NEIGHBOR_OFFSETS: [IVec3; 6] = [
+    IVec3::new(-1, 0, 0),
+    IVec3::new(1, 0, 0),
+    IVec3::new(0, -1, 0),
+    IVec3::new(0, 1, 0),
+    IVec3::new(0, 0, -1),
+    IVec3::new(0, 0, 1),
+];
 
 #[derive(Component, Default)]
 pub struct RemoveChunk;
@@ -22,6 +47,8 @@ pub struct RemoveChunk;
 pub struct LoadableTypes {
     pub entities: HashMap<String, EntityType>,
     pub blocks: HashMap<String, BlockType>,
+    pub biomes: HashMap<String, BiomeDescriptor>,
+    pub structures: HashMap<String, StructureDescriptor>,
 }
 
 #[derive(Resource, Default)]
@@ -49,6 +76,16 @@ impl CurrentChunks {
             && self.chunks.contains_key(&(pos + IVec3::new(0, 0, 1)))
             && self.chunks.contains_key(&(pos + IVec3::new(0, 0, -1)))
     }
+
+    // Entities for all six neighbours in NEIGHBOR_OFFSETS order, if they are all loaded
+    pub fn get_neighbors(&self, pos: IVec3) -> Option<[Entity; 6]> {
+        let neighbors = NEIGHBOR_OFFSETS.map(|offset| self.get_entity(pos + offset));
+        if neighbors.iter().all(|neighbor| neighbor.is_some()) {
+            Some(neighbors.map(|neighbor| neighbor.unwrap()))
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Default, Resource)]
@@ -94,7 +131,7 @@ pub trait Chunk {
         (x as u32, y as u32, z as u32)
     }
 
-    fn get(&self, x: u32, y: u32, z: u32, loadable_types: &LoadableTypes) -> Self::Output;
+    fn get(&self, x: u32, y: u32, z: u32, registry: &BlockRegistry) -> Self::Output;
 }
 
 #[derive(Debug, PartialEq, EnumString, Default, Eq, Clone, Copy)]
@@ -178,53 +215,61 @@ impl VoxelType {
     }
 }
 
-#[derive(Clone, Hash, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "SerializedChunk")]
 pub struct RawChunk {
-    pub palette: Vec<String>, // The namespace string will also be semi-colon seperated with state data for blocks that need it
-    pub voxels: Box<Array<u16, TOTAL_CHUNK_USIZE>>,
-}
-
-#[derive(Clone, Hash, Debug, PartialEq, Eq, Component)]
-pub struct LightChunk {
-    pub voxels: [u8; TOTAL_CHUNK_USIZE],
-}
-
-impl LightChunk {
-    pub fn get_voxel(&self, x: u32, y: u32, z: u32) -> u8 {
-        let index = RawChunk::linearize(UVec3::new(x, y, z));
-        self.voxels[index]
-    }
-    pub fn calculate_light(
-        &mut self,
-        raw_chunk: &RawChunk,
-        _neighbors: [&RawChunk; 6],
-        loadable_types: &LoadableTypes,
-    ) {
-        for i in 0..raw_chunk.voxels.len() {
-            let (x, y, z) = RawChunk::delinearize(i);
-            if (x > 0 && x < CHUNK_BOUND)
-                && (y > 0 && y < CHUNK_BOUND)
-                && (z > 0 && z < CHUNK_BOUND)
-            {
-                if let Some(light_val) = raw_chunk.get_data(i, loadable_types) {
-                    let light_val = light_val.light_val;
-                    if light_val > 0 {
-                        for _l in 0..light_val {}
-                    }
-                }
-            }
+    palette: Vec<String>, // The namespace string will also be semi-colon seperated with state data for blocks that need it
+    voxels: VoxelStorage,
+    // Reverse of palette so we never have to scan it, rebuilt on deserialize
+    #[serde(skip)]
+    palette_lookup: HashMap<String, u16>,
+    // Registry id for each palette entry so voxel lookups don't hash strings. Only filled once every entry
+    // is in the registry since ids never change after that, cleared whenever the palette changes
+    #[serde(skip)]
+    block_ids: OnceLock<Vec<u16>>,
+}
+
+// The caches are left out, they only depend on the palette
+impl PartialEq for RawChunk {
+    fn eq(&self, other: &RawChunk) -> bool {
+        self.palette == other.palette && self.voxels == other.voxels
+    }
+}
+
+impl Eq for RawChunk {}
+
+// Wire/disk layout of a chunk. Has to stay in the same field order as RawChunk
+#[derive(Deserialize)]
+struct SerializedChunk {
+    palette: Vec<String>,
+    voxels: VoxelStorage,
+}
+
+impl TryFrom<SerializedChunk> for RawChunk {
+    type Error = StorageError;
+
+    fn try_from(chunk: SerializedChunk) -> Result<RawChunk, StorageError> {
+        if let Some(index) = chunk
+            .voxels
+            .iter()
+            .find(|index| *index as usize >= chunk.palette.len())
+        {
+            return Err(StorageError::MissingPaletteEntry(index));
         }
+        let mut raw_chunk = RawChunk {
+            palette: chunk.palette,
+            voxels: chunk.voxels,
+            palette_lookup: HashMap::new(),
+            block_ids: OnceLock::new(),
+        };
+        raw_chunk.rebuild_lookup();
+        Ok(raw_chunk)
     }
 }
 
 impl Default for RawChunk {
     fn default() -> RawChunk {
-        let mut raw_chunk = RawChunk {
-            palette: Vec::new(),
-            voxels: Box::default(),
-        };
-        raw_chunk.palette.push("air".to_string());
-        raw_chunk
+        RawChunk::new()
     }
 }
 
@@ -240,9 +285,9 @@ pub fn world_to_voxel(voxel_pos: Vec3) -> (IVec3, UVec3) {
     (
         world_to_chunk(voxel_pos),
         UVec3::new(
-            voxel_pos.x.rem_euclid(CHUNK_SIZE as f32).floor() as u32 + 1,
-            voxel_pos.y.rem_euclid(CHUNK_SIZE as f32).floor() as u32 + 1,
-            voxel_pos.z.rem_euclid(CHUNK_SIZE as f32).floor() as u32 + 1,
+            voxel_pos.x.rem_euclid(CHUNK_SIZE as f32).floor() as u32,
+            voxel_pos.y.rem_euclid(CHUNK_SIZE as f32).floor() as u32,
+            voxel_pos.z.rem_euclid(CHUNK_SIZE as f32).floor() as u32,
         ),
     )
 }
@@ -256,15 +301,168 @@ pub fn voxel_to_world(voxel_pos: UVec3, chunk_pos: IVec3) -> Vec3 {
     )
 }
 
+// Global voxel positions count whole voxels from the world origin, scripts use them so they never have
+// to think about chunks
+pub fn global_voxel_to_voxel(pos: IVec3) -> (IVec3, UVec3) {
+    let size = CHUNK_SIZE as i32;
+    (
+        IVec3::new(
+            pos.x.div_euclid(size),
+            pos.y.div_euclid(size),
+            pos.z.div_euclid(size),
+        ),
+        UVec3::new(
+            pos.x.rem_euclid(size) as u32,
+            pos.y.rem_euclid(size) as u32,
+            pos.z.rem_euclid(size) as u32,
+        ),
+    )
+}
+
+pub fn voxel_to_global_voxel(voxel_pos: UVec3, chunk_pos: IVec3) -> IVec3 {
+    chunk_pos * IVec3::splat(CHUNK_SIZE as i32) + voxel_pos.as_ivec3()
+}
+
 impl Chunk for RawChunk {
     type Output = VoxelType;
 
+    const X: usize = CHUNK_SIZE as usize;
+    const Y: usize = CHUNK_SIZE as usize;
+    const Z: usize = CHUNK_SIZE as usize;
+
+    fn get(&self, x: u32, y: u32, z: u32, registry: &BlockRegistry) -> Self::Output {
+        self.get_voxel(RawChunk::linearize(UVec3::new(x, y, z)), registry)
+    }
+}
+
+// Blocks that aren't loaded are treated like air. So are slabs, stairs and blocks with models, they get
+// meshed or spawned on their own so the cube mesher shouldn't cull anything against them
+fn voxel_type(palette_index: u16, block_id: u16, registry: &BlockRegistry) -> VoxelType {
+    match registry
+        .get(block_id)
+        .filter(|block_type| block_type.is_full_cube())
+        .map(|block_type| block_type.visibility)
+    {
+        None | Some(VoxelVisibility::Empty) => VoxelType::Empty(palette_index),
+        Some(VoxelVisibility::Opaque) => VoxelType::Opaque(palette_index),
+        Some(VoxelVisibility::Transparent) => VoxelType::Transparent(palette_index),
+    }
+}
+
+// Meshing only copy of a chunk with a one voxel border copied in from its six neighbours so faces on
+// chunk edges can be culled. The edges and corners of the border are always air.
+// Real voxels go from 1,1,1 to CHUNK_SIZE, the same as the old padded RawChunk
+#[derive(Clone, Debug)]
+pub struct PaddedChunkView {
+    palette: Vec<String>,
+    // Registry id for each palette entry so the mesher never has to hash strings
+    block_ids: Vec<u16>,
+    voxels: Box<[u16]>,
+}
+
+impl PaddedChunkView {
+    // Neighbours have to be in NEIGHBOR_OFFSETS order
+    pub fn new(
+        chunk: &RawChunk,
+        neighbors: [&RawChunk; 6],
+        registry: &BlockRegistry,
+    ) -> PaddedChunkView {
+        let mut view = PaddedChunkView {
+            palette: chunk.palette.clone(),
+            block_ids: Vec::new(),
+            voxels: vec![0; TOTAL_PADDED_CHUNK_USIZE].into_boxed_slice(),
+        };
+        let mut lookup = chunk.palette_lookup.clone();
+
+        for index in 0..RawChunk::size() {
+            let (x, y, z) = RawChunk::delinearize(index);
+            view.voxels[PaddedChunkView::linearize(UVec3::new(x + 1, y + 1, z + 1))] =
+                chunk.voxels.get(index);
+        }
+
+        for (offset, neighbor) in NEIGHBOR_OFFSETS.iter().zip(neighbors) {
+            // Neighbours have their own palettes so merge theirs into ours
+            let remap: Vec<u16> = neighbor
+                .palette
+                .iter()
+                .map(|state| {
+                    *lookup.entry(state.to_owned()).or_insert_with(|| {
+                        view.palette.push(state.to_owned());
+                        (view.palette.len() - 1) as u16
+                    })
+                })
+                .collect();
+
+            for a in 0..CHUNK_SIZE {
+                for b in 0..CHUNK_SIZE {
+                    let mut free = [a, b].into_iter();
+                    let mut source = UVec3::ZERO;
+                    let mut target = UVec3::ZERO;
+                    for axis in 0..3 {
+                        match offset[axis] {
+                            -1 => {
+                                source[axis] = CHUNK_EDGE;
+                                target[axis] = 0;
+                            }
+                            1 => {
+                                source[axis] = 0;
+                                target[axis] = CHUNK_BOUND;
+                            }
+                            _ => {
+                                let value = free.next().unwrap();
+                                source[axis] = value;
+                                target[axis] = value + 1;
+                            }
+                        }
+                    }
+                    view.voxels[PaddedChunkView::linearize(target)] =
+                        remap[neighbor.voxels.get(RawChunk::linearize(source)) as usize];
+                }
+            }
+        }
+        view.block_ids = view
+            .palette
+            .iter()
+            .map(|state| registry.id(state).unwrap_or(AIR_ID))
+            .collect();
+        view
+    }
+
+    pub fn palette(&self) -> &[String] {
+        &self.palette
+    }
+
+    // Registry id for a palette entry
+    pub fn block_id(&self, palette_index: u16) -> u16 {
+        self.block_ids[palette_index as usize]
+    }
+
+    pub fn palette_index(&self, pos: UVec3) -> u16 {
+        self.voxels[PaddedChunkView::linearize(pos)]
+    }
+
+    pub fn get_block(&self, pos: UVec3) -> Option<String> {
+        let index = PaddedChunkView::linearize(pos);
+        self.palette
+            .get(self.voxels[index] as usize)
+            .map(|state| state.to_owned())
+    }
+}
+
+impl Chunk for PaddedChunkView {
+    type Output = VoxelType;
+
     const X: usize = CHUNK_SIZE_PADDED as usize;
     const Y: usize = CHUNK_SIZE_PADDED as usize;
     const Z: usize = CHUNK_SIZE_PADDED as usize;
 
-    fn get(&self, x: u32, y: u32, z: u32, loadable_types: &LoadableTypes) -> Self::Output {
-        self.get_voxel(RawChunk::linearize(UVec3::new(x, y, z)), loadable_types)
+    fn get(&self, x: u32, y: u32, z: u32, registry: &BlockRegistry) -> Self::Output {
+        let palette_index = self.voxels[PaddedChunkView::linearize(UVec3::new(x, y, z))];
+        voxel_type(
+            palette_index,
+            self.block_ids[palette_index as usize],
+            registry,
+        )
     }
 }
 
@@ -273,98 +471,405 @@ impl RawChunk {
     pub fn new() -> RawChunk {
         let mut raw_chunk = RawChunk {
             palette: Vec::new(),
-            voxels: Box::default(),
+            voxels: VoxelStorage::new(TOTAL_CHUNK_USIZE),
+            palette_lookup: HashMap::new(),
+            block_ids: OnceLock::new(),
         };
-        raw_chunk.palette.push("air".to_string());
+        raw_chunk.add_block_state("air");
         raw_chunk
     }
 
-    pub fn get_voxel(&self, index: usize, loadable_types: &LoadableTypes) -> VoxelType {
-        let block_state = self
-            .get_state_for_index(self.voxels[index] as usize)
-            .unwrap();
-        let block_id = self.get_index_for_state(&block_state).unwrap() as u16;
-        if block_state.eq("air") {
-            VoxelType::Empty(0)
-        } else {
-            let voxel_visibility = loadable_types.blocks.get(&block_state).unwrap().visibility;
-            match voxel_visibility {
-                VoxelVisibility::Empty => VoxelType::Empty(block_id),
-                VoxelVisibility::Opaque => VoxelType::Opaque(block_id),
-                VoxelVisibility::Transparent => VoxelType::Transparent(block_id),
-            }
+    pub fn palette(&self) -> &[String] {
+        &self.palette
+    }
+
+    // True when every voxel is air, compacted or not
+    pub fn is_empty(&self) -> bool {
+        let is_air = |index: u16| self.palette[index as usize] == "air";
+        match self.voxels {
+            VoxelStorage::Single { value, .. } => is_air(value),
+            VoxelStorage::Packed { .. } => self.voxels.iter().all(is_air),
         }
     }
 
-    pub fn get_data(&self, index: usize, loadable_types: &LoadableTypes) -> Option<BlockType> {
-        let block_state = self
-            .get_state_for_index(self.voxels[index] as usize)
-            .unwrap();
-        let _block_id = self.get_index_for_state(&block_state).unwrap() as u16;
-        if block_state.eq("air") {
-            None
-        } else {
-            Some(loadable_types.blocks.get(&block_state).unwrap().clone())
+    // Registry id for a palette entry, blocks the registry doesn't know come back as air
+    pub fn block_id(&self, palette_index: u16, registry: &BlockRegistry) -> u16 {
+        if let Some(block_ids) = self.block_ids.get() {
+            return block_ids[palette_index as usize];
         }
+        let block_ids: Vec<Option<u16>> = self
+            .palette
+            .iter()
+            .map(|state| registry.id(state))
+            .collect();
+        let block_id = block_ids[palette_index as usize].unwrap_or(AIR_ID);
+        if let Some(block_ids) = block_ids.into_iter().collect::<Option<Vec<u16>>>() {
+            let _ = self.block_ids.set(block_ids);
+        }
+        block_id
+    }
+
+    pub fn get_voxel(&self, index: usize, registry: &BlockRegistry) -> VoxelType {
+        let palette_index = self.voxels.get(index);
+        voxel_type(
+            palette_index,
+            self.block_id(palette_index, registry),
+            registry,
+        )
     }
 
-    pub fn get_index_for_state(&self, block_data: &String) -> Option<usize> {
-        self.palette.iter().position(|i| i.eq(block_data))
+    pub fn get_data(&self, index: usize, registry: &BlockRegistry) -> Option<BlockType> {
+        registry
+            .get(self.block_id(self.voxels.get(index), registry))
+            .cloned()
+    }
+
+    // Palette index of the voxel at this linear index
+    pub fn palette_index(&self, index: usize) -> u16 {
+        self.voxels.get(index)
+    }
+
+    pub fn get_index_for_state(&self, block_data: &str) -> Option<usize> {
+        self.palette_lookup.get(block_data).map(|&id| id as usize)
     }
 
     pub fn get_state_for_index(&self, index: usize) -> Option<String> {
         self.palette.get(index).map(|state| state.to_owned())
     }
 
-    // This is most likely a VERY awful way to handle this however for now I just want a working solution ill
-    // rewrite this if it causes major performance issues
-    pub fn update_chunk_pal(&mut self, old_vec: &[String]) {
-        for i in 0..self.voxels.len() {
-            if let Some(block_data) = old_vec.get(self.voxels[i] as usize) {
-                if let Some(new_index) = self.get_index_for_state(block_data) {
-                    self.voxels[i] = new_index as u16;
-                } else {
-                    self.voxels[i] = 0;
-                }
-            }
-        }
+    fn rebuild_lookup(&mut self) {
+        self.block_ids = OnceLock::new();
+        self.palette_lookup = self
+            .palette
+            .iter()
+            .enumerate()
+            .map(|(id, state)| (state.to_owned(), id as u16))
+            .collect();
     }
 
-    pub fn add_block_state(&mut self, block_data: &String) {
-        let old_vec = self.palette.clone();
-        if let Some(_id) = self.get_index_for_state(block_data) {
-        } else {
+    // Appending never touches the voxels, old ids stay valid. Call compact to get rid of unused states
+    pub fn add_block_state(&mut self, block_data: &str) {
+        if !self.palette_lookup.contains_key(block_data) {
+            self.palette_lookup
+                .insert(block_data.to_owned(), self.palette.len() as u16);
             self.palette.push(block_data.to_owned());
-            self.update_chunk_pal(&old_vec);
+            self.block_ids = OnceLock::new();
         }
     }
-    pub fn remove_block_state(&mut self, block_data: &String) {
-        if block_data.eq(&"air".to_string()) {
+
+    // Any voxels using this state are turned into air
+    pub fn remove_block_state(&mut self, block_data: &str) {
+        if block_data.eq("air") {
             return;
         }
-        let old_vec = self.palette.clone();
         if let Some(id) = self.get_index_for_state(block_data) {
-            self.palette.remove(id);
-            self.update_chunk_pal(&old_vec);
+            let mut remap: Vec<u16> = (0..self.palette.len() as u16).collect();
+            remap[id] = 0;
+            self.voxels.remap(&remap);
+            self.compact();
         } else {
             warn!("Block data: {}, doesn't exist!", block_data);
         }
     }
-    // This actual chunks data starts at 1,1,1 and ends at chunk_size
+
+    // Drops every palette entry no voxel points at and renumbers the rest in one pass. Air always stays at 0.
+    // Also shrinks the voxel storage, down to a single value if the whole chunk is one block
+    pub fn compact(&mut self) {
+        let mut used = vec![false; self.palette.len()];
+        used[0] = true;
+        for voxel in self.voxels.iter() {
+            used[voxel as usize] = true;
+        }
+        let mut remap = vec![0; self.palette.len()];
+        let mut new_palette = Vec::new();
+        for (old_id, state) in self.palette.drain(..).enumerate() {
+            if used[old_id] {
+                remap[old_id] = new_palette.len() as u16;
+                new_palette.push(state);
+            }
+        }
+        self.voxels.remap(&remap);
+        self.palette = new_palette;
+        self.rebuild_lookup();
+    }
+
+    // Run on chunks loaded from disk. Renames entries saved before namespaces had a separator and turns
+    // blocks the registry has never heard of into air. Returns true if anything changed
+    pub fn remap_blocks(&mut self, registry: &BlockRegistry) -> bool {
+        let mut changed = false;
+        let mut remap: Vec<u16> = (0..self.palette.len() as u16).collect();
+        let mut seen: HashMap<String, u16> = HashMap::new();
+        for (index, target) in remap.iter_mut().enumerate() {
+            let state = &self.palette[index];
+            let block_id = BlockState::block_id_of(state);
+            let renamed = if registry.id(block_id).is_some() {
+                state.to_owned()
+            } else if let Some(identifier) = registry.legacy_identifier(block_id) {
+                format!("{identifier}{}", &state[block_id.len()..])
+            } else {
+                warn!("Unknown block {block_id} in chunk, replacing it with air");
+                "air".to_string()
+            };
+            if renamed != *state {
+                changed = true;
+            }
+            // Renaming can end up with the same entry twice so point both at the first one
+            if let Some(&existing) = seen.get(&renamed) {
+                *target = existing;
+            } else {
+                seen.insert(renamed.clone(), index as u16);
+                self.palette[index] = renamed;
+            }
+        }
+        if changed {
+            self.voxels.remap(&remap);
+            self.compact();
+        }
+        changed
+    }
+
+    // Positions go from 0,0,0 to CHUNK_EDGE, borders for meshing live in PaddedChunkView
     pub fn set_block(&mut self, pos: UVec3, block_data: String) {
         let index = RawChunk::linearize(pos);
         if let Some(block_type) = self.get_index_for_state(&block_data) {
-            if block_type == 0 {
-                self.voxels[index] = 0;
-            } else {
-                self.voxels[index] = block_type as u16; // Set based off of transluency
-            }
+            self.voxels.set(index, block_type as u16);
         } else {
             warn!("Voxel doesn't exist");
         }
     }
-    pub fn get_block(&mut self, pos: UVec3) -> Option<String> {
+    pub fn get_block(&self, pos: UVec3) -> Option<String> {
         let index = RawChunk::linearize(pos);
-        self.get_state_for_index(self.voxels[index] as usize)
+        self.get_state_for_index(self.voxels.get(index) as usize)
+    }
+
+    // None for blocks that aren't loaded or states that don't fit their block
+    pub fn get_block_state(
+        &self,
+        pos: UVec3,
+        blocks: &HashMap<String, BlockType>,
+    ) -> Option<BlockState> {
+        let index = RawChunk::linearize(pos);
+        self.palette
+            .get(self.voxels.get(index) as usize)
+            .and_then(|state| BlockState::parse_validated(state, blocks).ok())
+    }
+
+    // Adds the state to the palette if it isn't there yet
+    pub fn set_block_state(&mut self, pos: UVec3, block_state: &BlockState) {
+        let state = block_state.to_string();
+        self.add_block_state(&state);
+        self.set_block(pos, state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bevy::prelude::*;
+    use serde::Serialize;
+
+    use super::{RawChunk, VoxelVisibility, TOTAL_CHUNK_USIZE};
+    use crate::game::{storage::BlockType, world::registry::BlockRegistry};
+
+    // Every palette entry has to be found again at its own index
+    fn assert_lookup(chunk: &RawChunk) {
+        for (index, state) in chunk.palette().iter().enumerate() {
+            assert_eq!(chunk.get_index_for_state(state), Some(index));
+        }
+    }
+
+    #[test]
+    fn lookup_survives_compact() {
+        let mut chunk = RawChunk::new();
+        for block in ["vinox:stone", "vinox:dirt", "vinox:grass"] {
+            chunk.add_block_state(block);
+        }
+        chunk.set_block(UVec3::new(1, 2, 3), "vinox:grass".to_string());
+        chunk.set_block(UVec3::new(4, 5, 6), "vinox:stone".to_string());
+        // Adding one that's already there doesn't grow the palette
+        chunk.add_block_state("vinox:stone");
+        assert_eq!(chunk.palette().len(), 4);
+        assert_lookup(&chunk);
+
+        chunk.compact();
+        assert_eq!(chunk.palette(), ["air", "vinox:stone", "vinox:grass"]);
+        assert_eq!(chunk.get_index_for_state("vinox:dirt"), None);
+        assert_lookup(&chunk);
+        assert_eq!(
+            chunk.get_block(UVec3::new(1, 2, 3)).as_deref(),
+            Some("vinox:grass")
+        );
+
+        chunk.remove_block_state("vinox:stone");
+        assert_eq!(chunk.palette(), ["air", "vinox:grass"]);
+        assert_lookup(&chunk);
+        assert_eq!(chunk.get_block(UVec3::new(4, 5, 6)).as_deref(), Some("air"));
+        assert_eq!(
+            chunk.get_block(UVec3::new(1, 2, 3)).as_deref(),
+            Some("vinox:grass")
+        );
+    }
+
+    #[test]
+    fn empty_without_compacting() {
+        let mut chunk = RawChunk::new();
+        assert!(chunk.is_empty());
+        chunk.add_block_state("vinox:stone");
+        // Unused palette entries don't count
+        assert!(chunk.is_empty());
+        chunk.set_block(UVec3::new(7, 8, 9), "vinox:stone".to_string());
+        assert!(!chunk.is_empty());
+        chunk.set_block(UVec3::new(7, 8, 9), "air".to_string());
+        assert!(chunk.is_empty());
+    }
+
+    #[test]
+    fn block_ids_follow_the_palette() {
+        let registry = BlockRegistry::from_blocks(&HashMap::from(["stone", "dirt"].map(|name| {
+            (
+                format!("vinox:{name}"),
+                BlockType::test(name, VoxelVisibility::Opaque, 0),
+            )
+        })));
+        let stone = registry.id("vinox:stone").unwrap();
+        let dirt = registry.id("vinox:dirt").unwrap();
+
+        let mut chunk = RawChunk::new();
+        chunk.add_block_state("vinox:stone");
+        assert_eq!(chunk.block_id(1, &registry), stone);
+        // A block the registry doesn't have is air and keeps the ids from being cached
+        chunk.add_block_state("vinox:missing");
+        assert_eq!(chunk.block_id(2, &registry), 0);
+        assert!(chunk.block_ids.get().is_none());
+
+        // Compacting renumbers the palette so the old ids can't stick around
+        chunk.add_block_state("vinox:dirt");
+        chunk.set_block(UVec3::ZERO, "vinox:dirt".to_string());
+        assert_eq!(chunk.block_id(3, &registry), dirt);
+        chunk.compact();
+        assert_eq!(chunk.block_id(1, &registry), dirt);
+        assert_eq!(chunk.block_ids.get(), Some(&vec![0, dirt]));
+        chunk.add_block_state("vinox:stone");
+        assert_eq!(chunk.block_id(2, &registry), stone);
+    }
+
+    #[test]
+    fn lookup_rebuilt_on_deserialize() {
+        let mut chunk = RawChunk::new();
+        chunk.add_block_state("vinox:stone");
+        chunk.add_block_state("vinox:dirt");
+        chunk.set_block(UVec3::new(0, 31, 0), "vinox:dirt".to_string());
+
+        let loaded: RawChunk = ron::from_str(&ron::to_string(&chunk).unwrap()).unwrap();
+        assert_eq!(loaded, chunk);
+        assert_lookup(&loaded);
+        assert_eq!(loaded.get_index_for_state("vinox:stone"), Some(1));
+        // Blocks added after loading don't clash with the saved ones
+        let mut loaded = loaded;
+        loaded.add_block_state("vinox:grass");
+        assert_eq!(loaded.get_index_for_state("vinox:grass"), Some(3));
+        loaded.set_block(UVec3::new(0, 0, 0), "vinox:grass".to_string());
+        assert_eq!(
+            loaded.get_block(UVec3::new(0, 31, 0)).as_deref(),
+            Some("vinox:dirt")
+        );
+        assert_eq!(
+            loaded.get_block(UVec3::new(0, 0, 0)).as_deref(),
+            Some("vinox:grass")
+        );
+    }
+
+    // Same layout as a saved chunk but nothing stops these from being broken
+    #[derive(Serialize)]
+    enum Storage {
+        Single {
+            len: usize,
+            value: u16,
+        },
+        Packed {
+            len: usize,
+            bits: u8,
+            words: Vec<u64>,
+        },
+    }
+
+    #[derive(Serialize)]
+    struct Saved {
+        palette: Vec<String>,
+        voxels: Storage,
+    }
+
+    fn load(palette: &[&str], voxels: Storage) -> Result<RawChunk, bincode::Error> {
+        let saved = Saved {
+            palette: palette.iter().map(|state| state.to_string()).collect(),
+            voxels,
+        };
+        bincode::deserialize(&bincode::serialize(&saved).unwrap())
+    }
+
+    #[test]
+    fn rejects_bad_chunks() {
+        let len = TOTAL_CHUNK_USIZE;
+        let palette = ["air", "vinox:stone"];
+        // 4 bits per voxel packs 16 into a word
+        let words = vec![0x1111; len / 16];
+        assert!(load(&palette, Storage::Single { len, value: 1 }).is_ok());
+        assert!(load(
+            &palette,
+            Storage::Packed {
+                len,
+                bits: 4,
+                words: words.clone()
+            }
+        )
+        .is_ok());
+
+        let bad = [
+            Storage::Packed {
+                len,
+                bits: 0,
+                words: Vec::new(),
+            },
+            Storage::Packed {
+                len,
+                bits: 3,
+                words: vec![0; len / 21 + 1],
+            },
+            Storage::Packed {
+                len,
+                bits: 64,
+                words: vec![0; len],
+            },
+            Storage::Packed {
+                len,
+                bits: 4,
+                words: words[1..].to_vec(),
+            },
+            Storage::Packed {
+                len: len + 1,
+                bits: 4,
+                words: words.clone(),
+            },
+            Storage::Single {
+                len: 34 * 34 * 34,
+                value: 0,
+            },
+            // Past the end of the palette
+            Storage::Single { len, value: 2 },
+            Storage::Packed {
+                len,
+                bits: 4,
+                words: vec![0x2222; len / 16],
+            },
+        ];
+        for voxels in bad {
+            assert!(load(&palette, voxels).is_err());
+        }
+        assert!(load(&[], Storage::Single { len, value: 0 }).is_err());
+
+        // Cut short on the way in
+        let chunk = bincode::serialize(&RawChunk::new()).unwrap();
+        assert!(bincode::deserialize::<RawChunk>(&chunk[..chunk.len() - 1]).is_err());
     }
 }
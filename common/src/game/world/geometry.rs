@@ -0,0 +1,81 @@
+use bevy::prelude::*;
+
+use super::{block_state::BlockState, chunk::GeometryType};
+
+// An axis aligned box inside a voxel, every axis goes from 0.0 to 1.0
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cuboid {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Cuboid {
+    pub const FULL: Cuboid = Cuboid {
+        min: Vec3::ZERO,
+        max: Vec3::ONE,
+    };
+
+    pub fn new(min: Vec3, max: Vec3) -> Cuboid {
+        Cuboid { min, max }
+    }
+
+    pub fn intersect(&self, other: &Cuboid) -> Cuboid {
+        Cuboid {
+            min: self.min.max(other.min),
+            max: self.max.min(other.max),
+        }
+    }
+
+    // Where the face on one side of the box sits along its axis
+    pub fn plane(&self, axis: usize, positive: bool) -> f32 {
+        if positive {
+            self.max[axis]
+        } else {
+            self.min[axis]
+        }
+    }
+
+    // True if this box has a face on the given plane that covers all of other's face on the two axes
+    // the face lies in
+    pub fn covers(&self, axis: usize, positive: bool, plane: f32, other: &Cuboid) -> bool {
+        self.plane(axis, positive) == plane
+            && (0..3).filter(|tangent| *tangent != axis).all(|tangent| {
+                self.min[tangent] <= other.min[tangent] && self.max[tangent] >= other.max[tangent]
+            })
+    }
+}
+
+// Slabs and stairs read "half" from their state, either "bottom" (the default) or "top". Vertical slabs
+// and stairs read "facing", one of "north" (the default), "east", "south" or "west". A vertical slab fills
+// the half it faces and stairs rise toward the way they face
+impl GeometryType {
+    pub fn is_full(&self) -> bool {
+        *self == GeometryType::Block
+    }
+
+    pub fn cuboids(&self, block_state: &BlockState) -> Vec<Cuboid> {
+        let vertical_half = match block_state.get_enum("half") {
+            Some("top") => Cuboid::new(Vec3::new(0.0, 0.5, 0.0), Vec3::ONE),
+            _ => Cuboid::new(Vec3::ZERO, Vec3::new(1.0, 0.5, 1.0)),
+        };
+        let facing_half = match block_state.get_enum("facing") {
+            Some("east") => Cuboid::new(Vec3::new(0.5, 0.0, 0.0), Vec3::ONE),
+            Some("south") => Cuboid::new(Vec3::new(0.0, 0.0, 0.5), Vec3::ONE),
+            Some("west") => Cuboid::new(Vec3::ZERO, Vec3::new(0.5, 1.0, 1.0)),
+            _ => Cuboid::new(Vec3::ZERO, Vec3::new(1.0, 1.0, 0.5)),
+        };
+        match self {
+            GeometryType::Block => vec![Cuboid::FULL],
+            GeometryType::HorizontalSlab => vec![vertical_half],
+            GeometryType::VerticalSlab => vec![facing_half],
+            GeometryType::Stairs => {
+                // The step sits in whichever half the slab doesn't
+                let step_half = match block_state.get_enum("half") {
+                    Some("top") => Cuboid::new(Vec3::ZERO, Vec3::new(1.0, 0.5, 1.0)),
+                    _ => Cuboid::new(Vec3::new(0.0, 0.5, 0.0), Vec3::ONE),
+                };
+                vec![vertical_half, facing_half.intersect(&step_half)]
+            }
+        }
+    }
+}
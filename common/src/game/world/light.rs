@@ -0,0 +1,538 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+
+use super::{
+    chunk::{
+        Chunk, PaddedChunkView, RawChunk, VoxelVisibility, CHUNK_EDGE, CHUNK_SIZE,
+        NEIGHBOR_OFFSETS, TOTAL_CHUNK_USIZE, TOTAL_PADDED_CHUNK_USIZE,
+    },
+    registry::BlockRegistry,
+};
+
+pub const MAX_LIGHT: u8 = 15;
+
+// Index of the chunk above in NEIGHBOR_OFFSETS
+const ABOVE: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LightChannel {
+    Block,
+    Sky,
+}
+
+impl LightChannel {
+    fn shift(self) -> u8 {
+        match self {
+            LightChannel::Block => 0,
+            LightChannel::Sky => 4,
+        }
+    }
+}
+
+// What lighting needs to know about each palette entry of a chunk, worked out once instead of per voxel
+struct LightBlocks<'a> {
+    chunk: &'a RawChunk,
+    opaque: Vec<bool>,
+    emission: Vec<u8>,
+}
+
+impl<'a> LightBlocks<'a> {
+    fn new(chunk: &'a RawChunk, registry: &BlockRegistry) -> LightBlocks<'a> {
+        let block_types: Vec<_> = (0..chunk.palette().len())
+            .map(|palette_index| registry.get(chunk.block_id(palette_index as u16, registry)))
+            .collect();
+        LightBlocks {
+            chunk,
+            opaque: block_types
+                .iter()
+                .map(|block_type| match block_type {
+                    // Light gets through the open part of slabs, stairs and models
+                    Some(block_type) => {
+                        block_type.visibility == VoxelVisibility::Opaque
+                            && block_type.is_full_cube()
+                    }
+                    None => false,
+                })
+                .collect(),
+            emission: block_types
+                .iter()
+                .map(|block_type| {
+                    block_type.map_or(0, |block_type| block_type.light_val.min(MAX_LIGHT))
+                })
+                .collect(),
+        }
+    }
+
+    fn opaque(&self, index: usize) -> bool {
+        self.opaque[self.chunk.palette_index(index) as usize]
+    }
+
+    fn emission(&self, index: usize) -> u8 {
+        self.emission[self.chunk.palette_index(index) as usize]
+    }
+}
+
+// Positions on one face of a chunk paired with the touching position in the neighbour on that side
+fn face_positions(side: usize) -> impl Iterator<Item = (UVec3, UVec3)> {
+    let offset = NEIGHBOR_OFFSETS[side];
+    (0..CHUNK_SIZE * CHUNK_SIZE).map(move |i| {
+        let mut free = [i % CHUNK_SIZE, i / CHUNK_SIZE].into_iter();
+        let mut local = UVec3::ZERO;
+        let mut neighbor = UVec3::ZERO;
+        for axis in 0..3 {
+            match offset[axis] {
+                -1 => {
+                    local[axis] = 0;
+                    neighbor[axis] = CHUNK_EDGE;
+                }
+                1 => {
+                    local[axis] = CHUNK_EDGE;
+                    neighbor[axis] = 0;
+                }
+                _ => {
+                    let value = free.next().unwrap();
+                    local[axis] = value;
+                    neighbor[axis] = value;
+                }
+            }
+        }
+        (local, neighbor)
+    })
+}
+
+fn offset_index(index: usize, offset: IVec3) -> Option<usize> {
+    let (x, y, z) = RawChunk::delinearize(index);
+    let pos = IVec3::new(x as i32, y as i32, z as i32) + offset;
+    if pos.cmplt(IVec3::ZERO).any() || pos.cmpgt(IVec3::splat(CHUNK_EDGE as i32)).any() {
+        None
+    } else {
+        Some(RawChunk::linearize(pos.as_uvec3()))
+    }
+}
+
+// Sky light is the high 4 bits and block light the low 4 bits of each voxel. Light crosses chunk borders
+// by reading the neighbours border, so whenever a face changes the neighbour on that side needs relighting
+#[derive(Clone, Hash, Debug, PartialEq, Eq, Component)]
+pub struct LightChunk {
+    pub voxels: Box<[u8]>,
+}
+
+impl Default for LightChunk {
+    fn default() -> LightChunk {
+        LightChunk {
+            voxels: vec![0; TOTAL_CHUNK_USIZE].into_boxed_slice(),
+        }
+    }
+}
+
+impl LightChunk {
+    pub fn get_voxel(&self, x: u32, y: u32, z: u32) -> u8 {
+        let index = RawChunk::linearize(UVec3::new(x, y, z));
+        self.voxels[index]
+    }
+
+    pub fn get_light(&self, pos: UVec3, channel: LightChannel) -> u8 {
+        self.light_at(RawChunk::linearize(pos), channel)
+    }
+
+    pub fn block_light(&self, pos: UVec3) -> u8 {
+        self.get_light(pos, LightChannel::Block)
+    }
+
+    pub fn sky_light(&self, pos: UVec3) -> u8 {
+        self.get_light(pos, LightChannel::Sky)
+    }
+
+    fn light_at(&self, index: usize, channel: LightChannel) -> u8 {
+        (self.voxels[index] >> channel.shift()) & MAX_LIGHT
+    }
+
+    fn set_light(&mut self, index: usize, channel: LightChannel, light: u8) {
+        let shift = channel.shift();
+        self.voxels[index] = (self.voxels[index] & !(MAX_LIGHT << shift)) | (light << shift);
+    }
+
+    fn faces(&self) -> [Vec<u8>; 6] {
+        [0, 1, 2, 3, 4, 5].map(|side| {
+            face_positions(side)
+                .map(|(local, _)| self.voxels[RawChunk::linearize(local)])
+                .collect()
+        })
+    }
+
+    fn changed_faces(&self, before: &[Vec<u8>; 6]) -> [bool; 6] {
+        let after = self.faces();
+        [0, 1, 2, 3, 4, 5].map(|side| before[side] != after[side])
+    }
+
+    // Lights the whole chunk from scratch. Neighbours are in NEIGHBOR_OFFSETS order, a missing chunk above
+    // counts as open sky. Gives back which faces changed
+    pub fn calculate_light(
+        &mut self,
+        raw_chunk: &RawChunk,
+        neighbors: [Option<&LightChunk>; 6],
+        registry: &BlockRegistry,
+    ) -> [bool; 6] {
+        let before = self.faces();
+        let blocks = LightBlocks::new(raw_chunk, registry);
+        self.voxels.fill(0);
+
+        let mut queue = VecDeque::new();
+        for (index, light) in (0..RawChunk::size()).map(|index| (index, blocks.emission(index))) {
+            if light > 0 {
+                self.set_light(index, LightChannel::Block, light);
+                queue.push_back(index);
+            }
+        }
+        self.seed_borders(&blocks, &neighbors, LightChannel::Block, &mut queue);
+        self.spread(&blocks, LightChannel::Block, &mut queue);
+
+        for x in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                self.seed_sky_column(&blocks, &neighbors, x, CHUNK_EDGE, z, &mut queue);
+            }
+        }
+        self.seed_borders(&blocks, &neighbors, LightChannel::Sky, &mut queue);
+        self.spread(&blocks, LightChannel::Sky, &mut queue);
+
+        self.changed_faces(&before)
+    }
+
+    // Relights after a single block was placed or removed. Light the old block was giving off is taken away
+    // first, then everything bordering the dark area floods back in
+    pub fn update_block(
+        &mut self,
+        pos: UVec3,
+        raw_chunk: &RawChunk,
+        neighbors: [Option<&LightChunk>; 6],
+        registry: &BlockRegistry,
+    ) -> [bool; 6] {
+        let before = self.faces();
+        let blocks = LightBlocks::new(raw_chunk, registry);
+        let start = RawChunk::linearize(pos);
+
+        for channel in [LightChannel::Block, LightChannel::Sky] {
+            let mut remove = VecDeque::from([(start, self.light_at(start, channel))]);
+            let mut removed = vec![start];
+            let mut queue = VecDeque::new();
+            self.set_light(start, channel, 0);
+
+            while let Some((index, light)) = remove.pop_front() {
+                for offset in NEIGHBOR_OFFSETS {
+                    let Some(neighbor) = offset_index(index, offset) else {
+                        continue;
+                    };
+                    let neighbor_light = self.light_at(neighbor, channel);
+                    if neighbor_light == 0 {
+                        continue;
+                    }
+                    let sunlight_below = channel == LightChannel::Sky
+                        && light == MAX_LIGHT
+                        && offset == IVec3::NEG_Y;
+                    if neighbor_light < light || sunlight_below {
+                        self.set_light(neighbor, channel, 0);
+                        remove.push_back((neighbor, neighbor_light));
+                        removed.push(neighbor);
+                    } else {
+                        queue.push_back(neighbor);
+                    }
+                }
+            }
+
+            for index in removed {
+                if blocks.opaque(index) && blocks.emission(index) == 0 {
+                    continue;
+                }
+                match channel {
+                    LightChannel::Block => {
+                        let light = blocks.emission(index);
+                        if light > 0 {
+                            self.set_light(index, channel, light);
+                            queue.push_back(index);
+                        }
+                    }
+                    LightChannel::Sky => {
+                        let (x, y, z) = RawChunk::delinearize(index);
+                        if y == CHUNK_EDGE {
+                            self.seed_sky_column(&blocks, &neighbors, x, y, z, &mut queue);
+                        }
+                    }
+                }
+            }
+            self.seed_borders(&blocks, &neighbors, channel, &mut queue);
+            self.spread(&blocks, channel, &mut queue);
+        }
+
+        self.changed_faces(&before)
+    }
+
+    // Full sunlight from above goes straight down until it hits something opaque
+    fn seed_sky_column(
+        &mut self,
+        blocks: &LightBlocks,
+        neighbors: &[Option<&LightChunk>; 6],
+        x: u32,
+        top: u32,
+        z: u32,
+        queue: &mut VecDeque<usize>,
+    ) {
+        let from_above =
+            neighbors[ABOVE].map_or(MAX_LIGHT, |above| above.sky_light(UVec3::new(x, 0, z)));
+        if from_above < MAX_LIGHT {
+            return;
+        }
+        for y in (0..=top).rev() {
+            let index = RawChunk::linearize(UVec3::new(x, y, z));
+            if blocks.opaque(index) {
+                break;
+            }
+            self.set_light(index, LightChannel::Sky, MAX_LIGHT);
+            queue.push_back(index);
+        }
+    }
+
+    fn seed_borders(
+        &mut self,
+        blocks: &LightBlocks,
+        neighbors: &[Option<&LightChunk>; 6],
+        channel: LightChannel,
+        queue: &mut VecDeque<usize>,
+    ) {
+        for (side, neighbor) in neighbors.iter().enumerate() {
+            let Some(neighbor) = neighbor else {
+                continue;
+            };
+            for (local, other) in face_positions(side) {
+                let index = RawChunk::linearize(local);
+                let light = neighbor.get_light(other, channel).saturating_sub(1);
+                if light > self.light_at(index, channel) && !blocks.opaque(index) {
+                    self.set_light(index, channel, light);
+                    queue.push_back(index);
+                }
+            }
+        }
+    }
+
+    fn spread(&mut self, blocks: &LightBlocks, channel: LightChannel, queue: &mut VecDeque<usize>) {
+        while let Some(index) = queue.pop_front() {
+            let light = self.light_at(index, channel);
+            if light <= 1 {
+                continue;
+            }
+            for offset in NEIGHBOR_OFFSETS {
+                let Some(neighbor) = offset_index(index, offset) else {
+                    continue;
+                };
+                if blocks.opaque(neighbor) {
+                    continue;
+                }
+                let new_light =
+                    if channel == LightChannel::Sky && light == MAX_LIGHT && offset == IVec3::NEG_Y
+                    {
+                        MAX_LIGHT
+                    } else {
+                        light - 1
+                    };
+                if new_light > self.light_at(neighbor, channel) {
+                    self.set_light(neighbor, channel, new_light);
+                    queue.push_back(neighbor);
+                }
+            }
+        }
+    }
+}
+
+// Light for a chunk plus a one voxel border from its neighbours, laid out the same as PaddedChunkView so the
+// mesher can sample light on the far side of a face. Missing neighbours and the border edges and corners
+// just repeat the closest voxel of the chunk, except a missing chunk above which is open sky
+#[derive(Clone, Debug)]
+pub struct PaddedLightView {
+    voxels: Box<[u8]>,
+}
+
+impl PaddedLightView {
+    // Neighbours have to be in NEIGHBOR_OFFSETS order
+    pub fn new(light: &LightChunk, neighbors: [Option<&LightChunk>; 6]) -> PaddedLightView {
+        let mut voxels = vec![0; TOTAL_PADDED_CHUNK_USIZE].into_boxed_slice();
+        for (index, voxel) in voxels.iter_mut().enumerate() {
+            let (x, y, z) = PaddedChunkView::delinearize(index);
+            let pos = IVec3::new(x as i32, y as i32, z as i32) - IVec3::ONE;
+            let clamped = pos.clamp(IVec3::ZERO, IVec3::splat(CHUNK_EDGE as i32));
+            let own = light.voxels[RawChunk::linearize(clamped.as_uvec3())];
+            let offset = pos - clamped;
+            *voxel = match NEIGHBOR_OFFSETS.iter().position(|side| *side == offset) {
+                Some(side) => match neighbors[side] {
+                    Some(neighbor) => {
+                        let wrapped = pos - offset * CHUNK_SIZE as i32;
+                        neighbor.voxels[RawChunk::linearize(wrapped.as_uvec3())]
+                    }
+                    None if side == ABOVE => {
+                        (MAX_LIGHT << LightChannel::Sky.shift()) | (own & MAX_LIGHT)
+                    }
+                    None => own,
+                },
+                None => own,
+            };
+        }
+        PaddedLightView { voxels }
+    }
+
+    pub fn get_light(&self, pos: UVec3, channel: LightChannel) -> u8 {
+        (self.voxels[PaddedChunkView::linearize(pos)] >> channel.shift()) & MAX_LIGHT
+    }
+
+    pub fn block_light(&self, pos: UVec3) -> u8 {
+        self.get_light(pos, LightChannel::Block)
+    }
+
+    pub fn sky_light(&self, pos: UVec3) -> u8 {
+        self.get_light(pos, LightChannel::Sky)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bevy::prelude::*;
+
+    use crate::game::{
+        storage::BlockType,
+        world::{
+            chunk::{RawChunk, VoxelVisibility, CHUNK_EDGE, CHUNK_SIZE},
+            registry::BlockRegistry,
+        },
+    };
+
+    use super::{LightChunk, MAX_LIGHT};
+
+    fn registry() -> BlockRegistry {
+        let blocks = [
+            ("stone", VoxelVisibility::Opaque, 0),
+            ("torch", VoxelVisibility::Transparent, 14),
+        ]
+        .map(|(name, visibility, light_val)| {
+            (
+                format!("vinox:{name}"),
+                BlockType::test(name, visibility, light_val),
+            )
+        });
+        BlockRegistry::from_blocks(&HashMap::from(blocks))
+    }
+
+    fn place(chunk: &mut RawChunk, pos: UVec3, block: &str) {
+        chunk.add_block_state(block);
+        chunk.set_block(pos, block.to_string());
+    }
+
+    // A chunk with a stone layer at y 20 so everything under it only gets light from torches
+    fn cave() -> RawChunk {
+        let mut chunk = RawChunk::new();
+        for x in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                place(&mut chunk, UVec3::new(x, 20, z), "vinox:stone");
+            }
+        }
+        chunk
+    }
+
+    #[test]
+    fn torch_falls_off() {
+        let registry = registry();
+        let mut chunk = cave();
+        place(&mut chunk, UVec3::new(10, 10, 10), "vinox:torch");
+        let mut light = LightChunk::default();
+        light.calculate_light(&chunk, [None; 6], &registry);
+        for distance in 0..14 {
+            assert_eq!(
+                light.block_light(UVec3::new(10 + distance, 10, 10)),
+                14 - distance as u8
+            );
+        }
+        // Distance is counted in steps, not a straight line
+        assert_eq!(light.block_light(UVec3::new(12, 12, 10)), 10);
+        assert_eq!(light.block_light(UVec3::new(10, 25, 10)), 0);
+    }
+
+    #[test]
+    fn sky_stops_at_opaque() {
+        let registry = registry();
+        let chunk = cave();
+        let mut light = LightChunk::default();
+        light.calculate_light(&chunk, [None; 6], &registry);
+        // Sunlight doesn't fade going straight down
+        assert_eq!(light.sky_light(UVec3::new(5, CHUNK_EDGE, 5)), MAX_LIGHT);
+        assert_eq!(light.sky_light(UVec3::new(5, 21, 5)), MAX_LIGHT);
+        assert_eq!(light.sky_light(UVec3::new(5, 20, 5)), 0);
+        assert_eq!(light.sky_light(UVec3::new(5, 0, 5)), 0);
+    }
+
+    #[test]
+    fn crosses_chunk_borders() {
+        let registry = registry();
+        // A buried chunk above so the sky doesn't drown out the torch
+        let mut buried = RawChunk::new();
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    place(&mut buried, UVec3::new(x, y, z), "vinox:stone");
+                }
+            }
+        }
+        let mut roof = LightChunk::default();
+        roof.calculate_light(&buried, [None; 6], &registry);
+
+        let mut chunk = RawChunk::new();
+        place(&mut chunk, UVec3::new(CHUNK_EDGE, 5, 5), "vinox:torch");
+        let mut light = LightChunk::default();
+        let changed = light.calculate_light(
+            &chunk,
+            [None, None, None, Some(&roof), None, None],
+            &registry,
+        );
+        // The +x face picked up light so that neighbour needs relighting, the far side didn't
+        assert!(changed[1]);
+        assert!(!changed[0]);
+
+        let mut neighbor = LightChunk::default();
+        neighbor.calculate_light(
+            &RawChunk::new(),
+            [Some(&light), None, None, Some(&roof), None, None],
+            &registry,
+        );
+        assert_eq!(neighbor.block_light(UVec3::new(0, 5, 5)), 13);
+        assert_eq!(neighbor.block_light(UVec3::new(3, 5, 5)), 10);
+        assert_eq!(neighbor.sky_light(UVec3::new(3, 5, 5)), 0);
+    }
+
+    #[test]
+    fn update_removes_light() {
+        let registry = registry();
+        let mut chunk = cave();
+        place(&mut chunk, UVec3::new(10, 10, 10), "vinox:torch");
+        let mut light = LightChunk::default();
+        light.calculate_light(&chunk, [None; 6], &registry);
+
+        // Breaking the torch takes its light with it
+        chunk.set_block(UVec3::new(10, 10, 10), "air".to_string());
+        light.update_block(UVec3::new(10, 10, 10), &chunk, [None; 6], &registry);
+        assert_eq!(light.block_light(UVec3::new(10, 10, 10)), 0);
+        assert_eq!(light.block_light(UVec3::new(12, 10, 10)), 0);
+
+        // Digging through the roof lets the sky in and filling it back takes it away again
+        chunk.set_block(UVec3::new(3, 20, 3), "air".to_string());
+        light.update_block(UVec3::new(3, 20, 3), &chunk, [None; 6], &registry);
+        assert_eq!(light.sky_light(UVec3::new(3, 0, 3)), MAX_LIGHT);
+        assert_eq!(light.sky_light(UVec3::new(4, 0, 3)), MAX_LIGHT - 1);
+        chunk.set_block(UVec3::new(3, 20, 3), "vinox:stone".to_string());
+        light.update_block(UVec3::new(3, 20, 3), &chunk, [None; 6], &registry);
+        assert_eq!(light.sky_light(UVec3::new(3, 0, 3)), 0);
+        assert_eq!(light.sky_light(UVec3::new(4, 0, 3)), 0);
+
+        // Same as lighting it from scratch
+        let mut full = LightChunk::default();
+        full.calculate_light(&chunk, [None; 6], &registry);
+        assert_eq!(full, light);
+    }
+}
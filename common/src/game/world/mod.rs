@@ -1 +1,6 @@
+pub mod block_state;
 pub mod chunk;
+pub mod geometry;
+pub mod light;
+pub mod registry;
+pub mod voxel_storage;
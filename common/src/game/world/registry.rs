@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+use crate::game::storage::BlockType;
+
+use super::block_state::BlockState;
+
+pub const AIR_ID: u16 = 0;
+pub const NAMESPACE_SEPARATOR: char = ':';
+
+// Blocks are identified everywhere as "namespace:name", eg "vinox:grass"
+pub fn block_identifier(namespace: &str, block_name: &str) -> String {
+    format!("{namespace}{NAMESPACE_SEPARATOR}{block_name}")
+}
+
+pub fn entity_identifier(namespace: &str, entity_name: &str) -> String {
+    format!("{namespace}{NAMESPACE_SEPARATOR}{entity_name}")
+}
+
+pub fn biome_identifier(namespace: &str, biome_name: &str) -> String {
+    format!("{namespace}{NAMESPACE_SEPARATOR}{biome_name}")
+}
+
+pub fn structure_identifier(namespace: &str, structure_name: &str) -> String {
+    format!("{namespace}{NAMESPACE_SEPARATOR}{structure_name}")
+}
+
+// Numeric ids for every block a world knows about. Air is always 0. Ids are stable for a world since the
+// table is saved with it, blocks that stop being loaded keep their id reserved in case they come back
+#[derive(Resource, Clone, Debug)]
+pub struct BlockRegistry {
+    names: Vec<String>,
+    blocks: Vec<Option<BlockType>>, // None for air and for blocks that aren't loaded anymore
+    ids: HashMap<String, u16>,
+    // Old worlds stored "vinoxgrass" instead of "vinox:grass"
+    legacy_ids: HashMap<String, u16>,
+}
+
+impl Default for BlockRegistry {
+    fn default() -> BlockRegistry {
+        BlockRegistry::new()
+    }
+}
+
+impl BlockRegistry {
+    pub fn new() -> BlockRegistry {
+        BlockRegistry {
+            names: vec!["air".to_string()],
+            blocks: vec![None],
+            ids: HashMap::from([("air".to_string(), AIR_ID)]),
+            legacy_ids: HashMap::new(),
+        }
+    }
+
+    // Fresh ids for a new world. Sorted so the same content always gives the same ids
+    pub fn from_blocks(blocks: &HashMap<String, BlockType>) -> BlockRegistry {
+        BlockRegistry::from_table(&[], blocks)
+    }
+
+    // Keeps every id from a saved table and gives any blocks that are new since then the next free ids
+    pub fn from_table(
+        table: &[(u16, String)],
+        blocks: &HashMap<String, BlockType>,
+    ) -> BlockRegistry {
+        let mut registry = BlockRegistry::new();
+        for (id, name) in table.iter() {
+            if *id == AIR_ID || registry.ids.contains_key(name) {
+                continue;
+            }
+            if blocks.get(name).is_none() {
+                warn!("Block {name} is missing, keeping id {id} reserved for it");
+            }
+            registry.insert(*id, name, blocks.get(name).cloned());
+        }
+
+        let mut new_blocks: Vec<&String> = blocks
+            .keys()
+            .filter(|name| !registry.ids.contains_key(*name))
+            .collect();
+        new_blocks.sort();
+        for name in new_blocks {
+            let id = registry.names.len() as u16;
+            registry.insert(id, name, blocks.get(name).cloned());
+        }
+        registry
+    }
+
+    fn insert(&mut self, id: u16, name: &str, block: Option<BlockType>) {
+        // Gaps only happen if the saved table was edited by hand, an empty name never matches anything
+        if id as usize >= self.names.len() {
+            self.names.resize(id as usize + 1, String::new());
+            self.blocks.resize(id as usize + 1, None);
+        }
+        self.names[id as usize] = name.to_owned();
+        self.blocks[id as usize] = block;
+        self.ids.insert(name.to_owned(), id);
+        self.legacy_ids
+            .insert(name.replacen(NAMESPACE_SEPARATOR, "", 1), id);
+    }
+
+    // What gets saved with the world
+    pub fn table(&self) -> Vec<(u16, String)> {
+        self.names
+            .iter()
+            .enumerate()
+            .skip(1)
+            .filter(|(_, name)| !name.is_empty())
+            .map(|(id, name)| (id as u16, name.to_owned()))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.len() == 1
+    }
+
+    // Takes a block id or a full palette entry, any state is ignored
+    pub fn id(&self, block_state: &str) -> Option<u16> {
+        self.ids.get(BlockState::block_id_of(block_state)).copied()
+    }
+
+    pub fn name(&self, id: u16) -> Option<&str> {
+        self.names
+            .get(id as usize)
+            .map(|name| name.as_str())
+            .filter(|name| !name.is_empty())
+    }
+
+    // None for air, unknown ids and blocks that aren't loaded
+    pub fn get(&self, id: u16) -> Option<&BlockType> {
+        self.blocks
+            .get(id as usize)
+            .and_then(|block| block.as_ref())
+    }
+
+    pub fn get_by_name(&self, block_state: &str) -> Option<&BlockType> {
+        self.id(block_state).and_then(|id| self.get(id))
+    }
+
+    // The proper identifier for a block id saved before namespaces had a separator
+    pub fn legacy_identifier(&self, block_id: &str) -> Option<&str> {
+        self.legacy_ids.get(block_id).and_then(|id| self.name(*id))
+    }
+}
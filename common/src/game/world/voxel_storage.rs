@@ -0,0 +1,284 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::chunk::TOTAL_CHUNK_USIZE;
+
+const WORD_BITS: usize = u64::BITS as usize;
+
+// Smallest width out of 1, 2, 4, 8 and 16 bits that can hold this many palette entries.
+// Only powers of two are used so a voxel never straddles two words
+pub fn bits_for_states(states: usize) -> u8 {
+    match states {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        17..=256 => 8,
+        _ => 16,
+    }
+}
+
+fn mask(bits: u8) -> u64 {
+    (1 << bits) - 1
+}
+
+// Palette indices for every voxel in a chunk. Chunks that are all one block (usually air or stone)
+// don't store anything per voxel, otherwise indices are packed as tightly as the palette allows
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "SerializedStorage")]
+pub enum VoxelStorage {
+    Single {
+        len: usize,
+        value: u16,
+    },
+    Packed {
+        len: usize,
+        bits: u8,
+        words: Vec<u64>,
+    },
+}
+
+// Same layout as VoxelStorage. Chunks come from the database and from the network so everything gets
+// checked before get and set can trip over it
+#[derive(Deserialize)]
+enum SerializedStorage {
+    Single {
+        len: usize,
+        value: u16,
+    },
+    Packed {
+        len: usize,
+        bits: u8,
+        words: Vec<u64>,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StorageError {
+    WrongLength(usize),
+    BadBits(u8),
+    WrongWordCount { expected: usize, found: usize },
+    // A voxel points past the end of its chunk's palette
+    MissingPaletteEntry(u16),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageError::WrongLength(len) => {
+                write!(f, "chunk has {len} voxels instead of {TOTAL_CHUNK_USIZE}")
+            }
+            StorageError::BadBits(bits) => write!(f, "{bits} bits per voxel isn't supported"),
+            StorageError::WrongWordCount { expected, found } => {
+                write!(f, "expected {expected} packed words, found {found}")
+            }
+            StorageError::MissingPaletteEntry(index) => {
+                write!(
+                    f,
+                    "voxel points at palette entry {index} which doesn't exist"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl TryFrom<SerializedStorage> for VoxelStorage {
+    type Error = StorageError;
+
+    fn try_from(storage: SerializedStorage) -> Result<VoxelStorage, StorageError> {
+        match storage {
+            SerializedStorage::Single { len, value } => {
+                if len != TOTAL_CHUNK_USIZE {
+                    return Err(StorageError::WrongLength(len));
+                }
+                Ok(VoxelStorage::Single { len, value })
+            }
+            SerializedStorage::Packed { len, bits, words } => {
+                if len != TOTAL_CHUNK_USIZE {
+                    return Err(StorageError::WrongLength(len));
+                }
+                if ![1, 2, 4, 8, 16, 32].contains(&bits) {
+                    return Err(StorageError::BadBits(bits));
+                }
+                let expected = len.div_ceil(WORD_BITS / bits as usize);
+                if words.len() != expected {
+                    return Err(StorageError::WrongWordCount {
+                        expected,
+                        found: words.len(),
+                    });
+                }
+                Ok(VoxelStorage::Packed { len, bits, words })
+            }
+        }
+    }
+}
+
+impl VoxelStorage {
+    pub fn new(len: usize) -> VoxelStorage {
+        VoxelStorage::Single { len, value: 0 }
+    }
+
+    pub fn from_values(len: usize, bits: u8, values: impl Iterator<Item = u16>) -> VoxelStorage {
+        let per_word = WORD_BITS / bits as usize;
+        let mut words = vec![0; len.div_ceil(per_word)];
+        for (index, value) in values.take(len).enumerate() {
+            let shift = (index % per_word) * bits as usize;
+            words[index / per_word] |= (value as u64 & mask(bits)) << shift;
+        }
+        VoxelStorage::Packed { len, bits, words }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            VoxelStorage::Single { len, .. } | VoxelStorage::Packed { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Bits used per voxel, 0 when the whole chunk is a single value
+    pub fn bits(&self) -> u8 {
+        match self {
+            VoxelStorage::Single { .. } => 0,
+            VoxelStorage::Packed { bits, .. } => *bits,
+        }
+    }
+
+    // Out of bounds is a bug in the caller so both of these panic, even for single value chunks
+    pub fn get(&self, index: usize) -> u16 {
+        self.check_bounds(index);
+        match self {
+            VoxelStorage::Single { value, .. } => *value,
+            VoxelStorage::Packed { bits, words, .. } => {
+                let per_word = WORD_BITS / *bits as usize;
+                let shift = (index % per_word) * *bits as usize;
+                ((words[index / per_word] >> shift) & mask(*bits)) as u16
+            }
+        }
+    }
+
+    pub fn set(&mut self, index: usize, value: u16) {
+        self.check_bounds(index);
+        let needed = match self {
+            VoxelStorage::Single { value: current, .. } => {
+                if *current == value {
+                    return;
+                }
+                bits_for_states(value.max(*current) as usize + 1)
+            }
+            VoxelStorage::Packed { .. } => bits_for_states(value as usize + 1),
+        };
+        if needed > self.bits() {
+            self.resize(needed);
+        }
+        if let VoxelStorage::Packed { bits, words, .. } = self {
+            let per_word = WORD_BITS / *bits as usize;
+            let shift = (index % per_word) * *bits as usize;
+            let word = &mut words[index / per_word];
+            *word = (*word & !(mask(*bits) << shift)) | ((value as u64) << shift);
+        }
+    }
+
+    fn check_bounds(&self, index: usize) {
+        assert!(
+            index < self.len(),
+            "Voxel index {index} out of bounds for {} voxels",
+            self.len()
+        );
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..self.len()).map(|index| self.get(index))
+    }
+
+    // Repack every voxel at a new width, the width has to fit every value already stored
+    pub fn resize(&mut self, bits: u8) {
+        *self = VoxelStorage::from_values(self.len(), bits, self.iter());
+    }
+
+    // Rewrite every voxel through remap (old index -> new index) in one pass and pick the
+    // narrowest storage that fits the result
+    pub fn remap(&mut self, remap: &[u16]) {
+        let len = self.len();
+        match self {
+            VoxelStorage::Single { value, .. } => *value = remap[*value as usize],
+            VoxelStorage::Packed { .. } => {
+                let first = remap[self.get(0) as usize];
+                if self.iter().all(|value| remap[value as usize] == first) {
+                    *self = VoxelStorage::Single { len, value: first };
+                } else {
+                    let max = remap.iter().copied().max().unwrap_or(0);
+                    let bits = bits_for_states(max as usize + 1);
+                    *self = VoxelStorage::from_values(
+                        len,
+                        bits,
+                        self.iter().map(|value| remap[value as usize]),
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VoxelStorage;
+
+    #[test]
+    fn grows_with_the_palette() {
+        let mut storage = VoxelStorage::new(100);
+        assert_eq!(storage.bits(), 0);
+        storage.set(3, 1);
+        assert_eq!(storage.bits(), 1);
+        storage.set(4, 3);
+        assert_eq!(storage.bits(), 2);
+        storage.set(5, 200);
+        assert_eq!(storage.bits(), 8);
+        storage.set(99, 1000);
+        assert_eq!(storage.bits(), 16);
+        // Growing keeps everything already there
+        let values: Vec<u16> = storage.iter().collect();
+        assert_eq!(&values[2..6], [0, 1, 3, 200]);
+        assert_eq!(values[99], 1000);
+        assert_eq!(values.iter().filter(|&&value| value != 0).count(), 4);
+    }
+
+    #[test]
+    fn remaps_and_collapses() {
+        let mut storage = VoxelStorage::new(64);
+        storage.set(0, 1);
+        storage.set(1, 2);
+        storage.set(63, 5);
+        assert_eq!(storage.bits(), 4);
+
+        storage.remap(&[0, 2, 1, 0, 0, 1]);
+        assert_eq!(storage.get(0), 2);
+        assert_eq!(storage.get(1), 1);
+        assert_eq!(storage.get(63), 1);
+        assert_eq!(storage.get(10), 0);
+        assert_eq!(storage.bits(), 2);
+
+        // Everything pointing at one entry goes back to a single value
+        storage.remap(&[3, 3, 3]);
+        assert_eq!(storage, VoxelStorage::Single { len: 64, value: 3 });
+        assert_eq!(storage.get(63), 3);
+    }
+
+    #[test]
+    #[should_panic]
+    fn single_checks_bounds() {
+        VoxelStorage::new(64).get(64);
+    }
+
+    #[test]
+    #[should_panic]
+    fn packed_checks_bounds() {
+        let mut storage = VoxelStorage::new(64);
+        storage.set(0, 1);
+        storage.set(64, 1);
+    }
+}
@@ -6,6 +6,8 @@ pub struct NetworkIP(pub String);
 
 use serde::{Deserialize, Serialize};
 
+use super::content::ContentManifest;
+
 pub const PROTOCOL_ID: u64 = 7;
 pub const RELIABLE_CHANNEL_MAX_LENGTH: u64 = 10240;
 
@@ -46,9 +48,20 @@ pub enum ClientMessage {
         voxel_pos: [u8; 3],
         block_type: String,
     },
+    // Right clicked an interactable block, the server runs its script
+    InteractBlock {
+        chunk_pos: IVec3,
+        voxel_pos: [u8; 3],
+    },
     Join {
         user_name: String, // Username is just for display we use an id for the actual identification of clients
         id: ClientId,
+        // Has to match the server's or the player gets kicked
+        content_hash: u64,
+    },
+    // Pack namespace and path of every file from the server's ContentManifest the client doesn't have
+    RequestContent {
+        files: Vec<(String, String)>,
     },
     Leave {
         id: ClientId,
@@ -60,6 +73,17 @@ pub enum ServerMessage {
     ClientId {
         id: ClientId,
     },
+    // Sent right after ClientId so the client can check it has the same content before joining
+    ContentInfo {
+        manifest: ContentManifest,
+        block_table: Vec<(u16, String)>,
+        entities: Vec<String>,
+    },
+    ContentFile {
+        namespace: String,
+        path: String,
+        data: Vec<u8>,
+    },
     PlayerCreate {
         entity: Entity,
         id: ClientId,
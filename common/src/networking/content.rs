@@ -0,0 +1,115 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::game::{assets::project_dirs, scripting::content::ContentPack};
+
+// Files bigger than this are never sent to clients, a message has to fit in one network frame
+pub const MAX_CONTENT_FILE_SIZE: u64 = 4 * 1024 * 1024;
+
+// FNV-1a, unlike the std hasher it's guaranteed to give the same hash on every machine and version
+pub fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// Paths from the network have to stay inside the folder they're written to
+pub fn safe_relative_path(path: &str) -> Option<PathBuf> {
+    let relative = PathBuf::from(path);
+    (!path.is_empty()
+        && relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_))))
+    .then_some(relative)
+}
+
+// Relative to the pack root with / between folders so it's the same on every platform
+fn relative_name(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let parts: Vec<String> = relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().to_string())
+        .collect();
+    Some(parts.join("/"))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ContentFile {
+    pub path: String,
+    pub hash: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PackFiles {
+    pub namespace: String,
+    pub files: Vec<ContentFile>,
+}
+
+// Every file the loaded content came from and a hash of all of them. Two sides with the same hash have the
+// same blocks and entities
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default, Resource)]
+pub struct ContentManifest {
+    pub hash: u64,
+    // In load order, the first one is the base game
+    pub packs: Vec<PackFiles>,
+}
+
+impl ContentManifest {
+    pub fn from_packs(packs: &[ContentPack]) -> ContentManifest {
+        let mut manifest = ContentManifest::default();
+        let mut all = Vec::new();
+        for pack in packs {
+            let mut pack_files_list = Vec::new();
+            for path in pack.files() {
+                // Overrides can point outside their pack, those never get sent
+                let name = relative_name(&pack.root, &path)
+                    .filter(|name| safe_relative_path(name).is_some());
+                let (Some(name), Ok(bytes)) = (name, fs::read(&path)) else {
+                    continue;
+                };
+                pack_files_list.push(ContentFile {
+                    path: name,
+                    hash: hash_bytes(&bytes),
+                });
+            }
+            all.extend_from_slice(pack.manifest.namespace.as_bytes());
+            all.push(0);
+            for file in pack_files_list.iter() {
+                all.extend_from_slice(file.path.as_bytes());
+                all.push(0);
+                all.extend_from_slice(&file.hash.to_le_bytes());
+            }
+            manifest.packs.push(PackFiles {
+                namespace: pack.manifest.namespace.clone(),
+                files: pack_files_list,
+            });
+        }
+        manifest.hash = hash_bytes(&all);
+        manifest
+    }
+
+    pub fn file(&self, namespace: &str, path: &str) -> Option<&ContentFile> {
+        self.packs
+            .iter()
+            .find(|pack| pack.namespace == namespace)
+            .and_then(|pack| pack.files.iter().find(|file| file.path == path))
+    }
+}
+
+// Where a client keeps the content of a server it joined, one folder per pack named after its namespace
+pub fn server_content_dir(hash: u64) -> Option<PathBuf> {
+    project_dirs().map(|proj_dirs| {
+        proj_dirs
+            .cache_dir()
+            .join("servers")
+            .join(format!("{hash:016x}"))
+    })
+}
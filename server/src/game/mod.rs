@@ -1,2 +1,3 @@
+pub mod scripting;
 pub mod setup;
 pub mod world;
@@ -0,0 +1,469 @@
+use std::collections::HashSet;
+
+use bevy::prelude::*;
+use bevy_quinnet::{
+    server::{Endpoint, Server},
+    shared::ClientId,
+};
+use common::{
+    game::{
+        scripting::{
+            block::script::{BlockHook, BlockScripts, BlockWorld},
+            entity::script::{EntityHook, EntityScripts, EntityWorld},
+        },
+        world::{
+            block_state::BlockState,
+            chunk::{
+                global_voxel_to_voxel, voxel_to_global_voxel, ChunkComp, CurrentChunks,
+                LoadableTypes, CHUNK_SIZE,
+            },
+        },
+    },
+    networking::components::{Player, ServerMessage},
+};
+use iyes_loopless::prelude::*;
+use rand::Rng;
+
+use super::world::storage::{insert_chunk, WorldDatabase};
+
+// How many random voxels get a tick each fixed update in every loaded chunk with a ticking block
+pub const RANDOM_TICKS_PER_CHUNK: usize = 8;
+
+// The loaded chunks as seen by block scripts. Blocks set by a script are applied straight away so later
+// reads in the same hook see them, they get saved and sent out afterwards with send_changes
+pub struct ChunkWorld<'a, 'w, 's, 'q> {
+    pub current_chunks: &'a CurrentChunks,
+    pub chunks: &'a mut Query<'w, 's, &'q mut ChunkComp>,
+    pub loadable_types: &'a LoadableTypes,
+    pub changed: Vec<(IVec3, UVec3, String)>,
+    pub spawned: Vec<SpawnEntityEvent>,
+}
+
+impl<'a, 'w, 's, 'q> ChunkWorld<'a, 'w, 's, 'q> {
+    pub fn new(
+        current_chunks: &'a CurrentChunks,
+        chunks: &'a mut Query<'w, 's, &'q mut ChunkComp>,
+        loadable_types: &'a LoadableTypes,
+    ) -> Self {
+        ChunkWorld {
+            current_chunks,
+            chunks,
+            loadable_types,
+            changed: Vec::new(),
+            spawned: Vec::new(),
+        }
+    }
+
+    // Blocks set by scripts don't run hooks themselves, otherwise two scripts could keep setting each other.
+    // Entities they spawn go through spawn_entities like any others
+    pub fn send_changes(
+        self,
+        database: &WorldDatabase,
+        endpoint: &mut Endpoint,
+        spawn_events: &mut EventWriter<SpawnEntityEvent>,
+    ) {
+        spawn_events.send_batch(self.spawned);
+        let mut saved = HashSet::new();
+        for (chunk_pos, voxel_pos, block_type) in self.changed {
+            if saved.insert(chunk_pos) {
+                if let Some(chunk) = self
+                    .current_chunks
+                    .get_entity(chunk_pos)
+                    .and_then(|chunk_entity| self.chunks.get(chunk_entity).ok())
+                {
+                    let data = database.connection.lock().unwrap();
+                    insert_chunk(chunk_pos, &chunk.chunk_data, &data);
+                }
+            }
+            endpoint.try_broadcast_message(ServerMessage::SentBlock {
+                chunk_pos,
+                voxel_pos: [voxel_pos.x as u8, voxel_pos.y as u8, voxel_pos.z as u8],
+                block_type,
+            });
+        }
+    }
+}
+
+impl BlockWorld for ChunkWorld<'_, '_, '_, '_> {
+    fn get_block(&self, pos: IVec3) -> Option<String> {
+        let (chunk_pos, voxel_pos) = global_voxel_to_voxel(pos);
+        let chunk_entity = self.current_chunks.get_entity(chunk_pos)?;
+        self.chunks
+            .get(chunk_entity)
+            .ok()?
+            .chunk_data
+            .get_block(voxel_pos)
+    }
+
+    fn set_block(&mut self, pos: IVec3, block: &str) -> bool {
+        let block_state = match BlockState::parse_validated(block, &self.loadable_types.blocks) {
+            Ok(block_state) => block_state,
+            Err(error) => {
+                warn!("A block script tried to set a bad block: {error}");
+                return false;
+            }
+        };
+        let (chunk_pos, voxel_pos) = global_voxel_to_voxel(pos);
+        let Some(chunk_entity) = self.current_chunks.get_entity(chunk_pos) else {
+            return false;
+        };
+        let Ok(mut chunk) = self.chunks.get_mut(chunk_entity) else {
+            return false;
+        };
+        chunk.chunk_data.set_block_state(voxel_pos, &block_state);
+        self.changed
+            .push((chunk_pos, voxel_pos, block_state.to_string()));
+        true
+    }
+
+    fn spawn_entity(&mut self, entity_type: &str, pos: Vec3) -> bool {
+        if !self.loadable_types.entities.contains_key(entity_type) {
+            warn!("A block script tried to spawn unknown entity {entity_type}");
+            return false;
+        }
+        self.spawned.push(SpawnEntityEvent {
+            entity_type: entity_type.to_owned(),
+            translation: pos,
+        });
+        true
+    }
+}
+
+// Runs one hook and sends out whatever the script changed. Script errors only get logged
+#[allow(clippy::too_many_arguments)]
+pub fn run_block_hook(
+    block_scripts: &BlockScripts,
+    current_chunks: &CurrentChunks,
+    chunks: &mut Query<&mut ChunkComp>,
+    loadable_types: &LoadableTypes,
+    database: &WorldDatabase,
+    endpoint: &mut Endpoint,
+    spawn_events: &mut EventWriter<SpawnEntityEvent>,
+    hook: BlockHook,
+    pos: IVec3,
+    block_state: &str,
+) {
+    let mut world = ChunkWorld::new(current_chunks, chunks, loadable_types);
+    if let Err(error) = block_scripts.call(&mut world, hook, pos, block_state) {
+        warn!("{error} (at {pos} on {block_state})");
+    }
+    world.send_changes(database, endpoint, spawn_events);
+}
+
+pub fn load_block_scripts(
+    mut block_scripts: NonSendMut<BlockScripts>,
+    loadable_types: Res<LoadableTypes>,
+) {
+    block_scripts.load_blocks(&loadable_types.blocks);
+}
+
+pub fn random_tick_blocks(
+    block_scripts: NonSend<BlockScripts>,
+    mut server: ResMut<Server>,
+    current_chunks: Res<CurrentChunks>,
+    mut chunks: Query<&mut ChunkComp>,
+    loadable_types: Res<LoadableTypes>,
+    database: Res<WorldDatabase>,
+    mut spawn_events: EventWriter<SpawnEntityEvent>,
+) {
+    let mut rng = rand::thread_rng();
+    let mut ticks = Vec::new();
+    for chunk in chunks.iter() {
+        // Most chunks have nothing that ticks so skip them without touching any voxels
+        if !chunk
+            .chunk_data
+            .palette()
+            .iter()
+            .any(|state| block_scripts.has_hook(state, BlockHook::RandomTick.name()))
+        {
+            continue;
+        }
+        for _ in 0..RANDOM_TICKS_PER_CHUNK {
+            let voxel_pos = UVec3::new(
+                rng.gen_range(0..CHUNK_SIZE),
+                rng.gen_range(0..CHUNK_SIZE),
+                rng.gen_range(0..CHUNK_SIZE),
+            );
+            if let Some(block_state) = chunk.chunk_data.get_block(voxel_pos) {
+                if block_scripts.has_hook(&block_state, BlockHook::RandomTick.name()) {
+                    ticks.push((voxel_to_global_voxel(voxel_pos, chunk.pos.0), block_state));
+                }
+            }
+        }
+    }
+
+    let endpoint = server.endpoint_mut();
+    for (pos, block_state) in ticks {
+        run_block_hook(
+            &block_scripts,
+            &current_chunks,
+            &mut chunks,
+            &loadable_types,
+            &database,
+            endpoint,
+            &mut spawn_events,
+            BlockHook::RandomTick,
+            pos,
+            &block_state,
+        );
+    }
+}
+
+// Entities spawned from an entity type, only ones with a script do anything for now
+#[derive(Component)]
+pub struct ScriptedEntity {
+    pub entity_type: String,
+    pub health: f32,
+}
+
+pub const ENTITY_HEALTH: f32 = 10.0;
+
+pub struct SpawnEntityEvent {
+    pub entity_type: String,
+    pub translation: Vec3,
+}
+
+pub struct DamageEntityEvent {
+    pub entity: Entity,
+    pub amount: f32,
+    pub source: Option<ClientId>,
+}
+
+pub struct InteractEntityEvent {
+    pub entity: Entity,
+    pub player: ClientId,
+}
+
+// Sent by entity.emit(name, data) in a script, anything on the server can listen for these. Translation is
+// where the entity was when it sent it, the entity might be gone by the time anyone reads this
+#[derive(Clone, Debug)]
+pub struct ScriptEvent {
+    pub entity: Entity,
+    pub translation: Vec3,
+    pub name: String,
+    pub data: ron::Value,
+}
+
+// One entity as seen by its script
+pub struct EntityContext<'a> {
+    pub entity: Entity,
+    pub transform: &'a mut Transform,
+    pub players: &'a [(ClientId, Vec3)],
+    pub events: &'a mut Vec<ScriptEvent>,
+}
+
+impl EntityWorld for EntityContext<'_> {
+    fn position(&self) -> Vec3 {
+        self.transform.translation
+    }
+
+    fn move_to(&mut self, pos: Vec3) {
+        self.transform.translation = pos;
+    }
+
+    fn nearby_players(&self, radius: f32) -> Vec<(u64, Vec3)> {
+        self.players
+            .iter()
+            .filter(|(_, pos)| pos.distance(self.transform.translation) <= radius)
+            .copied()
+            .collect()
+    }
+
+    fn emit(&mut self, name: String, data: ron::Value) {
+        self.events.push(ScriptEvent {
+            entity: self.entity,
+            translation: self.transform.translation,
+            name,
+            data,
+        });
+    }
+}
+
+// Runs one entity hook. Errors carry the script file and line and only get logged
+pub fn run_entity_hook(
+    entity_scripts: &EntityScripts,
+    context: &mut EntityContext,
+    entity_type: &str,
+    hook: EntityHook,
+) {
+    if let Err(error) = entity_scripts.call(context.entity, context, hook) {
+        warn!("{error} (entity {entity_type})");
+    }
+}
+
+pub fn load_entity_scripts(
+    mut entity_scripts: NonSendMut<EntityScripts>,
+    loadable_types: Res<LoadableTypes>,
+) {
+    entity_scripts.load_entities(&loadable_types.entities);
+}
+
+fn player_positions(
+    players: &Query<(&Player, &Transform), Without<ScriptedEntity>>,
+) -> Vec<(ClientId, Vec3)> {
+    players
+        .iter()
+        .map(|(player, transform)| (player.id, transform.translation))
+        .collect()
+}
+
+pub fn spawn_entities(
+    mut commands: Commands,
+    mut spawn_events: EventReader<SpawnEntityEvent>,
+    mut entity_scripts: NonSendMut<EntityScripts>,
+    loadable_types: Res<LoadableTypes>,
+    players: Query<(&Player, &Transform), Without<ScriptedEntity>>,
+    mut script_events: EventWriter<ScriptEvent>,
+) {
+    let players = player_positions(&players);
+    let mut events = Vec::new();
+    for evt in spawn_events.iter() {
+        if !loadable_types.entities.contains_key(&evt.entity_type) {
+            warn!("Tried to spawn unknown entity {}", evt.entity_type);
+            continue;
+        }
+        let entity = commands.spawn_empty().id();
+        let mut transform = Transform::from_translation(evt.translation);
+        let mut context = EntityContext {
+            entity,
+            transform: &mut transform,
+            players: &players,
+            events: &mut events,
+        };
+        if let Err(error) = entity_scripts.spawn(entity, &evt.entity_type, &mut context) {
+            warn!("{error} (entity {})", evt.entity_type);
+        }
+        commands.entity(entity).insert((
+            transform,
+            GlobalTransform::default(),
+            ScriptedEntity {
+                entity_type: evt.entity_type.clone(),
+                health: ENTITY_HEALTH,
+            },
+        ));
+    }
+    script_events.send_batch(events);
+}
+
+pub fn tick_entities(
+    entity_scripts: NonSend<EntityScripts>,
+    fixed_timesteps: Res<FixedTimesteps>,
+    mut entities: Query<(Entity, &mut Transform, &ScriptedEntity)>,
+    players: Query<(&Player, &Transform), Without<ScriptedEntity>>,
+    mut script_events: EventWriter<ScriptEvent>,
+) {
+    let delta = fixed_timesteps.get_current().unwrap().step.as_secs_f32();
+    let players = player_positions(&players);
+    let mut events = Vec::new();
+    for (entity, mut transform, scripted) in entities.iter_mut() {
+        let mut context = EntityContext {
+            entity,
+            transform: &mut transform,
+            players: &players,
+            events: &mut events,
+        };
+        run_entity_hook(
+            &entity_scripts,
+            &mut context,
+            &scripted.entity_type,
+            EntityHook::Tick { delta },
+        );
+    }
+    script_events.send_batch(events);
+}
+
+// Damage and interactions come in from players. Entities at zero health get on_death and are removed
+pub fn handle_entity_events(
+    mut commands: Commands,
+    entity_scripts: NonSend<EntityScripts>,
+    mut damage_events: EventReader<DamageEntityEvent>,
+    mut interact_events: EventReader<InteractEntityEvent>,
+    mut entities: Query<(&mut Transform, &mut ScriptedEntity)>,
+    players: Query<(&Player, &Transform), Without<ScriptedEntity>>,
+    mut script_events: EventWriter<ScriptEvent>,
+) {
+    let players = player_positions(&players);
+    let mut events = Vec::new();
+    for evt in interact_events.iter() {
+        let Ok((mut transform, scripted)) = entities.get_mut(evt.entity) else {
+            continue;
+        };
+        let mut context = EntityContext {
+            entity: evt.entity,
+            transform: &mut transform,
+            players: &players,
+            events: &mut events,
+        };
+        run_entity_hook(
+            &entity_scripts,
+            &mut context,
+            &scripted.entity_type,
+            EntityHook::Interact { player: evt.player },
+        );
+    }
+    for evt in damage_events.iter() {
+        let Ok((mut transform, mut scripted)) = entities.get_mut(evt.entity) else {
+            continue;
+        };
+        // Already dead and waiting on the despawn
+        if scripted.health <= 0.0 {
+            continue;
+        }
+        scripted.health -= evt.amount;
+        let mut context = EntityContext {
+            entity: evt.entity,
+            transform: &mut transform,
+            players: &players,
+            events: &mut events,
+        };
+        run_entity_hook(
+            &entity_scripts,
+            &mut context,
+            &scripted.entity_type,
+            EntityHook::Damage {
+                amount: evt.amount,
+                source: evt.source,
+            },
+        );
+        if scripted.health <= 0.0 {
+            run_entity_hook(
+                &entity_scripts,
+                &mut context,
+                &scripted.entity_type,
+                EntityHook::Death,
+            );
+            commands.entity(evt.entity).despawn();
+        }
+    }
+    script_events.send_batch(events);
+}
+
+// Script state goes whenever a scripted entity does, however it got removed. Has to run in a later stage
+// than the despawn or the removal doesn't show up
+pub fn clear_entity_scripts(
+    mut entity_scripts: NonSendMut<EntityScripts>,
+    removed: RemovedComponents<ScriptedEntity>,
+) {
+    for entity in removed.iter() {
+        entity_scripts.despawn(entity);
+    }
+}
+
+// `entity.emit("spawn_entity", "vinox:golem")` spawns one where the entity is. Nothing else is handled yet
+// so it only gets logged
+pub fn handle_script_events(
+    mut script_events: EventReader<ScriptEvent>,
+    mut spawn_events: EventWriter<SpawnEntityEvent>,
+) {
+    for evt in script_events.iter() {
+        match (evt.name.as_str(), &evt.data) {
+            ("spawn_entity", ron::Value::String(entity_type)) => {
+                spawn_events.send(SpawnEntityEvent {
+                    entity_type: entity_type.to_owned(),
+                    translation: evt.translation,
+                });
+            }
+            _ => debug!("Entity {:?} sent {} {:?}", evt.entity, evt.name, evt.data),
+        }
+    }
+}
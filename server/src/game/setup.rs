@@ -4,31 +4,155 @@ use bevy_quinnet::server::*;
 use common::{
     game::{
         bundles::PlayerBundleBuilder,
-        scripting::{block::load::load_all_blocks, entity::load::load_all_entities},
-        storage::{convert_block, convert_entity, BlockType, EntityType},
+        scripting::{
+            block::script::BlockScripts,
+            content::{load_all_content, Content, ContentWatcher},
+            entity::script::EntityScripts,
+        },
+        storage::{convert_biome, convert_block, convert_entity, convert_structure},
+        world::{chunk::LoadableTypes, registry::BlockRegistry},
+    },
+    networking::{
+        components::{NetworkIP, ServerMessage},
+        content::{safe_relative_path, ContentManifest, MAX_CONTENT_FILE_SIZE},
     },
-    networking::components::NetworkIP,
 };
+use iyes_loopless::prelude::*;
 
 pub fn setup(mut commands: Commands, _chunk_manager: ChunkManager) {
     commands.spawn(LoadPoint(IVec3::new(0, 0, 0)));
 }
 
-use std::collections::HashMap;
+use std::{collections::HashMap, fs, path::PathBuf, time::Duration};
 
-use super::world::chunk::{ChunkGenerationPlugin, ChunkManager, LoadPoint};
+use super::{
+    scripting::{
+        clear_entity_scripts, handle_entity_events, handle_script_events, load_block_scripts,
+        load_entity_scripts, random_tick_blocks, spawn_entities, tick_entities, DamageEntityEvent,
+        InteractEntityEvent, ScriptEvent, SpawnEntityEvent,
+    },
+    world::{
+        chunk::{ChunkGenerationPlugin, ChunkManager, LoadPoint},
+        storage::{load_block_table, save_block_table, WorldDatabase},
+    },
+};
 
 extern crate common;
 
+// What clients need to match before they can join, and where to find the files if they don't
 #[derive(Resource, Default)]
-pub struct LoadableTypes {
-    pub entities: HashMap<String, EntityType>,
-    pub blocks: HashMap<String, BlockType>,
+pub struct ServerContent {
+    pub manifest: ContentManifest,
+    // Pack namespace to its folder
+    pub roots: HashMap<String, PathBuf>,
+}
+
+impl ServerContent {
+    // Only files in the manifest can be read so clients can't ask for anything else on the server
+    pub fn read(&self, namespace: &str, path: &str) -> Option<Vec<u8>> {
+        self.manifest.file(namespace, path)?;
+        let full_path = self.roots.get(namespace)?.join(safe_relative_path(path)?);
+        if fs::metadata(&full_path).ok()?.len() > MAX_CONTENT_FILE_SIZE {
+            warn!("{} is too big to send", full_path.display());
+            return None;
+        }
+        fs::read(full_path).ok()
+    }
+
+    // The message every client gets when it connects and again whenever the content is reloaded
+    pub fn info(
+        &self,
+        block_registry: &BlockRegistry,
+        loadable_types: &LoadableTypes,
+    ) -> ServerMessage {
+        let mut entities: Vec<String> = loadable_types.entities.keys().cloned().collect();
+        entities.sort();
+        ServerMessage::ContentInfo {
+            manifest: self.manifest.clone(),
+            block_table: block_registry.table(),
+            entities,
+        }
+    }
 }
 
-pub fn setup_loadables(mut loadable_types: ResMut<LoadableTypes>) {
-    loadable_types.blocks = convert_block(load_all_blocks());
-    loadable_types.entities = convert_entity(load_all_entities());
+// Loaded content replaces whatever was there before, blocks keep the ids they already had
+fn apply_content(
+    content: Content,
+    loadable_types: &mut LoadableTypes,
+    block_registry: &mut BlockRegistry,
+    server_content: &mut ServerContent,
+    database: &WorldDatabase,
+) -> ContentWatcher {
+    content.report.log();
+    server_content.manifest = ContentManifest::from_packs(&content.packs);
+    server_content.roots = content
+        .packs
+        .iter()
+        .map(|pack| (pack.manifest.namespace.clone(), pack.root.clone()))
+        .collect();
+    loadable_types.blocks = convert_block(content.blocks);
+    loadable_types.entities = convert_entity(content.entities);
+    loadable_types.biomes = convert_biome(content.biomes);
+    loadable_types.structures = convert_structure(content.structures);
+    let data = database.connection.lock().unwrap();
+    *block_registry = BlockRegistry::from_table(&load_block_table(&data), &loadable_types.blocks);
+    save_block_table(block_registry, &data);
+    ContentWatcher::new(&content.packs, &content.archives)
+}
+
+pub fn setup_loadables(
+    mut loadable_types: ResMut<LoadableTypes>,
+    mut block_registry: ResMut<BlockRegistry>,
+    mut server_content: ResMut<ServerContent>,
+    mut content_watcher: ResMut<ContentWatcher>,
+    database: Res<WorldDatabase>,
+) {
+    *content_watcher = apply_content(
+        load_all_content(),
+        &mut loadable_types,
+        &mut block_registry,
+        &mut server_content,
+        &database,
+    );
+}
+
+// How often the content files get checked for changes
+pub const CONTENT_POLL_SECONDS: f32 = 1.0;
+
+// Edits to blocks, entities and their scripts show up without restarting. Everyone connected gets the new
+// content info and resyncs
+#[allow(clippy::too_many_arguments)]
+pub fn reload_content(
+    mut timer: Local<Timer>,
+    time: Res<Time>,
+    mut content_watcher: ResMut<ContentWatcher>,
+    mut loadable_types: ResMut<LoadableTypes>,
+    mut block_registry: ResMut<BlockRegistry>,
+    mut server_content: ResMut<ServerContent>,
+    database: Res<WorldDatabase>,
+    mut block_scripts: NonSendMut<BlockScripts>,
+    mut entity_scripts: NonSendMut<EntityScripts>,
+    mut server: ResMut<Server>,
+) {
+    timer.set_mode(TimerMode::Repeating);
+    timer.set_duration(Duration::from_secs_f32(CONTENT_POLL_SECONDS));
+    timer.tick(time.delta());
+    if !timer.just_finished() || !content_watcher.changed() {
+        return;
+    }
+    info!("Content changed, reloading it");
+    *content_watcher = apply_content(
+        load_all_content(),
+        &mut loadable_types,
+        &mut block_registry,
+        &mut server_content,
+        &database,
+    );
+    block_scripts.reload(&loadable_types.blocks);
+    entity_scripts.reload(&loadable_types.entities);
+    server
+        .endpoint_mut()
+        .try_broadcast_message(server_content.info(&block_registry, &loadable_types));
 }
 
 pub fn new_server(ip_res: Res<NetworkIP>, mut server: ResMut<Server>) {
@@ -57,7 +181,26 @@ impl Plugin for GamePlugin {
             .add_plugin(QuinnetServerPlugin::default())
             .add_plugin(NetworkingPlugin)
             .insert_resource(LoadableTypes::default())
+            .insert_resource(BlockRegistry::default())
+            .insert_resource(ServerContent::default())
+            .insert_resource(ContentWatcher::default())
+            // Lua isn't Send so scripts stay on the main thread
+            .insert_non_send_resource(BlockScripts::new())
+            .insert_non_send_resource(EntityScripts::new())
+            .add_event::<SpawnEntityEvent>()
+            .add_event::<DamageEntityEvent>()
+            .add_event::<InteractEntityEvent>()
+            .add_event::<ScriptEvent>()
             .add_startup_system(setup_loadables)
+            .add_startup_system(load_block_scripts.after(setup_loadables))
+            .add_startup_system(load_entity_scripts.after(setup_loadables))
+            .add_system(reload_content)
+            .add_fixed_timestep_system("fixed_update", 0, random_tick_blocks)
+            .add_fixed_timestep_system("fixed_update", 0, tick_entities)
+            .add_system(spawn_entities)
+            .add_system(handle_entity_events)
+            .add_system(handle_script_events)
+            .add_system_to_stage(CoreStage::PostUpdate, clear_entity_scripts)
             .add_startup_system(new_server)
             .add_startup_system(setup_builders)
             .add_startup_system(setup);
@@ -0,0 +1,350 @@
+use std::{
+    collections::HashMap,
+    f32::consts::{PI, TAU},
+};
+
+use bevy::prelude::*;
+use common::{
+    game::world::chunk::{RawChunk, CHUNK_SIZE},
+    networking::content::hash_bytes,
+};
+
+use super::generation::{scramble, Column, TerrainGenerator};
+
+// Random numbers for walking one tunnel, seeded from the world seed and where the tunnel starts
+struct Walk(u64);
+
+impl Walk {
+    fn range(&mut self, min: f32, max: f32) -> f32 {
+        self.0 = scramble(self.0);
+        min + (self.0 >> 40) as f32 / (1u64 << 24) as f32 * (max - min)
+    }
+}
+
+// Every step of every tunnel that reaches into the chunk, as the center and radius of a ball to carve out.
+// Like structures they only depend on the seed and the terrain so neighbouring chunks agree on them
+pub fn tunnels(
+    generator: &TerrainGenerator,
+    chunk_pos: IVec3,
+    grid: &mut HashMap<IVec2, usize>,
+) -> Vec<(Vec3, f32)> {
+    let config = &generator.config().tunnels;
+    let region_size = config.region_size.max(1);
+    let chunk_min = chunk_pos * CHUNK_SIZE as i32;
+    let chunk_max = chunk_min + IVec3::splat(CHUNK_SIZE as i32);
+    let reach = config.length as i32 + config.radius.1.ceil() as i32;
+    let mut result = Vec::new();
+    for region_x in (chunk_min.x - reach).div_euclid(region_size)
+        ..=(chunk_max.x + reach).div_euclid(region_size)
+    {
+        for region_z in (chunk_min.z - reach).div_euclid(region_size)
+            ..=(chunk_max.z + reach).div_euclid(region_size)
+        {
+            let hash = hash_bytes(
+                format!("{}:tunnels:{region_x}:{region_z}", generator.seed()).as_bytes(),
+            );
+            if (hash % 10_000) as f64 / 10_000.0 >= config.chance {
+                continue;
+            }
+            let x = region_x * region_size + ((hash >> 20) % region_size as u64) as i32;
+            let z = region_z * region_size + ((hash >> 40) % region_size as u64) as i32;
+            if x + reach < chunk_min.x
+                || x - reach > chunk_max.x
+                || z + reach < chunk_min.z
+                || z - reach > chunk_max.z
+            {
+                continue;
+            }
+            let column = generator.column(x, z, grid);
+            let biome = generator.biome(column.biome);
+            // Sealed caves start under the cover so the entrance doesn't leave a dent in the ground
+            let start_y = if biome.cave_entrances {
+                column.surface
+            } else {
+                column.surface - config.cover - config.radius.1.ceil() as i32
+            };
+            if start_y + reach < chunk_min.y || start_y - reach > chunk_max.y {
+                continue;
+            }
+            let count = (config.tunnels as f64 * biome.caves).round() as u32;
+            for tunnel in 0..count {
+                let mut walk = Walk(hash ^ scramble(tunnel as u64));
+                let mut pos = Vec3::new(x as f32, start_y as f32, z as f32) + 0.5;
+                let mut yaw = walk.range(0.0, TAU);
+                // Steep at first to get under the ground, then they level out
+                let mut pitch = walk.range(-1.2, -0.7);
+                for step in 0..config.length {
+                    // Narrow at both ends and widest in the middle
+                    let along = (step as f32 / config.length as f32 * PI).sin();
+                    let radius = config.radius.0 + (config.radius.1 - config.radius.0) * along;
+                    let ball_min = (pos - radius).floor().as_ivec3();
+                    let ball_max = (pos + radius).ceil().as_ivec3();
+                    if ball_max.cmpge(chunk_min).all() && ball_min.cmple(chunk_max).all() {
+                        result.push((pos, radius));
+                    }
+                    pos += Vec3::new(
+                        yaw.cos() * pitch.cos(),
+                        pitch.sin(),
+                        yaw.sin() * pitch.cos(),
+                    );
+                    yaw += walk.range(-config.turn, config.turn);
+                    // Drifts a little downwards so caves keep going deeper
+                    pitch = (pitch * 0.9 - 0.02 + walk.range(-config.turn, config.turn) * 0.5)
+                        .clamp(-1.0, 0.6);
+                    if pos.y < config.min_y as f32 {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    result
+}
+
+// Biomes get the last word on whether a block is carved, some have no caves and some keep them sealed
+fn carvable(generator: &TerrainGenerator, column: &Column, y: i32) -> bool {
+    let biome = generator.biome(column.biome);
+    biome.caves > 0.0
+        && (biome.cave_entrances || y <= column.surface - generator.config().tunnels.cover)
+}
+
+// Tunnels first, then caverns deep down. Columns are the chunk's own, x major like in generate_chunk
+pub fn carve_caves(
+    generator: &TerrainGenerator,
+    raw_chunk: &mut RawChunk,
+    chunk_pos: IVec3,
+    columns: &[Column],
+    grid: &mut HashMap<IVec2, usize>,
+) {
+    let chunk_min = chunk_pos * CHUNK_SIZE as i32;
+    let column = |local: UVec3| &columns[(local.x * CHUNK_SIZE + local.z) as usize];
+    for (center, radius) in tunnels(generator, chunk_pos, grid) {
+        let min = ((center - radius).floor().as_ivec3() - chunk_min).max(IVec3::ZERO);
+        let max = ((center + radius).ceil().as_ivec3() - chunk_min)
+            .min(IVec3::splat(CHUNK_SIZE as i32 - 1));
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                for z in min.z..=max.z {
+                    let local = IVec3::new(x, y, z);
+                    let full = chunk_min + local;
+                    if (full.as_vec3() + 0.5).distance_squared(center) > radius * radius {
+                        continue;
+                    }
+                    let local = local.as_uvec3();
+                    if carvable(generator, column(local), full.y) {
+                        raw_chunk.set_block(local, "air".to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    let caverns = &generator.config().caverns;
+    if chunk_min.y > caverns.max_y {
+        return;
+    }
+    for x in 0..CHUNK_SIZE {
+        for z in 0..CHUNK_SIZE {
+            let column = column(UVec3::new(x, 0, z));
+            let caves = generator.biome(column.biome).caves;
+            for y in 0..CHUNK_SIZE {
+                let local = UVec3::new(x, y, z);
+                let full = chunk_min + local.as_ivec3();
+                if full.y > column.surface || !carvable(generator, column, full.y) {
+                    continue;
+                }
+                if generator.cavern(full) * caves > caverns.threshold {
+                    raw_chunk.set_block(local, "air".to_string());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::Path};
+
+    use bevy::prelude::*;
+    use common::game::{
+        scripting::{
+            biome::biome_descriptor::{BiomeDescriptor, HeightNoise},
+            content::load_content,
+        },
+        storage::convert_block,
+        world::chunk::{global_voxel_to_voxel, LoadableTypes, RawChunk, CHUNK_SIZE},
+    };
+
+    use super::tunnels;
+    use crate::game::world::{
+        config::{TunnelConfig, WorldGenConfig},
+        generation::TerrainGenerator,
+    };
+
+    // Flat ground with a cave system in every region. Both biomes share a climate so the selector mixes them
+    fn generator(biomes: &[(&str, f64)]) -> TerrainGenerator {
+        let content = load_content(&Path::new(env!("CARGO_MANIFEST_DIR")).join("../client/assets"));
+        let mut loadable_types = LoadableTypes {
+            blocks: convert_block(content.blocks),
+            ..default()
+        };
+        for (name, caves) in biomes {
+            loadable_types.biomes.insert(
+                format!("test:{name}"),
+                BiomeDescriptor {
+                    namespace: "test".to_string(),
+                    biome_name: name.to_string(),
+                    temperature: (-1.0, 1.0),
+                    humidity: (-1.0, 1.0),
+                    surface_block: "vinox:grass".to_string(),
+                    subsurface_block: "vinox:dirt".to_string(),
+                    subsurface_depth: 3,
+                    stone_block: "vinox:cobblestone".to_string(),
+                    height: HeightNoise {
+                        base: 8.0,
+                        amplitude: 0.0,
+                        frequency: 0.01,
+                        octaves: 1,
+                        ridged: false,
+                    },
+                    caves: *caves,
+                    cave_entrances: true,
+                },
+            );
+        }
+        let config = WorldGenConfig {
+            tunnels: TunnelConfig {
+                chance: 1.0,
+                ..default()
+            },
+            ores: Vec::new(),
+            stone_layers: Vec::new(),
+            ..default()
+        };
+        TerrainGenerator::new(1234, &config, &loadable_types)
+    }
+
+    fn carved(generator: &TerrainGenerator, pos: IVec3) -> RawChunk {
+        let columns = generator.columns(pos, &mut HashMap::new());
+        let mut raw_chunk = generator.terrain(pos, &columns);
+        generator.carve(&mut raw_chunk, pos, &columns);
+        raw_chunk
+    }
+
+    // Every block a ball carves, same test as carve_caves
+    fn ball_blocks(center: Vec3, radius: f32) -> Vec<IVec3> {
+        let min = (center - radius).floor().as_ivec3();
+        let max = (center + radius).ceil().as_ivec3();
+        let mut blocks = Vec::new();
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                for z in min.z..=max.z {
+                    let full = IVec3::new(x, y, z);
+                    if (full.as_vec3() + 0.5).distance_squared(center) <= radius * radius {
+                        blocks.push(full);
+                    }
+                }
+            }
+        }
+        blocks
+    }
+
+    #[test]
+    fn tunnels_agree_across_borders() {
+        let generator = generator(&[("caves", 1.0)]);
+        let mut grid = HashMap::new();
+        let mut checked = 0;
+        for x in -1..=1 {
+            for y in -1..=0 {
+                for z in -1..=1 {
+                    let pos = IVec3::new(x, y, z);
+                    let balls = tunnels(&generator, pos, &mut grid);
+                    for side in [IVec3::X, IVec3::Y, IVec3::Z] {
+                        let neighbour = pos + side;
+                        // Only the balls that carve blocks on both sides of the border
+                        let crossing: Vec<&(Vec3, f32)> = balls
+                            .iter()
+                            .filter(|(center, radius)| {
+                                let chunks: Vec<IVec3> = ball_blocks(*center, *radius)
+                                    .into_iter()
+                                    .map(|full| global_voxel_to_voxel(full).0)
+                                    .collect();
+                                chunks.contains(&pos) && chunks.contains(&neighbour)
+                            })
+                            .collect();
+                        if crossing.is_empty() {
+                            continue;
+                        }
+                        let neighbour_balls = tunnels(&generator, neighbour, &mut grid);
+                        let chunks = [
+                            (pos, carved(&generator, pos)),
+                            (neighbour, carved(&generator, neighbour)),
+                        ];
+                        for (center, radius) in crossing {
+                            assert!(neighbour_balls.contains(&(*center, *radius)));
+                            for full in ball_blocks(*center, *radius) {
+                                let (chunk_pos, local) = global_voxel_to_voxel(full);
+                                let Some((_, raw_chunk)) =
+                                    chunks.iter().find(|(pos, _)| *pos == chunk_pos)
+                                else {
+                                    continue;
+                                };
+                                assert_eq!(
+                                    raw_chunk.get_block(local).as_deref(),
+                                    Some("air"),
+                                    "{full} should be carved out"
+                                );
+                            }
+                            checked += 1;
+                        }
+                    }
+                }
+            }
+        }
+        assert!(checked > 0, "no tunnel crossed a border");
+    }
+
+    #[test]
+    fn no_caves_never_carved() {
+        let generator = generator(&[("caves", 1.5), ("solid", 0.0)]);
+        let mut grid = HashMap::new();
+        // Blocks of the solid biome that a tunnel went through, so it's not passing by never getting close
+        let mut reached = 0;
+        let mut carved_elsewhere = 0;
+        for x in -3..=3 {
+            for y in -2..=0 {
+                for z in -3..=3 {
+                    let pos = IVec3::new(x, y, z);
+                    let chunk_min = pos * CHUNK_SIZE as i32;
+                    let balls = tunnels(&generator, pos, &mut grid);
+                    let columns = generator.columns(pos, &mut grid);
+                    let raw_chunk = carved(&generator, pos);
+                    for (index, column) in columns.iter().enumerate() {
+                        let solid = generator.biome(column.biome).caves == 0.0;
+                        let (local_x, local_z) =
+                            (index as u32 / CHUNK_SIZE, index as u32 % CHUNK_SIZE);
+                        for local_y in 0..CHUNK_SIZE {
+                            let local = UVec3::new(local_x, local_y, local_z);
+                            let full = chunk_min + local.as_ivec3();
+                            if full.y > column.surface {
+                                continue;
+                            }
+                            let air = raw_chunk.get_block(local).as_deref() == Some("air");
+                            if !solid {
+                                carved_elsewhere += air as u32;
+                                continue;
+                            }
+                            assert!(!air, "{full} got carved in a biome without caves");
+                            let inside = balls.iter().any(|(center, radius)| {
+                                (full.as_vec3() + 0.5).distance_squared(*center) <= radius * radius
+                            });
+                            reached += inside as u32;
+                        }
+                    }
+                }
+            }
+        }
+        assert!(carved_elsewhere > 0, "the other biome never got carved");
+        assert!(reached > 0, "no tunnel reached the biome without caves");
+    }
+}
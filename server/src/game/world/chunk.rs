@@ -1,20 +1,27 @@
 use crate::networking::syncing::SentChunks;
 
 use super::{
-    generation::generate_chunk,
-    storage::{insert_chunk, load_chunk, WorldDatabase},
+    config::{load_world_gen_file, NewWorldOptions, WorldGenConfig},
+    generation::{TerrainGenerator, WorldGenerator},
+    pipeline::{
+        advance_generation, clear_partial_chunks, finish_chunks, load_saved_chunk, process_task,
+        ChunkStatus, ProtoChunk,
+    },
+    storage::{has_chunks, load_world_meta, save_world_meta, WorldDatabase, WorldMeta},
 };
 use bevy::{
     ecs::{schedule::ShouldRun, system::SystemParam},
     prelude::*,
-    tasks::{AsyncComputeTaskPool, Task},
     utils::FloatOrd,
 };
-use common::game::world::chunk::{
-    ChunkComp, ChunkPos, CurrentChunks, RemoveChunk, SimulationDistance, ViewDistance,
+use common::game::world::{
+    chunk::{
+        ChunkComp, ChunkPos, CurrentChunks, LoadableTypes, RemoveChunk, SimulationDistance,
+        ViewDistance,
+    },
+    registry::BlockRegistry,
 };
-use futures_lite::future;
-use rand::Rng;
+use std::sync::Arc;
 
 #[derive(Resource, Default)]
 pub struct WorldSeed(pub u32);
@@ -91,6 +98,7 @@ pub fn should_update_chunks(load_points: Query<&LoadPoint, Changed<LoadPoint>>)
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn generate_chunks_world(
     view_distance: Res<ViewDistance>,
     load_points: Query<&LoadPoint>,
@@ -98,27 +106,31 @@ pub fn generate_chunks_world(
     mut current_chunks: ResMut<CurrentChunks>,
     mut commands: Commands,
     database: Res<WorldDatabase>,
+    block_registry: Res<BlockRegistry>,
+    mut protos: Query<&mut ProtoChunk>,
 ) {
     for point in load_points.iter() {
         for x in -view_distance.horizontal..view_distance.horizontal {
             for y in -view_distance.vertical..view_distance.vertical {
                 for z in -view_distance.horizontal..view_distance.horizontal {
                     let pos = IVec3::new(x + point.0.x, y + point.0.y, z + point.0.z);
-                    if current_chunks.get_entity(pos).is_none() {
-                        let data = database.connection.lock().unwrap();
-                        if let Some(chunk) = load_chunk(pos, &data) {
-                            let chunk_id = commands
-                                .spawn(ChunkComp {
-                                    pos: ChunkPos(pos),
-                                    chunk_data: chunk,
-                                    entities: Vec::new(),
-                                    saved_entities: Vec::new(),
-                                })
-                                .id();
-                            current_chunks.insert_entity(pos, chunk_id);
-                        } else {
-                            chunk_queue.create.push(pos);
+                    if let Some(entity) = current_chunks.get_entity(pos) {
+                        // Only went part of the way for a neighbour, now it's in view it has to be finished
+                        if let Ok(mut proto) = protos.get_mut(entity) {
+                            proto.target = ChunkStatus::Full;
                         }
+                    } else if let Some(chunk) = load_saved_chunk(pos, &database, &block_registry) {
+                        let chunk_id = commands
+                            .spawn(ChunkComp {
+                                pos: ChunkPos(pos),
+                                chunk_data: chunk,
+                                entities: Vec::new(),
+                                saved_entities: Vec::new(),
+                            })
+                            .id();
+                        current_chunks.insert_entity(pos, chunk_id);
+                    } else {
+                        chunk_queue.create.push(pos);
                     }
                 }
             }
@@ -183,52 +195,75 @@ pub fn unsend_chunks(
     }
 }
 
-#[derive(Component)]
-pub struct ChunkGenTask(Task<ChunkComp>);
-
-pub fn process_task(
+// Queued chunks start generating from scratch, see pipeline for the rest
+pub fn process_queue(
     mut commands: Commands,
-    mut chunk_query: Query<(Entity, &mut ChunkGenTask)>,
-    database: Res<WorldDatabase>,
+    mut chunk_queue: ResMut<ChunkQueue>,
+    mut current_chunks: ResMut<CurrentChunks>,
 ) {
-    for (entity, mut chunk_task) in &mut chunk_query {
-        if let Some(chunk) = future::block_on(future::poll_once(&mut chunk_task.0)) {
-            let data = database.connection.lock().unwrap();
-            insert_chunk(chunk.pos.0, &chunk.chunk_data, &data);
-            commands.entity(entity).insert(chunk);
-            commands.entity(entity).remove::<ChunkGenTask>();
+    for chunk_pos in chunk_queue.create.drain(..) {
+        if current_chunks.get_entity(chunk_pos).is_none() {
+            let chunk_id = commands
+                .spawn(ProtoChunk::new(chunk_pos, ChunkStatus::Full))
+                .id();
+            current_chunks.insert_entity(chunk_pos, chunk_id);
         }
     }
 }
 
-pub fn process_queue(
-    mut commands: Commands,
-    mut chunk_queue: ResMut<ChunkQueue>,
-    mut current_chunks: ResMut<CurrentChunks>,
+pub fn update_generator(
+    mut generator: ResMut<WorldGenerator>,
     seed: Res<WorldSeed>,
+    config: Res<WorldGenConfig>,
+    loadable_types: Res<LoadableTypes>,
 ) {
-    let cloned_seed = seed.0;
-    let task_pool = AsyncComputeTaskPool::get();
-    chunk_queue
-        .create
-        .drain(..)
-        .map(|chunk_pos| {
-            (
-                chunk_pos,
-                ChunkGenTask(task_pool.spawn(async move {
-                    ChunkComp {
-                        pos: ChunkPos(chunk_pos),
-                        chunk_data: generate_chunk(chunk_pos, cloned_seed),
-                        entities: Vec::new(),
-                        saved_entities: Vec::new(),
-                    }
-                })),
-            )
-        })
-        .for_each(|(chunk_pos, chunk)| {
-            let chunk_id = commands.spawn(chunk).id();
-            current_chunks.insert_entity(chunk_pos, chunk_id);
-        });
+    if seed.is_changed() || config.is_changed() || loadable_types.is_changed() {
+        let new_generator = TerrainGenerator::new(seed.0, &config, &loadable_types);
+        if !new_generator.has_biomes() {
+            warn!("There are no biomes to generate the world with, new chunks will be empty");
+        }
+        *generator = WorldGenerator(Arc::new(new_generator));
+    }
+}
+
+// Reuses the seed and settings the world was made with. New worlds take them from the command line or the
+// world gen file and save them straight away so a restart generates the same terrain
+pub fn setup_world_meta(
+    mut commands: Commands,
+    options: Res<NewWorldOptions>,
+    database: Res<WorldDatabase>,
+) {
+    let data = database.connection.lock().unwrap();
+    let saved = load_world_meta(&data)
+        .unwrap_or_else(|error| panic!("Couldn't load the world's settings, {error}"));
+    let meta = match saved {
+        Some(meta) => {
+            if options.seed.is_some() || options.world_gen.is_some() {
+                warn!(
+                    "The world already has a seed, --seed and --world-gen only apply to new worlds"
+                );
+            }
+            meta
+        }
+        None => {
+            if has_chunks(&data) {
+                warn!(
+                    "The world was saved without its seed, new chunks won't line up with old ones"
+                );
+            }
+            let file = load_world_gen_file(options.world_gen.as_ref())
+                .unwrap_or_else(|error| panic!("Couldn't load the world gen settings {error}"));
+            let meta = WorldMeta {
+                seed: options.seed.or(file.seed).unwrap_or_else(rand::random),
+                config: file.world_gen,
+            };
+            save_world_meta(&meta, &data);
+            info!("Made a new world with seed {}", meta.seed);
+            meta
+        }
+    };
+    commands.insert_resource(WorldSeed(meta.seed));
+    commands.insert_resource(meta.config);
 }
 
 pub struct ChunkGenerationPlugin;
@@ -246,12 +281,20 @@ impl Plugin for ChunkGenerationPlugin {
                 height: 4,
                 depth: 4,
             })
-            .insert_resource(WorldSeed(rand::thread_rng().gen_range(0..u32::MAX)))
+            .init_resource::<WorldSeed>()
+            .init_resource::<WorldGenConfig>()
+            .init_resource::<NewWorldOptions>()
+            .add_startup_system(setup_world_meta)
+            .insert_resource(WorldGenerator::default())
+            .add_system(update_generator.before(process_queue))
             .add_system(clear_unloaded_chunks.with_run_criteria(should_update_chunks))
             .add_system(unsend_chunks.with_run_criteria(should_update_chunks))
             .add_system(generate_chunks_world.with_run_criteria(should_update_chunks))
+            .add_system(clear_partial_chunks.with_run_criteria(should_update_chunks))
             .add_system(process_queue.after(clear_unloaded_chunks))
-            .add_system_to_stage(CoreStage::Last, destroy_chunks)
-            .add_system(process_task);
+            .add_system(advance_generation.after(process_queue))
+            .add_system(process_task.after(advance_generation))
+            .add_system(finish_chunks.after(process_task))
+            .add_system_to_stage(CoreStage::Last, destroy_chunks);
     }
 }
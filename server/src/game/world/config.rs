@@ -0,0 +1,155 @@
+use std::{fs, path::PathBuf};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// Read when a new world gets made if it's there, --world-gen points somewhere else
+pub const WORLD_GEN_FILE: &str = "world_gen.ron";
+
+// Tunnels that wander out from an entrance. Every tunnel in a region starts at the same spot so they're all
+// connected to each other, and to the surface unless the biome there keeps its caves sealed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct TunnelConfig {
+    // Regions are squares of columns this wide that get at most one cave system each
+    pub region_size: i32,
+    pub chance: f64,
+    pub tunnels: u32,
+    // Steps of about a block
+    pub length: u32,
+    pub radius: (f32, f32),
+    // How much a tunnel turns each step, in radians
+    pub turn: f32,
+    // Tunnels stop once they get this deep
+    pub min_y: i32,
+    // Solid ground left on top of caves in biomes without entrances
+    pub cover: i32,
+}
+
+impl Default for TunnelConfig {
+    fn default() -> TunnelConfig {
+        TunnelConfig {
+            region_size: 64,
+            chance: 0.7,
+            tunnels: 3,
+            length: 120,
+            radius: (1.5, 3.5),
+            turn: 0.3,
+            min_y: -160,
+            cover: 4,
+        }
+    }
+}
+
+// Big open chambers from 3D noise, only deep down so they don't swallow the surface. They aren't joined up
+// with tunnels, a cavern no tunnel happens to pass through stays sealed off
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct CavernConfig {
+    pub frequency: f64,
+    // Higher means smaller and rarer caverns, noise goes from about -1 to 1
+    pub threshold: f64,
+    // Caverns start under this height and grow to full size over fade blocks
+    pub max_y: i32,
+    pub fade: i32,
+    // Squashes caverns so they're wider than they're tall
+    pub flatten: f64,
+}
+
+impl Default for CavernConfig {
+    fn default() -> CavernConfig {
+        CavernConfig {
+            frequency: 0.02,
+            threshold: 0.45,
+            max_y: -40,
+            fade: 32,
+            flatten: 2.0,
+        }
+    }
+}
+
+// Blobs of an ore between two heights. They only replace stone, never dirt or the surface
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OreVein {
+    pub block: String,
+    pub min_y: i32,
+    pub max_y: i32,
+    pub frequency: f64,
+    pub threshold: f64,
+}
+
+// Stone gets swapped for this block below max_y, the boundary is a bit uneven so it doesn't look like a line
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StoneLayer {
+    pub block: String,
+    pub max_y: i32,
+}
+
+// Everything about generation that isn't a biome or a structure. Blocks are full ids like "vinox:coal_ore"
+#[derive(Resource, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct WorldGenConfig {
+    pub tunnels: TunnelConfig,
+    pub caverns: CavernConfig,
+    pub ores: Vec<OreVein>,
+    pub stone_layers: Vec<StoneLayer>,
+}
+
+impl Default for WorldGenConfig {
+    fn default() -> WorldGenConfig {
+        WorldGenConfig {
+            tunnels: TunnelConfig::default(),
+            caverns: CavernConfig::default(),
+            ores: vec![
+                OreVein {
+                    block: "vinox:coal_ore".to_string(),
+                    min_y: -96,
+                    max_y: 64,
+                    frequency: 0.12,
+                    threshold: 0.55,
+                },
+                OreVein {
+                    block: "vinox:iron_ore".to_string(),
+                    min_y: -192,
+                    max_y: -16,
+                    frequency: 0.15,
+                    threshold: 0.6,
+                },
+            ],
+            stone_layers: vec![StoneLayer {
+                block: "vinox:deepstone".to_string(),
+                max_y: -64,
+            }],
+        }
+    }
+}
+
+// From the command line, only used when a new world gets made
+#[derive(Resource, Default, Debug, Clone)]
+pub struct NewWorldOptions {
+    pub seed: Option<u32>,
+    pub world_gen: Option<PathBuf>,
+}
+
+// The world gen file, eg `(seed: Some(1234), world_gen: (tunnels: (chance: 0.9)))`. Anything left out gets
+// its default and without a seed one is picked at random. --seed beats the one in here
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct WorldGenFile {
+    pub seed: Option<u32>,
+    pub world_gen: WorldGenConfig,
+}
+
+// A missing file is only fine if it wasn't asked for
+pub fn load_world_gen_file(path: Option<&PathBuf>) -> Result<WorldGenFile, String> {
+    let file = path
+        .cloned()
+        .unwrap_or_else(|| PathBuf::from(WORLD_GEN_FILE));
+    match fs::read_to_string(&file) {
+        Ok(source) => {
+            ron::from_str(&source).map_err(|error| format!("{}: {error}", file.display()))
+        }
+        Err(_) if path.is_none() => Ok(WorldGenFile::default()),
+        Err(error) => Err(format!("{}: {error}", file.display())),
+    }
+}
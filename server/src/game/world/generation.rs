@@ -1,72 +1,462 @@
+use std::{collections::HashMap, sync::Arc};
+
 use bevy::prelude::*;
-use common::game::world::chunk::*;
-use noise::{BasicMulti, MultiFractal, NoiseFn, OpenSimplex, RidgedMulti};
-
-// Just some interesting stuff to look at while testing
-pub fn add_grass(raw_chunk: &mut RawChunk) {
-    for x in 1..=CHUNK_SIZE {
-        for z in 1..=CHUNK_SIZE {
-            for y in 1..=CHUNK_SIZE {
-                if raw_chunk.get_block(UVec3::new(x, y + 1, z)).unwrap() == "air"
-                    && raw_chunk.get_block(UVec3::new(x, y, z)).unwrap() == "vinoxcobblestone"
-                {
-                    raw_chunk.add_block_state(&"vinoxgrass".to_string());
-                    raw_chunk.set_block(UVec3::new(x, y, z), "vinoxgrass".to_string());
-                    raw_chunk.add_block_state(&"vinoxdirt".to_string());
-                    raw_chunk.set_block(UVec3::new(x, y - 1, z), "vinoxdirt".to_string());
+use common::{
+    game::{
+        scripting::biome::biome_descriptor::{BiomeDescriptor, HeightNoise},
+        world::chunk::*,
+    },
+    networking::content::hash_bytes,
+};
+use noise::{Fbm, MultiFractal, NoiseFn, OpenSimplex, RidgedMulti};
+
+use super::{
+    cave::carve_caves,
+    config::WorldGenConfig,
+    structure::{place_structures, Structure},
+};
+
+// Biomes are picked on a grid this far apart and blended over this distance so the ground doesn't jump
+// at their borders
+const BLEND_STEP: i32 = 4;
+const BLEND_RADIUS: i32 = 8;
+// Temperature and humidity change slowly so biomes end up a few hundred blocks across
+const CLIMATE_FREQUENCY: f64 = 0.002;
+const SELECTOR_FREQUENCY: f64 = 0.004;
+
+enum Terrain {
+    Ridged(RidgedMulti<OpenSimplex>),
+    Smooth(Fbm<OpenSimplex>),
+}
+
+impl Terrain {
+    fn new(seed: u32, height: &HeightNoise) -> Terrain {
+        if height.ridged {
+            Terrain::Ridged(
+                RidgedMulti::new(seed)
+                    .set_octaves(height.octaves)
+                    .set_frequency(height.frequency),
+            )
+        } else {
+            Terrain::Smooth(
+                Fbm::new(seed)
+                    .set_octaves(height.octaves)
+                    .set_frequency(height.frequency),
+            )
+        }
+    }
+
+    fn get(&self, x: f64, z: f64) -> f64 {
+        match self {
+            Terrain::Ridged(noise) => noise.get([x, z]),
+            Terrain::Smooth(noise) => noise.get([x, z]),
+        }
+    }
+}
+
+struct Biome {
+    id: String,
+    descriptor: BiomeDescriptor,
+    terrain: Terrain,
+    // Where the biome sits on the selector noise, the closest one wins when more than one fits the climate
+    selector: f64,
+}
+
+impl Biome {
+    fn height(&self, x: i32, z: i32) -> f64 {
+        let height = &self.descriptor.height;
+        height.base + self.terrain.get(x as f64, z as f64) * height.amplitude
+    }
+
+    // How far the climate is from this biome's ranges, 0 inside them
+    fn climate_distance(&self, temperature: f64, humidity: f64) -> f64 {
+        let outside = |(min, max): (f64, f64), value: f64| (min - value).max(value - max).max(0.0);
+        outside(self.descriptor.temperature, temperature)
+            .hypot(outside(self.descriptor.humidity, humidity))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Column {
+    // Index into the generator's biomes
+    pub biome: usize,
+    // Height of the top block
+    pub surface: i32,
+}
+
+// Mixes bits well enough to scatter things around, same input always gives the same output
+pub fn scramble(mut value: u64) -> u64 {
+    value = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}
+
+// Everything needed to generate chunks for one seed, config and set of biomes. Chunks only depend on this and
+// their position so the same seed always gives the same world
+pub struct TerrainGenerator {
+    seed: u32,
+    config: WorldGenConfig,
+    biomes: Vec<Biome>,
+    structures: Vec<Structure>,
+    temperature: Fbm<OpenSimplex>,
+    humidity: Fbm<OpenSimplex>,
+    selector: Fbm<OpenSimplex>,
+    caverns: Fbm<OpenSimplex>,
+    // One noise per ore in the config
+    ores: Vec<Fbm<OpenSimplex>>,
+}
+
+impl TerrainGenerator {
+    // Biomes, structures, ores and stone layers using blocks that don't exist are left out
+    pub fn new(
+        seed: u32,
+        config: &WorldGenConfig,
+        loadable_types: &LoadableTypes,
+    ) -> TerrainGenerator {
+        let mut ids: Vec<&String> = loadable_types.biomes.keys().collect();
+        // Sorted so the selector values don't depend on hash map order
+        ids.sort();
+        let mut biomes = Vec::new();
+        for id in ids {
+            let descriptor = &loadable_types.biomes[id];
+            let missing: Vec<&str> = [
+                &descriptor.surface_block,
+                &descriptor.subsurface_block,
+                &descriptor.stone_block,
+            ]
+            .into_iter()
+            .filter(|block| !loadable_types.blocks.contains_key(*block))
+            .map(String::as_str)
+            .collect();
+            if !missing.is_empty() {
+                warn!("Biome {id} uses missing blocks {}", missing.join(", "));
+                continue;
+            }
+            let hash = hash_bytes(format!("{seed}:{id}").as_bytes());
+            biomes.push(Biome {
+                id: id.clone(),
+                descriptor: descriptor.clone(),
+                terrain: Terrain::new(seed, &descriptor.height),
+                selector: (hash % 2001) as f64 / 1000.0 - 1.0,
+            });
+        }
+        let mut structure_ids: Vec<&String> = loadable_types.structures.keys().collect();
+        structure_ids.sort();
+        let structures = structure_ids
+            .into_iter()
+            .filter_map(|id| {
+                Structure::new(id, &loadable_types.structures[id], &loadable_types.blocks)
+            })
+            .collect();
+
+        let mut config = config.clone();
+        let exists = |kind: &str, block: &String| {
+            let exists = loadable_types.blocks.contains_key(block);
+            if !exists {
+                warn!("{kind} uses missing block {block}");
+            }
+            exists
+        };
+        config.ores.retain(|ore| exists("Ore vein", &ore.block));
+        config
+            .stone_layers
+            .retain(|layer| exists("Stone layer", &layer.block));
+        // Deepest last so it wins where layers overlap
+        config
+            .stone_layers
+            .sort_by_key(|layer| std::cmp::Reverse(layer.max_y));
+        let ores = config
+            .ores
+            .iter()
+            .map(|ore| {
+                let hash = hash_bytes(format!("{seed}:{}", ore.block).as_bytes());
+                Fbm::new(hash as u32)
+                    .set_octaves(2)
+                    .set_frequency(ore.frequency)
+            })
+            .collect();
+        let caverns = Fbm::new(seed.wrapping_add(4))
+            .set_octaves(3)
+            .set_frequency(config.caverns.frequency);
+
+        TerrainGenerator {
+            seed,
+            config,
+            biomes,
+            structures,
+            temperature: Fbm::new(seed.wrapping_add(1))
+                .set_octaves(2)
+                .set_frequency(CLIMATE_FREQUENCY),
+            humidity: Fbm::new(seed.wrapping_add(2))
+                .set_octaves(2)
+                .set_frequency(CLIMATE_FREQUENCY),
+            selector: Fbm::new(seed.wrapping_add(3))
+                .set_octaves(1)
+                .set_frequency(SELECTOR_FREQUENCY),
+            caverns,
+            ores,
+        }
+    }
+
+    pub fn has_biomes(&self) -> bool {
+        !self.biomes.is_empty()
+    }
+
+    pub fn seed(&self) -> u32 {
+        self.seed
+    }
+
+    pub fn config(&self) -> &WorldGenConfig {
+        &self.config
+    }
+
+    pub fn structures(&self) -> &[Structure] {
+        &self.structures
+    }
+
+    pub fn biome(&self, index: usize) -> &BiomeDescriptor {
+        &self.biomes[index].descriptor
+    }
+
+    // Biomes whose ranges fit the climate here, or the closest ones if none do. If that's still more than
+    // one the selector noise picks between them
+    fn biome_at(&self, x: i32, z: i32) -> usize {
+        let point = [x as f64, z as f64];
+        let temperature = self.temperature.get(point);
+        let humidity = self.humidity.get(point);
+        let selector = self.selector.get(point);
+        let distances: Vec<f64> = self
+            .biomes
+            .iter()
+            .map(|biome| biome.climate_distance(temperature, humidity))
+            .collect();
+        let closest = distances.iter().cloned().fold(f64::INFINITY, f64::min);
+        (0..self.biomes.len())
+            .filter(|index| distances[*index] <= closest)
+            .min_by(|a, b| {
+                let a = (self.biomes[*a].selector - selector).abs();
+                let b = (self.biomes[*b].selector - selector).abs();
+                a.total_cmp(&b)
+            })
+            .unwrap_or_default()
+    }
+
+    // The biome of a column and its height blended with every biome on the grid around it. Grid points are
+    // in world space so a column blends the same no matter which chunk asks
+    pub fn column(&self, x: i32, z: i32, grid: &mut HashMap<IVec2, usize>) -> Column {
+        let reach = BLEND_RADIUS + BLEND_STEP;
+        let mut weights = vec![0.0; self.biomes.len()];
+        for grid_x in (x - reach).div_euclid(BLEND_STEP)..=(x + reach).div_euclid(BLEND_STEP) {
+            for grid_z in (z - reach).div_euclid(BLEND_STEP)..=(z + reach).div_euclid(BLEND_STEP) {
+                let point = IVec2::new(grid_x, grid_z) * BLEND_STEP;
+                let weight =
+                    (reach - (point.x - x).abs()).max(0) * (reach - (point.y - z).abs()).max(0);
+                if weight == 0 {
+                    continue;
                 }
+                let biome = *grid
+                    .entry(point)
+                    .or_insert_with(|| self.biome_at(point.x, point.y));
+                weights[biome] += weight as f64;
             }
         }
+        let total: f64 = weights.iter().sum();
+        let height = weights
+            .iter()
+            .enumerate()
+            .filter(|(_, weight)| **weight > 0.0)
+            .map(|(biome, weight)| self.biomes[biome].height(x, z) * weight)
+            .sum::<f64>()
+            / total;
+        Column {
+            biome: self.biome_at(x, z),
+            surface: height.floor() as i32,
+        }
+    }
+
+    // The biome id of a column and the height of its top block
+    pub fn surface(&self, x: i32, z: i32, grid: &mut HashMap<IVec2, usize>) -> (&str, i32) {
+        let column = self.column(x, z, grid);
+        (&self.biomes[column.biome].id, column.surface)
     }
-}
 
-pub fn generate_chunk(pos: IVec3, seed: u32) -> RawChunk {
-    //TODO: Switch to using ron files to determine biomes and what blocks they should use. For now hardcoding a simplex noise
-    let ridged_noise: RidgedMulti<OpenSimplex> =
-        RidgedMulti::new(seed).set_octaves(8).set_frequency(0.25);
-    let basic_noise: BasicMulti<OpenSimplex> =
-        BasicMulti::new(seed).set_octaves(2).set_frequency(0.5);
-    let mut raw_chunk = RawChunk::new();
-    for x in 1..=CHUNK_SIZE {
-        for z in 1..=CHUNK_SIZE {
-            for y in 1..=CHUNK_SIZE {
-                let full_x = x as i32 + ((CHUNK_SIZE as i32) * pos.x);
-                let full_z = z as i32 + ((CHUNK_SIZE as i32) * pos.z);
-                let full_y = y as i32 + ((CHUNK_SIZE as i32) * pos.y);
-                let noise_val =
-                    ridged_noise.get([(full_x as f64 / 100.0), (full_z as f64 / 100.0)]) * 100.0;
-                if full_y as f64 <= noise_val {
-                    raw_chunk.add_block_state(&"vinoxcobblestone".to_string());
-                    raw_chunk.set_block(UVec3::new(x, y, z), "vinoxcobblestone".to_string());
-                } else {
-                    raw_chunk.set_block(UVec3::new(x, y, z), "air".to_string());
+    // Cavern noise at a position, already squashed and faded out towards the top
+    pub fn cavern(&self, pos: IVec3) -> f64 {
+        let caverns = &self.config.caverns;
+        if pos.y > caverns.max_y {
+            return 0.0;
+        }
+        let fade = ((caverns.max_y - pos.y) as f64 / caverns.fade.max(1) as f64).min(1.0);
+        self.caverns
+            .get([pos.x as f64, pos.y as f64 * caverns.flatten, pos.z as f64])
+            * fade
+    }
+
+    // What stone turns into at this depth, ore veins included
+    fn stone<'a>(&'a self, biome: &'a BiomeDescriptor, pos: IVec3) -> &'a String {
+        for (ore, noise) in self.config.ores.iter().zip(self.ores.iter()) {
+            if pos.y >= ore.min_y
+                && pos.y <= ore.max_y
+                && noise.get([pos.x as f64, pos.y as f64, pos.z as f64]) > ore.threshold
+            {
+                return &ore.block;
+            }
+        }
+        // Up to two blocks either way so layers don't meet in a flat line
+        let column =
+            ((self.seed as u64) << 32) ^ ((pos.x as u32 as u64) << 16) ^ pos.z as u32 as u64;
+        let jitter = (scramble(column) % 5) as i32 - 2;
+        self.config
+            .stone_layers
+            .iter()
+            .rev()
+            .find(|layer| pos.y < layer.max_y + jitter)
+            .map(|layer| &layer.block)
+            .unwrap_or(&biome.stone_block)
+    }
+
+    // Every column of a chunk, x major. Empty without any biomes
+    pub fn columns(&self, pos: IVec3, grid: &mut HashMap<IVec2, usize>) -> Vec<Column> {
+        let chunk_min = pos * CHUNK_SIZE as i32;
+        let mut columns = Vec::with_capacity((CHUNK_SIZE * CHUNK_SIZE) as usize);
+        if self.biomes.is_empty() {
+            return columns;
+        }
+        for x in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                columns.push(self.column(chunk_min.x + x as i32, chunk_min.z + z as i32, grid));
+            }
+        }
+        columns
+    }
+
+    // First stage, stone up to the height of the ground with ores and deeper layers mixed in. Every stage
+    // takes the chunk's columns so they only get worked out once
+    pub fn terrain(&self, pos: IVec3, columns: &[Column]) -> RawChunk {
+        let mut raw_chunk = RawChunk::new();
+        if self.biomes.is_empty() {
+            return raw_chunk;
+        }
+        let chunk_min = pos * CHUNK_SIZE as i32;
+        for x in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                let column = columns[(x * CHUNK_SIZE + z) as usize];
+                let biome = &self.biomes[column.biome].descriptor;
+                for y in 0..CHUNK_SIZE {
+                    let full = chunk_min + UVec3::new(x, y, z).as_ivec3();
+                    if full.y > column.surface {
+                        continue;
+                    }
+                    let block = self.stone(biome, full);
+                    raw_chunk.add_block_state(block);
+                    raw_chunk.set_block(UVec3::new(x, y, z), block.clone());
                 }
+            }
+        }
+        raw_chunk
+    }
 
-                let density = basic_noise.get([
-                    (full_x as f64 / 5.0),
-                    (full_y as f64 / 5.0),
-                    (full_z as f64 / 5.0),
-                ]) / 16.0;
+    pub fn carve(&self, raw_chunk: &mut RawChunk, pos: IVec3, columns: &[Column]) {
+        if self.biomes.is_empty() {
+            return;
+        }
+        carve_caves(self, raw_chunk, pos, columns, &mut HashMap::new());
+    }
 
-                if (density * (get_value_at_height(full_y) * 2.0)) >= 0.01 {
-                    raw_chunk.set_block(UVec3::new(x, y, z), "air".to_string());
+    // The top blocks of the ground turn into the biome's surface and subsurface blocks. Carving can leave a
+    // gap in those, whatever is under the gap starts over with a surface block. The bottom of the carved
+    // chunk above decides what goes at the top of this one, without it the ground above counts as solid
+    pub fn surface_blocks(
+        &self,
+        raw_chunk: &mut RawChunk,
+        pos: IVec3,
+        columns: &[Column],
+        above: Option<&RawChunk>,
+    ) {
+        if self.biomes.is_empty() {
+            return;
+        }
+        let chunk_min = pos * CHUNK_SIZE as i32;
+        for x in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                let column = columns[(x * CHUNK_SIZE + z) as usize];
+                let biome = &self.biomes[column.biome].descriptor;
+                let bottom = column.surface - biome.subsurface_depth as i32;
+                if column.surface < chunk_min.y || bottom >= chunk_min.y + CHUNK_SIZE as i32 {
+                    continue;
+                }
+                // Solid blocks in a row since the last air
+                let mut depth = None;
+                for y in (bottom..=column.surface).rev() {
+                    let local_y = y - chunk_min.y;
+                    let block = if local_y >= CHUNK_SIZE as i32 {
+                        let local = UVec3::new(x, (local_y - CHUNK_SIZE as i32) as u32, z);
+                        above.and_then(|above| above.get_block(local))
+                    } else if local_y >= 0 {
+                        raw_chunk.get_block(UVec3::new(x, local_y as u32, z))
+                    } else {
+                        break;
+                    };
+                    if block.as_deref() == Some("air") {
+                        depth = None;
+                        continue;
+                    }
+                    let block_depth = depth.map_or(0, |depth| depth + 1);
+                    depth = Some(block_depth);
+                    if (0..CHUNK_SIZE as i32).contains(&local_y) {
+                        let block = if block_depth == 0 {
+                            &biome.surface_block
+                        } else {
+                            &biome.subsurface_block
+                        };
+                        raw_chunk.add_block_state(block);
+                        raw_chunk.set_block(UVec3::new(x, local_y as u32, z), block.clone());
+                    }
                 }
             }
         }
     }
-    add_grass(&mut raw_chunk);
-    raw_chunk
-}
 
-fn get_value_at_height(pos: i32) -> f64 {
-    let max_height = 96;
-    let min_height = -128;
-    let mut val = pos;
-    if pos > max_height {
-        val = max_height;
-    } else if pos < min_height {
-        val = min_height;
+    pub fn add_structures(&self, raw_chunk: &mut RawChunk, pos: IVec3) {
+        if self.biomes.is_empty() {
+            return;
+        }
+        place_structures(self, raw_chunk, pos, &mut HashMap::new());
+        raw_chunk.compact();
     }
 
-    ((val as f64 - min_height as f64) / (max_height as f64 - min_height as f64)) * (1.0 - 0.0) + 0.0
+    // The chunk above as it is right after carving, for the surface stage. It shares the columns so it can
+    // always be made again, no matter what's happened to the real one since
+    pub fn carved_above(&self, pos: IVec3, columns: &[Column]) -> RawChunk {
+        let mut above = self.terrain(pos + IVec3::Y, columns);
+        self.carve(&mut above, pos + IVec3::Y, columns);
+        above
+    }
+
+    // Every stage in one go, without waiting on neighbours. Only the surface looks outside the chunk so the
+    // one above gets generated up to carving
+    pub fn generate_chunk(&self, pos: IVec3) -> RawChunk {
+        let columns = self.columns(pos, &mut HashMap::new());
+        let above = self.carved_above(pos, &columns);
+        let mut raw_chunk = self.terrain(pos, &columns);
+        self.carve(&mut raw_chunk, pos, &columns);
+        self.surface_blocks(&mut raw_chunk, pos, &columns, Some(&above));
+        self.add_structures(&mut raw_chunk, pos);
+        raw_chunk
+    }
+}
+
+// Shared with the generation tasks, rebuilt when the seed or the biomes change
+#[derive(Resource, Clone)]
+pub struct WorldGenerator(pub Arc<TerrainGenerator>);
+
+impl Default for WorldGenerator {
+    fn default() -> WorldGenerator {
+        WorldGenerator(Arc::new(TerrainGenerator::new(
+            0,
+            &WorldGenConfig::default(),
+            &LoadableTypes::default(),
+        )))
+    }
 }
@@ -1,3 +1,7 @@
+pub mod cave;
 pub mod chunk;
+pub mod config;
 pub mod generation;
+pub mod pipeline;
 pub mod storage;
+pub mod structure;
@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+
+use bevy::{
+    prelude::*,
+    tasks::{AsyncComputeTaskPool, Task},
+};
+use common::game::world::{
+    chunk::{ChunkComp, ChunkPos, CurrentChunks, RawChunk, ViewDistance},
+    registry::BlockRegistry,
+};
+use futures_lite::future;
+
+use super::{
+    chunk::LoadPoint,
+    generation::{Column, TerrainGenerator, WorldGenerator},
+    storage::{insert_chunk, load_chunk, WorldDatabase},
+};
+
+// The last stage a chunk has been through, in the order they run
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ChunkStatus {
+    Empty,
+    Terrain,
+    Carved,
+    Surface,
+    Structures,
+    // The only status that gets sent to players or saved. Light is left to the client, it works it out for
+    // every chunk it gets anyway
+    Full,
+}
+
+impl ChunkStatus {
+    pub fn next(self) -> ChunkStatus {
+        match self {
+            ChunkStatus::Empty => ChunkStatus::Terrain,
+            ChunkStatus::Terrain => ChunkStatus::Carved,
+            ChunkStatus::Carved => ChunkStatus::Surface,
+            ChunkStatus::Surface => ChunkStatus::Structures,
+            ChunkStatus::Structures | ChunkStatus::Full => ChunkStatus::Full,
+        }
+    }
+
+    // What all 26 chunks around have to be at before the next stage can run. Terrain doesn't look at
+    // neighbours so it can always go, and a chunk with its structures is done
+    pub fn prerequisite(self) -> Option<ChunkStatus> {
+        match self {
+            ChunkStatus::Empty | ChunkStatus::Structures | ChunkStatus::Full => None,
+            status => Some(status),
+        }
+    }
+}
+
+// Everything a chunk carries between stages
+pub struct ProtoData {
+    pub raw_chunk: RawChunk,
+    pub columns: Vec<Column>,
+}
+
+// A chunk that's still being generated. It only turns into a ChunkComp once it's full so nothing else in the
+// server ever sees half a chunk
+#[derive(Component)]
+pub struct ProtoChunk {
+    pub pos: IVec3,
+    // Empty before terrain and while a stage is running
+    pub data: Option<ProtoData>,
+    pub status: ChunkStatus,
+    // How far it needs to go. Chunks around one being generated only get as far as it needs them to
+    pub target: ChunkStatus,
+    // Came out of the database so there's nothing new to save
+    pub saved: bool,
+}
+
+impl ProtoChunk {
+    pub fn new(pos: IVec3, target: ChunkStatus) -> ProtoChunk {
+        ProtoChunk {
+            pos,
+            data: None,
+            status: ChunkStatus::Empty,
+            target,
+            saved: false,
+        }
+    }
+
+    // Saved chunks are always full
+    pub fn saved(pos: IVec3, raw_chunk: RawChunk, target: ChunkStatus) -> ProtoChunk {
+        ProtoChunk {
+            pos,
+            data: Some(ProtoData {
+                raw_chunk,
+                columns: Vec::new(),
+            }),
+            status: ChunkStatus::Full,
+            target,
+            saved: true,
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct ChunkGenTask(Task<ProtoData>);
+
+// Loads a chunk from the database, renaming or dropping blocks the registry doesn't know anymore
+pub fn load_saved_chunk(
+    pos: IVec3,
+    database: &WorldDatabase,
+    block_registry: &BlockRegistry,
+) -> Option<RawChunk> {
+    let data = database.connection.lock().unwrap();
+    let mut chunk = load_chunk(pos, &data)?;
+    if chunk.remap_blocks(block_registry) {
+        insert_chunk(pos, &chunk, &data);
+    }
+    Some(chunk)
+}
+
+fn run_stage(
+    generator: &TerrainGenerator,
+    pos: IVec3,
+    stage: ChunkStatus,
+    data: Option<ProtoData>,
+) -> ProtoData {
+    if stage == ChunkStatus::Terrain {
+        let columns = generator.columns(pos, &mut HashMap::new());
+        return ProtoData {
+            raw_chunk: generator.terrain(pos, &columns),
+            columns,
+        };
+    }
+    let mut data = data.expect("Only terrain starts without any data");
+    match stage {
+        ChunkStatus::Carved => generator.carve(&mut data.raw_chunk, pos, &data.columns),
+        ChunkStatus::Surface => {
+            // The real chunk above could already have structures or edits in it
+            let above = generator.carved_above(pos, &data.columns);
+            generator.surface_blocks(&mut data.raw_chunk, pos, &data.columns, Some(&above));
+        }
+        ChunkStatus::Structures => generator.add_structures(&mut data.raw_chunk, pos),
+        ChunkStatus::Empty | ChunkStatus::Terrain | ChunkStatus::Full => {}
+    }
+    data
+}
+
+// Starts the next stage on every chunk whose neighbours are far enough along. Neighbours that aren't get
+// their target raised, ones that don't exist yet get loaded from the database or start generating
+#[allow(clippy::too_many_arguments)]
+pub fn advance_generation(
+    mut commands: Commands,
+    mut current_chunks: ResMut<CurrentChunks>,
+    mut protos: Query<(Entity, &mut ProtoChunk, Option<&ChunkGenTask>)>,
+    chunks: Query<&ChunkComp>,
+    generator: Res<WorldGenerator>,
+    block_registry: Res<BlockRegistry>,
+    database: Res<WorldDatabase>,
+) {
+    // Anything in current chunks that isn't a proto or a chunk was spawned this frame and isn't ready yet
+    let status_of = |pos: IVec3| -> Option<ChunkStatus> {
+        let entity = current_chunks.get_entity(pos)?;
+        if let Ok((_, proto, _)) = protos.get(entity) {
+            Some(proto.status)
+        } else if chunks.get(entity).is_ok() {
+            Some(ChunkStatus::Full)
+        } else {
+            Some(ChunkStatus::Empty)
+        }
+    };
+
+    let mut requests: Vec<(IVec3, ChunkStatus)> = Vec::new();
+    let mut ready = Vec::new();
+    for (entity, proto, task) in protos.iter() {
+        if task.is_some() || proto.status >= proto.target {
+            continue;
+        }
+        let mut waiting = false;
+        if let Some(prerequisite) = proto.status.prerequisite() {
+            for x in -1..=1 {
+                for y in -1..=1 {
+                    for z in -1..=1 {
+                        let pos = proto.pos + IVec3::new(x, y, z);
+                        if pos == proto.pos {
+                            continue;
+                        }
+                        match status_of(pos) {
+                            Some(status) if status >= prerequisite => {}
+                            _ => {
+                                waiting = true;
+                                requests.push((pos, prerequisite));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        if !waiting {
+            ready.push(entity);
+        }
+    }
+
+    let task_pool = AsyncComputeTaskPool::get();
+    for entity in ready {
+        let (_, mut proto, _) = protos.get_mut(entity).unwrap();
+        let pos = proto.pos;
+        let stage = proto.status.next();
+        let data = proto.data.take();
+        let generator = generator.0.clone();
+        commands.entity(entity).insert(ChunkGenTask(
+            task_pool.spawn(async move { run_stage(&generator, pos, stage, data) }),
+        ));
+    }
+
+    for (pos, target) in requests {
+        match current_chunks.get_entity(pos) {
+            Some(entity) => {
+                if let Ok((_, mut proto, _)) = protos.get_mut(entity) {
+                    proto.target = proto.target.max(target);
+                }
+            }
+            None => {
+                let proto = match load_saved_chunk(pos, &database, &block_registry) {
+                    Some(raw_chunk) => ProtoChunk::saved(pos, raw_chunk, target),
+                    None => ProtoChunk::new(pos, target),
+                };
+                let entity = commands.spawn(proto).id();
+                current_chunks.insert_entity(pos, entity);
+            }
+        }
+    }
+}
+
+pub fn process_task(
+    mut commands: Commands,
+    mut chunk_query: Query<(Entity, &mut ChunkGenTask, &mut ProtoChunk)>,
+) {
+    for (entity, mut chunk_task, mut proto) in &mut chunk_query {
+        if let Some(data) = future::block_on(future::poll_once(&mut chunk_task.0)) {
+            proto.data = Some(data);
+            proto.status = proto.status.next();
+            commands.entity(entity).remove::<ChunkGenTask>();
+        }
+    }
+}
+
+// Chunks that made it through every stage join the world, this is the only place generated chunks get saved
+pub fn finish_chunks(
+    mut commands: Commands,
+    mut protos: Query<(Entity, &mut ProtoChunk), Without<ChunkGenTask>>,
+    database: Res<WorldDatabase>,
+) {
+    for (entity, mut proto) in protos.iter_mut() {
+        if proto.status != ChunkStatus::Full || proto.target != ChunkStatus::Full {
+            continue;
+        }
+        let Some(data) = proto.data.take() else {
+            continue;
+        };
+        if !proto.saved {
+            let connection = database.connection.lock().unwrap();
+            insert_chunk(proto.pos, &data.raw_chunk, &connection);
+        }
+        commands
+            .entity(entity)
+            .remove::<ProtoChunk>()
+            .insert(ChunkComp {
+                pos: ChunkPos(proto.pos),
+                chunk_data: data.raw_chunk,
+                entities: Vec::new(),
+                saved_entities: Vec::new(),
+            });
+    }
+}
+
+// Chunks only generated part of the way for their neighbours get dropped once no load point is close enough
+// to need them. Generation always gives the same result so they can just be made again
+pub fn clear_partial_chunks(
+    mut commands: Commands,
+    mut current_chunks: ResMut<CurrentChunks>,
+    protos: Query<(Entity, &ProtoChunk), Without<ChunkGenTask>>,
+    load_points: Query<&LoadPoint>,
+    view_distance: Res<ViewDistance>,
+) {
+    // A chunk in view can need chunks one further out for each stage that looks at its neighbours
+    let margin = 3;
+    for (entity, proto) in protos.iter() {
+        if proto.target == ChunkStatus::Full {
+            continue;
+        }
+        let needed = load_points.iter().any(|load_point| {
+            load_point.is_in_radius(
+                proto.pos,
+                IVec2::new(
+                    -view_distance.horizontal - margin,
+                    -view_distance.vertical - margin,
+                ),
+                IVec2::new(
+                    view_distance.horizontal + margin,
+                    view_distance.vertical + margin,
+                ),
+            )
+        });
+        if !needed {
+            current_chunks.remove_entity(proto.pos);
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use bevy::{
+        prelude::*,
+        tasks::{AsyncComputeTaskPool, TaskPool},
+    };
+    use common::game::world::{
+        chunk::{ChunkComp, CurrentChunks},
+        registry::BlockRegistry,
+    };
+    use rusqlite::Connection;
+
+    use super::{advance_generation, finish_chunks, process_task, ChunkStatus, ProtoChunk};
+    use crate::game::world::{
+        generation::WorldGenerator,
+        storage::{create_database, load_chunk, WorldDatabase},
+    };
+
+    // Just the generation systems and a database in memory
+    pub fn generation_app(generator: WorldGenerator) -> App {
+        AsyncComputeTaskPool::init(TaskPool::new);
+        let connection = Connection::open_in_memory().unwrap();
+        create_database(&connection);
+        let mut app = App::new();
+        app.insert_resource(CurrentChunks::default())
+            .insert_resource(generator)
+            .insert_resource(BlockRegistry::default())
+            .insert_resource(WorldDatabase {
+                name: "test".to_string(),
+                connection: Arc::new(Mutex::new(connection)),
+            })
+            .add_system(advance_generation)
+            .add_system(process_task.after(advance_generation))
+            .add_system(finish_chunks.after(process_task));
+        app
+    }
+
+    // Asks for full chunks the same way generate_chunks_world does and runs until they're done
+    pub fn generate(app: &mut App, positions: &[IVec3]) -> Vec<Entity> {
+        let entities: Vec<Entity> = positions
+            .iter()
+            .map(|pos| {
+                let existing = app.world.resource::<CurrentChunks>().get_entity(*pos);
+                match existing {
+                    Some(entity) => {
+                        if let Some(mut proto) = app.world.get_mut::<ProtoChunk>(entity) {
+                            proto.target = ChunkStatus::Full;
+                        }
+                        entity
+                    }
+                    None => {
+                        let entity = app
+                            .world
+                            .spawn(ProtoChunk::new(*pos, ChunkStatus::Full))
+                            .id();
+                        app.world
+                            .resource_mut::<CurrentChunks>()
+                            .insert_entity(*pos, entity);
+                        entity
+                    }
+                }
+            })
+            .collect();
+        // Stages run on other threads so give them time instead of counting updates
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(300) {
+            if entities
+                .iter()
+                .all(|entity| app.world.get::<ChunkComp>(*entity).is_some())
+            {
+                return entities;
+            }
+            app.update();
+            thread::sleep(Duration::from_millis(1));
+        }
+        panic!("chunks never finished generating");
+    }
+
+    #[test]
+    fn neighbours_gate_stages() {
+        // No biomes so every stage is quick
+        let mut app = generation_app(WorldGenerator::default());
+        generate(&mut app, &[IVec3::ZERO]);
+
+        // Each ring out only needed to get one stage less far
+        let mut protos = app.world.query::<&ProtoChunk>();
+        assert_eq!(protos.iter(&app.world).count(), 7 * 7 * 7 - 1);
+        for proto in protos.iter(&app.world) {
+            let distance = proto.pos.abs().max_element();
+            let expected = match distance {
+                1 => ChunkStatus::Surface,
+                2 => ChunkStatus::Carved,
+                _ => ChunkStatus::Terrain,
+            };
+            assert_eq!(proto.status, expected, "{}", proto.pos);
+        }
+
+        let database = app.world.resource::<WorldDatabase>();
+        let connection = database.connection.lock().unwrap();
+        assert!(load_chunk(IVec3::ZERO, &connection).is_some());
+        assert!(load_chunk(IVec3::X, &connection).is_none());
+    }
+}
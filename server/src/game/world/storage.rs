@@ -1,19 +1,26 @@
 use std::{
+    collections::HashMap,
     io::Cursor,
     sync::{Arc, Mutex},
 };
 
 use bevy::prelude::*;
-use common::game::world::chunk::RawChunk;
+use common::game::world::{chunk::RawChunk, registry::BlockRegistry};
 use rusqlite::*;
 use zstd::stream::{copy_decode, copy_encode};
 
+use super::config::WorldGenConfig;
+
 #[derive(Resource)]
 pub struct WorldDatabase {
     pub name: String,
     pub connection: Arc<Mutex<Connection>>,
 }
 
+// Bumped whenever the way chunks are saved changes. Chunks saved in an older format can't be read anymore so
+// they get thrown away and generated again
+pub const CHUNK_FORMAT: u32 = 2;
+
 pub fn create_database(database: &Connection) {
     database
         .execute(
@@ -27,6 +34,120 @@ pub fn create_database(database: &Connection) {
             [],
         )
         .unwrap();
+    database
+        .execute(
+            " create table if not exists block_ids (
+            id integer primary key,
+            name text not null unique
+        )",
+            [],
+        )
+        .unwrap();
+    database
+        .execute(
+            " create table if not exists world_meta (
+            key text primary key,
+            value text not null
+        )",
+            [],
+        )
+        .unwrap();
+    check_chunk_format(database);
+}
+
+fn check_chunk_format(database: &Connection) {
+    let version: u32 = database
+        .query_row("PRAGMA user_version;", [], |row| row.get(0))
+        .unwrap();
+    if version == CHUNK_FORMAT {
+        return;
+    }
+    assert!(
+        version < CHUNK_FORMAT,
+        "World was saved with a newer chunk format ({version}) than this server knows ({CHUNK_FORMAT})"
+    );
+    let old: u32 = database
+        .query_row("SELECT count(*) FROM blocks;", [], |row| row.get(0))
+        .unwrap();
+    if old > 0 {
+        warn!("Throwing away {old} chunks saved in chunk format {version}, they'll be generated again");
+        database.execute("DELETE FROM blocks;", []).unwrap();
+    }
+    database
+        .execute_batch(&format!("PRAGMA user_version = {CHUNK_FORMAT};"))
+        .unwrap();
+}
+
+// What the world was made with, kept so restarting generates the same terrain next to saved chunks
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorldMeta {
+    pub seed: u32,
+    pub config: WorldGenConfig,
+}
+
+// None for a world that hasn't been set up yet. Settings that don't parse are an error, generating with
+// anything else would leave seams next to the saved chunks
+pub fn load_world_meta(database: &Connection) -> Result<Option<WorldMeta>, String> {
+    let mut stmt = database
+        .prepare("SELECT key, value FROM world_meta;")
+        .unwrap();
+    let values: HashMap<String, String> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .filter_map(|row| row.ok())
+        .collect();
+    let Some(seed) = values.get("seed") else {
+        return Ok(None);
+    };
+    let seed = seed
+        .parse()
+        .map_err(|error| format!("saved seed {seed:?}: {error}"))?;
+    // Settings added since the world was made get their defaults
+    let config = match values.get("world_gen") {
+        Some(config) => {
+            ron::from_str(config).map_err(|error| format!("saved world gen settings: {error}"))?
+        }
+        None => WorldGenConfig::default(),
+    };
+    Ok(Some(WorldMeta { seed, config }))
+}
+
+pub fn save_world_meta(meta: &WorldMeta, database: &Connection) {
+    let config = ron::to_string(&meta.config).unwrap();
+    for (key, value) in [("seed", meta.seed.to_string()), ("world_gen", config)] {
+        database
+            .execute(
+                "REPLACE INTO world_meta (key, value) values (?1, ?2)",
+                params![&key, &value],
+            )
+            .unwrap();
+    }
+}
+
+pub fn has_chunks(database: &Connection) -> bool {
+    database
+        .query_row("SELECT 1 FROM blocks LIMIT 1;", [], |_| Ok(()))
+        .is_ok()
+}
+
+pub fn load_block_table(database: &Connection) -> Vec<(u16, String)> {
+    let mut stmt = database.prepare("SELECT id, name FROM block_ids;").unwrap();
+    stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .filter_map(|row| row.ok())
+        .collect()
+}
+
+// Ids never change once they are given out so only new rows ever get added
+pub fn save_block_table(registry: &BlockRegistry, database: &Connection) {
+    for (id, name) in registry.table() {
+        database
+            .execute(
+                "INSERT OR IGNORE INTO block_ids (id, name) values (?1, ?2)",
+                params![&id, &name],
+            )
+            .unwrap();
+    }
 }
 
 pub fn insert_chunk(chunk_pos: IVec3, raw_chunk: &RawChunk, database: &Connection) {
@@ -63,11 +184,73 @@ pub fn load_chunk(chunk_pos: IVec3, database: &Connection) -> Option<RawChunk> {
         );
         if let Ok(chunk_row) = chunk_result {
             let mut temp_output = Cursor::new(Vec::new());
-            copy_decode(&chunk_row[..], &mut temp_output).unwrap();
-            let final_chunk = bincode::deserialize(temp_output.get_ref()).unwrap();
-            return Some(final_chunk);
+            if let Err(error) = copy_decode(&chunk_row[..], &mut temp_output) {
+                warn!("Couldn't decompress saved chunk {chunk_pos}: {error}");
+                return None;
+            }
+            match bincode::deserialize(temp_output.get_ref()) {
+                Ok(final_chunk) => return Some(final_chunk),
+                Err(error) => warn!("Couldn't read saved chunk {chunk_pos}: {error}"),
+            }
         }
     }
 
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+    use common::game::world::chunk::RawChunk;
+    use rusqlite::Connection;
+
+    use super::{
+        create_database, has_chunks, insert_chunk, load_world_meta, save_world_meta, WorldMeta,
+        CHUNK_FORMAT,
+    };
+    use crate::game::world::config::WorldGenConfig;
+
+    #[test]
+    fn world_meta_round_trip() {
+        let database = Connection::open_in_memory().unwrap();
+        create_database(&database);
+        assert_eq!(load_world_meta(&database), Ok(None));
+        assert!(!has_chunks(&database));
+
+        let mut config = WorldGenConfig::default();
+        config.tunnels.chance = 0.9;
+        config.ores.clear();
+        let meta = WorldMeta { seed: 42, config };
+        save_world_meta(&meta, &database);
+        assert_eq!(load_world_meta(&database), Ok(Some(meta)));
+
+        // Settings that can't be read stop the world from loading instead of quietly changing
+        database
+            .execute(
+                "UPDATE world_meta SET value = 'nonsense' WHERE key = 'world_gen';",
+                [],
+            )
+            .unwrap();
+        assert!(load_world_meta(&database).is_err());
+    }
+
+    #[test]
+    fn old_chunks_dropped() {
+        let database = Connection::open_in_memory().unwrap();
+        create_database(&database);
+        insert_chunk(IVec3::ZERO, &RawChunk::new(), &database);
+        // Opening it again with the same format keeps everything
+        create_database(&database);
+        assert!(has_chunks(&database));
+
+        database
+            .execute_batch(&format!("PRAGMA user_version = {};", CHUNK_FORMAT - 1))
+            .unwrap();
+        create_database(&database);
+        assert!(!has_chunks(&database));
+        let version: u32 = database
+            .query_row("PRAGMA user_version;", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, CHUNK_FORMAT);
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::{HashMap, HashSet};
+
+use bevy::prelude::*;
+use common::{
+    game::{
+        scripting::structure::structure_descriptor::StructureDescriptor,
+        storage::BlockType,
+        world::{
+            block_state::BlockState,
+            chunk::{RawChunk, CHUNK_SIZE},
+        },
+    },
+    networking::content::hash_bytes,
+};
+
+use super::generation::TerrainGenerator;
+
+// Structures are picked per region, a square of columns this wide
+pub const REGION_SIZE: i32 = 32;
+
+pub struct Structure {
+    pub id: String,
+    pub blocks: Vec<(IVec3, String)>,
+    // Corners of the box every block fits in, relative to the origin
+    pub min: IVec3,
+    pub max: IVec3,
+    pub biomes: HashSet<String>,
+    pub attempts: u32,
+    pub chance: f64,
+}
+
+impl Structure {
+    // None if it's empty or uses blocks that don't exist
+    pub fn new(
+        id: &str,
+        descriptor: &StructureDescriptor,
+        blocks: &HashMap<String, BlockType>,
+    ) -> Option<Structure> {
+        let block_list = descriptor.block_list();
+        let missing: HashSet<&str> = block_list
+            .iter()
+            .map(|(_, state)| BlockState::block_id_of(state))
+            .filter(|block| !blocks.contains_key(*block))
+            .collect();
+        if !missing.is_empty() {
+            let mut missing: Vec<&str> = missing.into_iter().collect();
+            missing.sort();
+            warn!("Structure {id} uses missing blocks {}", missing.join(", "));
+            return None;
+        }
+        let min = block_list.iter().map(|(pos, _)| *pos).reduce(IVec3::min)?;
+        let max = block_list.iter().map(|(pos, _)| *pos).reduce(IVec3::max)?;
+        Some(Structure {
+            id: id.to_string(),
+            blocks: block_list,
+            min,
+            max,
+            biomes: descriptor.biomes.iter().cloned().collect(),
+            attempts: descriptor.attempts,
+            chance: descriptor.chance,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Placement {
+    // Index into the generator's structures
+    pub structure: usize,
+    pub origin: IVec3,
+}
+
+// Every structure that reaches into the chunk. Where they go only depends on the seed and the terrain, so
+// each chunk finds the same structures as its neighbours and the order chunks generate in doesn't matter
+pub fn placements(
+    generator: &TerrainGenerator,
+    chunk_pos: IVec3,
+    grid: &mut HashMap<IVec2, usize>,
+) -> Vec<Placement> {
+    let chunk_min = chunk_pos * CHUNK_SIZE as i32;
+    let chunk_max = chunk_min + IVec3::splat(CHUNK_SIZE as i32 - 1);
+    let mut result = Vec::new();
+    for (index, structure) in generator.structures().iter().enumerate() {
+        // Origins of any copy that could reach the chunk
+        let origin_min = chunk_min - structure.max;
+        let origin_max = chunk_max - structure.min;
+        for region_x in origin_min.x.div_euclid(REGION_SIZE)..=origin_max.x.div_euclid(REGION_SIZE)
+        {
+            for region_z in
+                origin_min.z.div_euclid(REGION_SIZE)..=origin_max.z.div_euclid(REGION_SIZE)
+            {
+                for attempt in 0..structure.attempts {
+                    let hash = hash_bytes(
+                        format!(
+                            "{}:{}:{region_x}:{region_z}:{attempt}",
+                            generator.seed(),
+                            structure.id
+                        )
+                        .as_bytes(),
+                    );
+                    if (hash % 10_000) as f64 / 10_000.0 >= structure.chance {
+                        continue;
+                    }
+                    let x = region_x * REGION_SIZE + ((hash >> 20) % REGION_SIZE as u64) as i32;
+                    let z = region_z * REGION_SIZE + ((hash >> 40) % REGION_SIZE as u64) as i32;
+                    if x < origin_min.x || x > origin_max.x || z < origin_min.z || z > origin_max.z
+                    {
+                        continue;
+                    }
+                    let (biome, surface) = generator.surface(x, z, grid);
+                    if !structure.biomes.is_empty() && !structure.biomes.contains(biome) {
+                        continue;
+                    }
+                    let origin = IVec3::new(x, surface + 1, z);
+                    if origin.y >= origin_min.y && origin.y <= origin_max.y {
+                        result.push(Placement {
+                            structure: index,
+                            origin,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    result
+}
+
+// Puts the part of every structure that's inside the chunk into it. Structures only replace air so they
+// don't dig into the ground, where two overlap the one found first wins in every chunk
+pub fn place_structures(
+    generator: &TerrainGenerator,
+    raw_chunk: &mut RawChunk,
+    chunk_pos: IVec3,
+    grid: &mut HashMap<IVec2, usize>,
+) {
+    let chunk_min = chunk_pos * CHUNK_SIZE as i32;
+    for placement in placements(generator, chunk_pos, grid) {
+        let structure = &generator.structures()[placement.structure];
+        for (offset, block) in structure.blocks.iter() {
+            let local = placement.origin + *offset - chunk_min;
+            if local.cmplt(IVec3::ZERO).any() || local.cmpge(IVec3::splat(CHUNK_SIZE as i32)).any()
+            {
+                continue;
+            }
+            let local = local.as_uvec3();
+            if raw_chunk.get_block(local).as_deref() == Some("air") {
+                raw_chunk.add_block_state(block);
+                raw_chunk.set_block(local, block.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::Path, sync::Arc};
+
+    use bevy::prelude::*;
+    use common::game::{
+        scripting::{
+            biome::biome_descriptor::{BiomeDescriptor, HeightNoise},
+            content::load_content,
+            structure::structure_descriptor::{StructureBox, StructureDescriptor},
+        },
+        storage::convert_block,
+        world::chunk::{global_voxel_to_voxel, ChunkComp, LoadableTypes},
+    };
+
+    use super::{placements, Placement};
+    use crate::game::world::{
+        config::WorldGenConfig,
+        generation::{TerrainGenerator, WorldGenerator},
+        pipeline::tests::{generate, generation_app},
+    };
+
+    // Flat ground and a tree in every region. No caves or ores so generating the neighbours stays quick
+    fn generator() -> TerrainGenerator {
+        let content = load_content(&Path::new(env!("CARGO_MANIFEST_DIR")).join("../client/assets"));
+        let mut loadable_types = LoadableTypes {
+            blocks: convert_block(content.blocks),
+            ..default()
+        };
+        loadable_types.biomes.insert(
+            "test:flat".to_string(),
+            BiomeDescriptor {
+                namespace: "test".to_string(),
+                biome_name: "flat".to_string(),
+                temperature: (-1.0, 1.0),
+                humidity: (-1.0, 1.0),
+                surface_block: "vinox:grass".to_string(),
+                subsurface_block: "vinox:dirt".to_string(),
+                subsurface_depth: 3,
+                stone_block: "vinox:cobblestone".to_string(),
+                height: HeightNoise {
+                    base: 8.0,
+                    amplitude: 0.0,
+                    frequency: 0.01,
+                    octaves: 1,
+                    ridged: false,
+                },
+                caves: 0.0,
+                cave_entrances: true,
+            },
+        );
+        loadable_types.structures.insert(
+            "test:tree".to_string(),
+            StructureDescriptor {
+                namespace: "test".to_string(),
+                structure_name: "tree".to_string(),
+                boxes: vec![
+                    StructureBox {
+                        min: (-3, 4, -3),
+                        max: (3, 6, 3),
+                        block: "vinox:moss".to_string(),
+                    },
+                    StructureBox {
+                        min: (0, 0, 0),
+                        max: (0, 5, 0),
+                        block: "vinox:wood".to_string(),
+                    },
+                ],
+                blocks: Vec::new(),
+                biomes: Vec::new(),
+                attempts: 1,
+                chance: 1.0,
+            },
+        );
+        let config = WorldGenConfig {
+            ores: Vec::new(),
+            stone_layers: Vec::new(),
+            ..default()
+        };
+        TerrainGenerator::new(1234, &config, &loadable_types)
+    }
+
+    fn chunks_touched(generator: &TerrainGenerator, placement: &Placement) -> Vec<IVec3> {
+        let structure = &generator.structures()[placement.structure];
+        let mut chunks: Vec<IVec3> = structure
+            .blocks
+            .iter()
+            .map(|(offset, _)| global_voxel_to_voxel(placement.origin + *offset).0)
+            .collect();
+        chunks.sort_by_key(|pos| (pos.x, pos.y, pos.z));
+        chunks.dedup();
+        chunks
+    }
+
+    #[test]
+    fn tree_across_four_chunks() {
+        let generator = Arc::new(generator());
+        let mut grid = HashMap::new();
+        // Any tree that crosses both borders and has nothing else in its way
+        let mut found = None;
+        'search: for x in 0..16 {
+            for z in 0..16 {
+                let nearby = placements(&generator, IVec3::new(x, 0, z), &mut grid);
+                for placement in nearby.iter() {
+                    let chunks = chunks_touched(&generator, placement);
+                    let structure = &generator.structures()[placement.structure];
+                    let clear = nearby
+                        .iter()
+                        .filter(|other| *other != placement)
+                        .all(|other| {
+                            (other.origin - placement.origin).abs().max_element()
+                                > (structure.max - structure.min).max_element()
+                        });
+                    if chunks.len() == 4 && clear {
+                        found = Some((*placement, chunks));
+                        break 'search;
+                    }
+                }
+            }
+        }
+        let (placement, chunks) = found.expect("no tree across four chunks");
+        let structure = &generator.structures()[placement.structure];
+
+        // All at once and one at a time both ways, so neighbours get generated in a different order
+        let mut batches: Vec<Vec<Vec<IVec3>>> = vec![vec![chunks.clone()]];
+        batches.push(chunks.iter().map(|pos| vec![*pos]).collect());
+        batches.push(chunks.iter().rev().map(|pos| vec![*pos]).collect());
+        for batches in batches {
+            let mut app = generation_app(WorldGenerator(generator.clone()));
+            let mut world = HashMap::new();
+            for batch in batches {
+                for (pos, entity) in batch.iter().zip(generate(&mut app, &batch)) {
+                    let chunk = app.world.get::<ChunkComp>(entity).unwrap();
+                    world.insert(*pos, chunk.chunk_data.clone());
+                }
+            }
+            for (offset, block) in structure.blocks.iter() {
+                let pos = placement.origin + *offset;
+                let (chunk_pos, local) = global_voxel_to_voxel(pos);
+                assert_eq!(
+                    world[&chunk_pos].get_block(local).as_deref(),
+                    Some(block.as_str()),
+                    "{pos} is missing from the tree"
+                );
+            }
+            // Same as generating each chunk in one go
+            for pos in chunks.iter() {
+                assert_eq!(world[pos], generator.generate_chunk(*pos), "{pos}");
+            }
+        }
+    }
+}
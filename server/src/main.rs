@@ -5,7 +5,10 @@ use bevy::{
 use common::networking::components::NetworkIP;
 use game::{
     setup::GamePlugin,
-    world::storage::{create_database, WorldDatabase},
+    world::{
+        config::NewWorldOptions,
+        storage::{create_database, WorldDatabase},
+    },
 };
 use iyes_loopless::prelude::*;
 
@@ -20,15 +23,24 @@ mod networking;
 
 // Server should always keep spawn chunks loaded and any chunks near players
 fn main() {
-    let args: Vec<String> = env::args().collect();
-
+    // server [ip] [--seed <seed>] [--world-gen <file>], the last two only matter for a new world
     let mut ip = "127.0.0.1".to_string();
-    match args.len() {
-        1 => {}
-        2 => {
-            ip = args[1].to_string();
+    let mut new_world = NewWorldOptions::default();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => {
+                let seed = args.next().expect("--seed needs a number");
+                new_world.seed = Some(
+                    seed.parse()
+                        .unwrap_or_else(|_| panic!("Seed has to be a number, got {seed:?}")),
+                );
+            }
+            "--world-gen" => {
+                new_world.world_gen = Some(args.next().expect("--world-gen needs a file").into());
+            }
+            _ => ip = arg,
         }
-        _ => {}
     }
 
     let database = Connection::open("world.db").unwrap();
@@ -48,6 +60,7 @@ fn main() {
             connection: Arc::new(Mutex::new(database)),
         })
         .insert_resource(NetworkIP(ip))
+        .insert_resource(new_world)
         .add_plugins(MinimalPlugins)
         .add_plugin(DiagnosticsPlugin)
         .add_plugin(LogPlugin::default())
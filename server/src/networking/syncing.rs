@@ -6,29 +6,53 @@ use bevy::prelude::*;
 use common::{
     game::{
         bundles::PlayerBundleBuilder,
-        world::chunk::{world_to_chunk, ChunkComp, CurrentChunks},
+        scripting::block::script::{BlockHook, BlockScripts},
+        world::{
+            block_state::BlockState,
+            chunk::{
+                voxel_to_global_voxel, world_to_chunk, ChunkComp, CurrentChunks, LoadableTypes,
+                CHUNK_SIZE,
+            },
+            registry::BlockRegistry,
+        },
     },
     networking::components::{ClientMessage, NetworkedEntities, Player, ServerMessage},
 };
 use rustc_data_structures::stable_set::FxHashSet;
 use zstd::stream::copy_encode;
 
-use crate::game::world::{
-    chunk::{ChunkManager, LoadPoint},
-    storage::{insert_chunk, WorldDatabase},
+use crate::game::{
+    scripting::{run_block_hook, DamageEntityEvent, InteractEntityEvent, SpawnEntityEvent},
+    setup::ServerContent,
+    world::{
+        chunk::{ChunkManager, LoadPoint},
+        storage::{insert_chunk, WorldDatabase},
+    },
 };
 
 use super::components::ServerLobby;
 
+// Players don't have weapons yet so every hit does the same
+pub const PLAYER_ATTACK: f32 = 1.0;
+
 #[derive(Component, Clone)]
 pub struct SentChunks {
     pub chunks: FxHashSet<IVec3>,
 }
 
+// Voxel positions from clients have to be inside the chunk, anything else would index past its voxels
+fn voxel_in_chunk(voxel_pos: [u8; 3]) -> Option<UVec3> {
+    let voxel = UVec3::from_array(voxel_pos.map(|axis| axis as u32));
+    voxel.cmplt(UVec3::splat(CHUNK_SIZE)).all().then_some(voxel)
+}
+
 pub fn connections(
     mut server: ResMut<Server>,
     lobby: Res<ServerLobby>,
     mut connection_events: EventReader<ConnectionEvent>,
+    server_content: Res<ServerContent>,
+    block_registry: Res<BlockRegistry>,
+    loadable_types: Res<LoadableTypes>,
 ) {
     for client in connection_events.iter() {
         // Refuse connection once we already have two players
@@ -38,6 +62,10 @@ pub fn connections(
             server
                 .endpoint_mut()
                 .try_send_message(client.id, ServerMessage::ClientId { id: client.id });
+            server.endpoint_mut().try_send_message(
+                client.id,
+                server_content.info(&block_registry, &loadable_types),
+            );
         }
     }
 }
@@ -53,12 +81,28 @@ pub fn server_update_system(
     mut chunks: Query<&mut ChunkComp>,
     current_chunks: Res<CurrentChunks>,
     database: Res<WorldDatabase>,
+    loadable_types: Res<LoadableTypes>,
+    server_content: Res<ServerContent>,
+    block_scripts: NonSend<BlockScripts>,
+    mut damage_events: EventWriter<DamageEntityEvent>,
+    mut interact_events: EventWriter<InteractEntityEvent>,
+    mut spawn_events: EventWriter<SpawnEntityEvent>,
 ) {
     let endpoint = server.endpoint_mut();
     for client_id in endpoint.clients() {
         while let Some(message) = endpoint.try_receive_message_from::<ClientMessage>(client_id) {
             match message {
-                ClientMessage::Join { id, user_name: _ } => {
+                ClientMessage::Join {
+                    id,
+                    user_name: _,
+                    content_hash,
+                } => {
+                    // The client checks this before joining, anything else is out of date or modified
+                    if content_hash != server_content.manifest.hash {
+                        warn!("Player {client_id} has different content, disconnecting them");
+                        endpoint.disconnect_client(client_id).ok();
+                        break;
+                    }
                     println!("Player {id} connected.");
 
                     // Initialize other players for this new client
@@ -118,25 +162,123 @@ pub fn server_update_system(
                     voxel_pos,
                     block_type,
                 } => {
-                    if let Some(chunk_entity) = current_chunks.get_entity(chunk_pos) {
-                        if let Ok(mut chunk) = chunks.get_mut(chunk_entity) {
-                            chunk.chunk_data.add_block_state(&block_type);
-                            chunk.chunk_data.set_block(
-                                UVec3::new(
-                                    voxel_pos[0] as u32,
-                                    voxel_pos[1] as u32,
-                                    voxel_pos[2] as u32,
-                                ),
-                                block_type.clone(),
-                            );
-                            let data = database.connection.lock().unwrap();
-                            insert_chunk(chunk.pos.0, &chunk.chunk_data, &data);
-                            endpoint.try_broadcast_message(ServerMessage::SentBlock {
-                                chunk_pos,
-                                voxel_pos,
-                                block_type,
-                            });
-                        }
+                    // Don't trust the client, only known blocks with valid properties go into the palette
+                    let block_state =
+                        match BlockState::parse_validated(&block_type, &loadable_types.blocks) {
+                            Ok(block_state) => block_state,
+                            Err(error) => {
+                                warn!("Player {client_id} sent a bad block: {error}");
+                                continue;
+                            }
+                        };
+                    let block_type = block_state.to_string();
+                    let Some(voxel) = voxel_in_chunk(voxel_pos) else {
+                        warn!("Player {client_id} sent a block outside the chunk: {voxel_pos:?}");
+                        continue;
+                    };
+                    let Some(chunk_entity) = current_chunks.get_entity(chunk_pos) else {
+                        continue;
+                    };
+                    let Ok(mut chunk) = chunks.get_mut(chunk_entity) else {
+                        continue;
+                    };
+                    let old_block = chunk.chunk_data.get_block(voxel);
+                    chunk.chunk_data.set_block_state(voxel, &block_state);
+                    {
+                        let data = database.connection.lock().unwrap();
+                        insert_chunk(chunk.pos.0, &chunk.chunk_data, &data);
+                    }
+                    endpoint.try_broadcast_message(ServerMessage::SentBlock {
+                        chunk_pos,
+                        voxel_pos,
+                        block_type: block_type.clone(),
+                    });
+
+                    // Breaking runs the old block's script, placing runs the new one's
+                    let (hook, hook_block) = if block_state.block_id == "air" {
+                        (BlockHook::Break, old_block)
+                    } else {
+                        (BlockHook::Place, Some(block_type))
+                    };
+                    if let Some(hook_block) = hook_block {
+                        run_block_hook(
+                            &block_scripts,
+                            &current_chunks,
+                            &mut chunks,
+                            &loadable_types,
+                            &database,
+                            endpoint,
+                            &mut spawn_events,
+                            hook,
+                            voxel_to_global_voxel(voxel, chunk_pos),
+                            &hook_block,
+                        );
+                    }
+                }
+                ClientMessage::Interact { entity, attack } => {
+                    if attack {
+                        damage_events.send(DamageEntityEvent {
+                            entity,
+                            amount: PLAYER_ATTACK,
+                            source: Some(client_id),
+                        });
+                    } else {
+                        interact_events.send(InteractEntityEvent {
+                            entity,
+                            player: client_id,
+                        });
+                    }
+                }
+                ClientMessage::InteractBlock {
+                    chunk_pos,
+                    voxel_pos,
+                } => {
+                    let Some(voxel_pos) = voxel_in_chunk(voxel_pos) else {
+                        warn!("Player {client_id} interacted outside the chunk: {voxel_pos:?}");
+                        continue;
+                    };
+                    let Some(block_state) = current_chunks
+                        .get_entity(chunk_pos)
+                        .and_then(|chunk_entity| chunks.get(chunk_entity).ok())
+                        .and_then(|chunk| chunk.chunk_data.get_block(voxel_pos))
+                    else {
+                        continue;
+                    };
+                    // The client checks this too but it can't be trusted
+                    let interactable = loadable_types
+                        .blocks
+                        .get(BlockState::block_id_of(&block_state))
+                        .map(|block_type| block_type.interactable)
+                        .unwrap_or(false);
+                    if interactable {
+                        run_block_hook(
+                            &block_scripts,
+                            &current_chunks,
+                            &mut chunks,
+                            &loadable_types,
+                            &database,
+                            endpoint,
+                            &mut spawn_events,
+                            BlockHook::Interact { player: client_id },
+                            voxel_to_global_voxel(voxel_pos, chunk_pos),
+                            &block_state,
+                        );
+                    }
+                }
+                ClientMessage::RequestContent { files } => {
+                    for (namespace, path) in files {
+                        let Some(data) = server_content.read(&namespace, &path) else {
+                            warn!("Player {client_id} asked for {namespace}/{path} which can't be sent");
+                            continue;
+                        };
+                        endpoint.try_send_message(
+                            client_id,
+                            ServerMessage::ContentFile {
+                                namespace,
+                                path,
+                                data,
+                            },
+                        );
                     }
                 }
                 _ => {}